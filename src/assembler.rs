@@ -0,0 +1,300 @@
+use std::collections::HashMap;
+use std::convert::TryFrom;
+
+use instruction::Instruction;
+use instruction::Opcode;
+use instruction::OperandKind;
+
+// Register `$sp` names unless a program aliases it elsewhere
+const STACK_POINTER: u8 = 31;
+
+// An assembled program: the encoded instructions, and the bytes laid out by
+// .data directives which the VM loads as the start of its heap
+#[derive(Debug, PartialEq)]
+pub struct Program {
+    pub code: Vec<u8>,
+    pub data: Vec<u8>,
+}
+
+// Two pass assembler for text such as
+//
+//     answer: .data 42       ; places a byte in the heap, `answer` is its address
+//     start:  loada $0 @answer
+//             load $1 #500
+//
+// Operands are `$n` registers, `#n` immediates and `@label` addresses. Registers
+// can also be named, `$sp` is the stack pointer and `.alias name $n` declares more.
+// The first pass records every label and alias, code labels at their instruction's
+// byte offset and data labels at their offset into the heap, the second encodes
+// the instructions. An alias can't be used above the .alias line declaring it.
+pub struct Assembler {
+    labels: HashMap<String, u16>,
+    // Each alias's register and the line it was declared on, 0 for `$sp`
+    aliases: HashMap<String, (u8, usize)>,
+}
+
+impl Assembler {
+    pub fn new() -> Assembler {
+        Assembler {
+            labels: HashMap::new(),
+            aliases: HashMap::new(),
+        }
+    }
+
+    pub fn assemble(&mut self, source: &str) -> Result<Program, String> {
+        self.labels.clear();
+        self.aliases.clear();
+        self.aliases.insert("sp".to_string(), (STACK_POINTER, 0));
+
+        let lines: Vec<(usize, Vec<&str>)> = source.lines()
+            .enumerate()
+            .map(|(i, line)| (i + 1, line.split(';').next().unwrap_or("").split_whitespace().collect()))
+            .collect();
+
+        let mut program = Program {
+            code: vec![],
+            data: vec![],
+        };
+
+        // First pass: label addresses and the data section
+        let mut code_len = 0;
+        for &(number, ref words) in &lines {
+            let (label, rest) = split_label(words);
+
+            match rest.first() {
+                Some(&".data") => {
+                    if let Some(label) = label {
+                        self.define(number, label, program.data.len())?;
+                    }
+
+                    for word in &rest[1..] {
+                        match word.parse::<u8>() {
+                            Ok(byte) => program.data.push(byte),
+                            Err(_) => return Err(format!("Line {}: '{}' isn't a byte", number, word))
+                        }
+                    }
+                },
+                Some(&".alias") => {
+                    let register = match rest[1..] {
+                        [name, register] => register.strip_prefix('$').and_then(|r| self.register(r)).map(|r| (name, r)),
+                        _ => None
+                    };
+
+                    match register {
+                        Some((name, register)) => self.aliases.insert(name.to_string(), (register, number)),
+                        None => return Err(format!("Line {}: .alias takes a name and a register", number))
+                    };
+                },
+                _ => {
+                    if let Some(label) = label {
+                        self.define(number, label, code_len)?;
+                    }
+
+                    if let Some(word) = rest.first() {
+                        code_len += Opcode::from(*word).width();
+                    }
+                }
+            }
+        }
+
+        // Second pass: encode the instructions
+        for &(number, ref words) in &lines {
+            let (_, rest) = split_label(words);
+
+            match rest.first() {
+                None | Some(&".data") | Some(&".alias") => (),
+                Some(_) => {
+                    let instruction = self.instruction(number, rest)?;
+                    program.code.extend_from_slice(&instruction.encode());
+                }
+            }
+        }
+
+        return Ok(program)
+    }
+
+    fn define(&mut self, number: usize, label: &str, address: usize) -> Result<(), String> {
+        if address > u16::MAX as usize {
+            return Err(format!("Line {}: label '{}' is out of 16 bit range", number, label))
+        }
+
+        if self.labels.insert(label.to_string(), address as u16).is_some() {
+            return Err(format!("Line {}: label '{}' is already defined", number, label))
+        }
+
+        return Ok(())
+    }
+
+    fn instruction(&self, number: usize, words: &[&str]) -> Result<Instruction, String> {
+        let opcode = Opcode::from(words[0]);
+        if opcode == Opcode::IGL {
+            return Err(format!("Line {}: unknown opcode '{}'", number, words[0]))
+        }
+
+        let kinds: Vec<&OperandKind> = opcode.operand_kinds().iter()
+            .filter(|k| **k != OperandKind::Unused)
+            .collect();
+
+        let operands = &words[1..];
+        if operands.len() != kinds.len() {
+            return Err(format!("Line {}: {:?} takes {} operands, got {}", number, opcode, kinds.len(), operands.len()))
+        }
+
+        let mut bytes = [0; 3];
+        let mut offset = 0;
+        for (kind, operand) in kinds.into_iter().zip(operands) {
+            let value = self.operand(number, *kind, operand)?;
+
+            match *kind {
+                OperandKind::Immediate16 => {
+                    bytes[offset] = (value >> 8) as u8;
+                    bytes[offset + 1] = value as u8;
+                },
+                _ => bytes[offset] = value as u8,
+            }
+
+            offset += kind.width();
+        }
+
+        return Ok(Instruction::with_operands(opcode, bytes))
+    }
+
+    // A register by number or by alias. Aliases are only known once the first pass has seen them.
+    fn register(&self, name: &str) -> Option<u8> {
+        match parse_number(name) {
+            Some(n) => return u8::try_from(n).ok(),
+            None => return self.aliases.get(name).map(|alias| alias.0)
+        }
+    }
+
+    // Resolves an operand to the bits it encodes to, range checked for its kind
+    fn operand(&self, number: usize, kind: OperandKind, operand: &str) -> Result<u16, String> {
+        let mut chars = operand.chars();
+        let prefix = chars.next();
+        let body = chars.as_str();
+
+        let value = match (kind, prefix) {
+            (OperandKind::Register, Some('$')) => {
+                if parse_number(body).is_none() {
+                    match self.aliases.get(body) {
+                        None => return Err(format!("Line {}: unknown register '{}'", number, operand)),
+                        Some(&(_, declared)) if declared > number => {
+                            return Err(format!("Line {}: alias '{}' is used before its .alias on line {}", number, operand, declared))
+                        },
+                        Some(_) => ()
+                    }
+                }
+                self.register(body).map(i32::from)
+            },
+            (OperandKind::Immediate8, Some('#')) => parse_number(body).filter(|n| *n >= 0 && *n <= 255),
+            // Negative immediates are stored as their 16 bit two's complement
+            (OperandKind::Immediate16, Some('#')) => parse_number(body).filter(|n| *n >= i32::from(i16::MIN) && *n <= i32::from(u16::MAX)),
+            (OperandKind::Immediate16, Some('@')) => {
+                match self.labels.get(body) {
+                    Some(&address) => Some(i32::from(address)),
+                    None => return Err(format!("Line {}: undefined label '{}'", number, body))
+                }
+            },
+            _ => return Err(format!("Line {}: expected a {:?} operand, got '{}'", number, kind, operand))
+        };
+
+        match value {
+            Some(value) => return Ok(value as u16),
+            None => return Err(format!("Line {}: operand '{}' is out of range", number, operand))
+        }
+    }
+}
+
+// Separates a leading `label:` from the rest of a line
+fn split_label<'a, 'b>(words: &'b [&'a str]) -> (Option<&'a str>, &'b [&'a str]) {
+    match words.first() {
+        Some(word) if word.ends_with(':') => return (Some(&word[..word.len() - 1]), &words[1..]),
+        _ => return (None, words)
+    }
+}
+
+fn parse_number(s: &str) -> Option<i32> {
+    return s.parse::<i32>().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use vm::VM;
+
+    #[test]
+    fn test_assemble_instructions() {
+        let program = Assembler::new().assemble("load $0 #500\nadd $0 $1 $2 ; sum\nhlt $2").unwrap();
+
+        assert_eq!(program.code, vec![0, 0, 1, 244, 1, 0, 1, 2, 5, 2, 0, 0]);
+        assert!(program.data.is_empty());
+    }
+
+    #[test]
+    fn test_assemble_data_label() {
+        let source = "
+            table:  .data 1 2 3
+            answer: .data 42
+            start:  loada $0 @answer
+                    loada $1 @start
+        ";
+
+        let program = Assembler::new().assemble(source).unwrap();
+
+        assert_eq!(program.data, vec![1, 2, 3, 42]);
+        assert_eq!(program.code, vec![33, 0, 0, 3, 33, 1, 0, 0]);
+
+        let mut vm = VM::new();
+        vm.load_data(program.data);
+        vm.load_bytes(program.code);
+        vm.run().unwrap();
+
+        assert_eq!(vm.registers[0], 3);
+        assert_eq!(vm.heap()[vm.registers[0] as usize], 42);
+    }
+
+    #[test]
+    fn test_assemble_aliases() {
+        let mut assembler = Assembler::new();
+
+        let program = assembler.assemble("add $sp $sp $sp").unwrap();
+        assert_eq!(program.code, vec![1, 31, 31, 31]);
+
+        let source = "
+            .alias sp $30
+            .alias acc $2
+            add $sp $acc $acc
+        ";
+        let program = assembler.assemble(source).unwrap();
+        assert_eq!(program.code, vec![1, 30, 2, 2]);
+
+        assert_eq!(assembler.assemble("add $acc $0 $0"), Err("Line 1: unknown register '$acc'".to_string()));
+        assert_eq!(assembler.assemble("add $300 $0 $0"), Err("Line 1: operand '$300' is out of range".to_string()));
+        assert_eq!(assembler.assemble(".alias acc 2"), Err("Line 1: .alias takes a name and a register".to_string()));
+    }
+
+    #[test]
+    fn test_alias_used_before_declaration() {
+        let mut assembler = Assembler::new();
+
+        assert_eq!(assembler.assemble("add $sp $0 $0\n.alias sp $30\nadd $sp $0 $0"),
+            Err("Line 1: alias '$sp' is used before its .alias on line 2".to_string()));
+        assert_eq!(assembler.assemble("add $acc $0 $0\n.alias acc $2"),
+            Err("Line 1: alias '$acc' is used before its .alias on line 2".to_string()));
+
+        // Redeclaring an alias that's already in use is caught the same way
+        assert_eq!(assembler.assemble(".alias acc $1\nadd $acc $0 $0\n.alias acc $2"),
+            Err("Line 2: alias '$acc' is used before its .alias on line 3".to_string()));
+    }
+
+    #[test]
+    fn test_assemble_errors() {
+        let mut assembler = Assembler::new();
+
+        assert_eq!(assembler.assemble("loada $0 @missing"), Err("Line 1: undefined label 'missing'".to_string()));
+        assert_eq!(assembler.assemble("frob $0"), Err("Line 1: unknown opcode 'frob'".to_string()));
+        assert_eq!(assembler.assemble("load $0 #70000"), Err("Line 1: operand '#70000' is out of range".to_string()));
+        assert_eq!(assembler.assemble("x: .data 1\nx: nop"), Err("Line 2: label 'x' is already defined".to_string()));
+    }
+}
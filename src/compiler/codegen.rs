@@ -0,0 +1,439 @@
+use std::collections::HashMap;
+use std::mem;
+
+use compiler::token::Token;
+use compiler::parser::Expression;
+use compiler::parser::ExpressionType;
+use compiler::parser::AstProgram;
+
+use instruction::Instruction;
+use instruction::Opcode;
+
+// Number of integer registers available to generated code
+const REGISTER_COUNT: usize = 32;
+
+// Jumps out of the loop being generated, patched once the loop's head and end are known
+struct LoopJumps {
+    breaks: Vec<usize>,
+    continues: Vec<usize>
+}
+
+// Lowers parsed expressions to VM bytecode. Variables live in the low registers
+// in declaration order, temporaries are handed out above them and reused by
+// the next statement.
+pub struct Codegen {
+    bytes: Vec<u8>,
+    vars: HashMap<String, u8>,
+    next_register: usize,
+    // Loops being generated, innermost last
+    loops: Vec<LoopJumps>
+}
+
+impl Codegen {
+    pub fn new() -> Codegen {
+        Codegen {
+            bytes: vec![],
+            vars: HashMap::new(),
+            next_register: 0,
+            loops: vec![]
+        }
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+
+    // Hands over the bytes emitted so far, keeping variable registers for later code
+    pub fn take_bytes(&mut self) -> Vec<u8> {
+        mem::take(&mut self.bytes)
+    }
+
+    // Register a variable was allocated to, if it has been declared
+    pub fn register_of(&self, name: &str) -> Option<u8> {
+        self.vars.get(name).cloned()
+    }
+
+    // Emits code for a statement, returning the register holding its value if it has one.
+    // The register stays valid until the next call.
+    pub fn generate(&mut self, expr: &Expression) -> Result<Option<u8>, String> {
+        return self.statement(expr)
+    }
+
+    // Emits code for every statement of the program, returning the bytes emitted for it
+    pub fn compile(&mut self, program: &AstProgram) -> Result<Vec<u8>, String> {
+        for statement in &program.statements {
+            self.statement(&statement.expr)?;
+        }
+
+        return Ok(self.take_bytes())
+    }
+
+    fn emit(&mut self, opcode: Opcode, operands: [u8; 3]) {
+        let instruction = Instruction::with_operands(opcode, operands);
+        self.bytes.extend_from_slice(&instruction.encode());
+    }
+
+    // Emits a jump with a placeholder offset, returning where it is so it can be patched
+    fn emit_jump(&mut self, opcode: Opcode) -> usize {
+        let at = self.bytes.len();
+        self.emit(opcode, [0, 0, 0]);
+
+        return at
+    }
+
+    // Points the jump at the byte offset, relative to the instruction after the jump
+    fn patch_jump(&mut self, at: usize, target: usize) -> Result<(), String> {
+        let offset = target as isize - (at + 4) as isize;
+
+        if offset < isize::from(i16::MIN) || offset > isize::from(i16::MAX) {
+            return Err(format!("Jump of {} bytes is out of range", offset))
+        }
+
+        let bits = offset as u16;
+        self.bytes[at + 1] = (bits >> 8) as u8;
+        self.bytes[at + 2] = bits as u8;
+
+        return Ok(())
+    }
+
+    fn allocate(&mut self) -> Result<u8, String> {
+        if self.next_register >= REGISTER_COUNT {
+            return Err("Out of registers".to_string())
+        }
+
+        let register = self.next_register as u8;
+        self.next_register += 1;
+
+        return Ok(register)
+    }
+
+    fn statement(&mut self, expr: &Expression) -> Result<Option<u8>, String> {
+        // Temporaries from the previous statement are dead by now
+        self.next_register = self.vars.len();
+
+        match expr.expression_type {
+            ExpressionType::VarExpression(ref var) |
+            ExpressionType::ConstExpression(ref var) => {
+                match var.expression_type {
+                    ExpressionType::LiteralExpression(ref name, ref value) => {
+                        let register = self.allocate()?;
+                        let result = self.expression(value)?;
+
+                        self.emit(Opcode::MOV, [result, register, 0]);
+                        self.vars.insert(name.clone(), register);
+
+                        return Ok(Some(register))
+                    },
+                    _ => return Err("Malformed variable declaration".to_string())
+                }
+            },
+
+            ExpressionType::BlockExpression(ref exprs) => {
+                let mut last = None;
+                for e in exprs {
+                    last = self.statement(e)?;
+                }
+                return Ok(last)
+            },
+
+            ExpressionType::WhileExpression(ref cond, ref body) => {
+                let head = self.bytes.len();
+                let result = self.expression(cond)?;
+
+                // The flag is set once the condition is false, which leaves the loop
+                self.emit(Opcode::CMPI, [result, 0, 0]);
+                let exit = self.emit_jump(Opcode::JEQI);
+
+                self.loops.push(LoopJumps { breaks: vec![], continues: vec![] });
+                let generated = self.statement(body);
+                let jumps = self.loops.pop().expect("Loop jumps popped by their body");
+                generated?;
+
+                let back = self.emit_jump(Opcode::JMPI);
+                self.patch_jump(back, head)?;
+
+                for at in jumps.continues {
+                    self.patch_jump(at, head)?;
+                }
+
+                let end = self.bytes.len();
+                for at in jumps.breaks.into_iter().chain(Some(exit)) {
+                    self.patch_jump(at, end)?;
+                }
+
+                return Ok(None)
+            },
+
+            ExpressionType::BreakExpression |
+            ExpressionType::ContinueExpression => {
+                let at = self.emit_jump(Opcode::JMPI);
+
+                match (self.loops.last_mut(), &expr.expression_type) {
+                    (Some(jumps), &ExpressionType::BreakExpression) => jumps.breaks.push(at),
+                    (Some(jumps), _) => jumps.continues.push(at),
+                    (None, _) => return Err("Jump outside loop".to_string())
+                }
+
+                return Ok(None)
+            },
+
+            ExpressionType::FunctionHeaderExpression(_) |
+            ExpressionType::StructExpression(..) => return Ok(None),
+
+            _ => return Ok(Some(self.expression(expr)?))
+        }
+    }
+
+    // Emits code for an expression, returning the register holding its value
+    fn expression(&mut self, expr: &Expression) -> Result<u8, String> {
+        match expr.expression_type {
+            ExpressionType::Literal(Token::Identifier(ref name)) => {
+                match self.vars.get(name) {
+                    Some(&register) => return Ok(register),
+                    None => return Err(format!("Undefined variable '{}'", name))
+                }
+            },
+
+            ExpressionType::Literal(Token::IntegerLiteral(i)) => return self.load_integer(i),
+
+            ExpressionType::Literal(Token::BooleanLiteral(b)) => return self.load_integer(b as i32),
+
+            ExpressionType::AssignmentExpression(ref name, ref rhs) => {
+                let register = match self.vars.get(name) {
+                    Some(&register) => register,
+                    None => return Err(format!("Undefined variable '{}'", name))
+                };
+
+                let result = self.expression(rhs)?;
+                self.emit(Opcode::MOV, [result, register, 0]);
+
+                return Ok(register)
+            },
+
+            ExpressionType::UnaryExpression(ref op, ref rhs) => {
+                let value = self.expression(rhs)?;
+                let register = self.allocate()?;
+
+                match *op {
+                    Token::Subtract => {
+                        self.emit(Opcode::MOV, [value, register, 0]);
+                        self.emit(Opcode::NEG, [register, 0, 0]);
+                    },
+                    Token::Bang => {
+                        self.emit(Opcode::CMPI, [value, 0, 0]);
+                        self.flag_to_register(register);
+                    },
+                    ref op => return Err(format!("Unsupported unary operator {:?}", op))
+                }
+
+                return Ok(register)
+            },
+
+            ExpressionType::BinaryExpression(ref op @ Token::LogicalAnd, ref lhs, ref rhs) |
+            ExpressionType::BinaryExpression(ref op @ Token::LogicalOr, ref lhs, ref rhs) => {
+                let left = self.expression(lhs)?;
+                let register = self.allocate()?;
+
+                self.emit(Opcode::MOV, [left, register, 0]);
+
+                // The left operand settles && when false and || when true, so the
+                // right operand's code is jumped over
+                let settled = if *op == Token::LogicalAnd { 0 } else { 1 };
+                self.emit(Opcode::CMPI, [left, 0, settled]);
+
+                let jump = self.emit_jump(Opcode::JEQI);
+
+                let right = self.expression(rhs)?;
+                self.emit(Opcode::MOV, [right, register, 0]);
+
+                let end = self.bytes.len();
+                self.patch_jump(jump, end)?;
+
+                return Ok(register)
+            },
+
+            ExpressionType::BinaryExpression(ref op, ref lhs, ref rhs) => {
+                let left = self.expression(lhs)?;
+                let right = self.expression(rhs)?;
+                let register = self.allocate()?;
+
+                let opcode = match *op {
+                    Token::Add => Opcode::ADD,
+                    Token::Subtract => Opcode::SUB,
+                    Token::Multiply => Opcode::MUL,
+                    Token::Divide => Opcode::DIV,
+
+                    Token::Equality => Opcode::EQ,
+                    Token::NotEquality => Opcode::NEQ,
+                    Token::LessThan => Opcode::LT,
+                    Token::GreaterThan => Opcode::GT,
+                    Token::LessThanEqual => Opcode::LTE,
+                    Token::GreaterThanEqual => Opcode::GTE,
+
+                    ref op => return Err(format!("Unsupported binary operator {:?}", op))
+                };
+
+                match opcode {
+                    Opcode::ADD | Opcode::SUB | Opcode::MUL | Opcode::DIV => {
+                        self.emit(opcode, [left, right, register]);
+                    },
+                    _ => {
+                        self.emit(opcode, [left, right, 0]);
+                        self.flag_to_register(register);
+                    }
+                }
+
+                return Ok(register)
+            },
+
+            ref t => return Err(format!("Codegen doesn't support {:?} yet", t))
+        }
+    }
+
+    // LOAD takes an unsigned 16 bit immediate and LOADS a signed one. Negatives
+    // beyond LOADS's range are loaded as their magnitude then negated.
+    fn load_integer(&mut self, value: i32) -> Result<u8, String> {
+        if value.unsigned_abs() > u32::from(u16::MAX) {
+            return Err(format!("Integer literal {} doesn't fit in an immediate", value))
+        }
+
+        let register = self.allocate()?;
+
+        if value < 0 && value >= i32::from(i16::MIN) {
+            let bits = value as u16;
+            self.emit(Opcode::LOADS, [register, (bits >> 8) as u8, bits as u8]);
+
+            return Ok(register)
+        }
+
+        let magnitude = value.unsigned_abs() as u16;
+
+        self.emit(Opcode::LOAD, [register, (magnitude >> 8) as u8, magnitude as u8]);
+
+        if value < 0 {
+            self.emit(Opcode::NEG, [register, 0, 0]);
+        }
+
+        return Ok(register)
+    }
+
+    // Materialises the comparison flag as 1 or 0 in the register
+    fn flag_to_register(&mut self, register: u8) {
+        self.emit(Opcode::LOAD, [register, 0, 1]);
+        // Skip the following LOAD when the flag is set
+        self.emit(Opcode::JEQI, [0, 4, 0]);
+        self.emit(Opcode::LOAD, [register, 0, 0]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use compiler::Scanner;
+    use compiler::parser::Parser;
+    use compiler::parser::ParseResult;
+
+    use vm::VM;
+
+    // Compiles every statement in the source, returning the VM after running it
+    // along with the register holding the last statement's value
+    fn run(source: &str) -> (VM, Option<u8>) {
+        let tokens = Scanner::new(source).tokenize();
+
+        let mut parser = Parser::new(tokens);
+        let mut codegen = Codegen::new();
+        let mut result = None;
+
+        while let Some(res) = parser.parse_next() {
+            match res {
+                ParseResult::Success(expr) => result = codegen.generate(&expr).unwrap(),
+                ParseResult::Failed(f) => panic!("{}", f)
+            }
+        }
+
+        let mut vm = VM::new();
+        vm.load_bytes(codegen.into_bytes());
+        vm.run().unwrap();
+
+        return (vm, result)
+    }
+
+    #[test]
+    fn test_generate_arithmetic() {
+        let (vm, result) = run("1 + 2 * 3 - 4;");
+
+        assert_eq!(vm.registers[result.unwrap() as usize], 3);
+    }
+
+    #[test]
+    fn test_generate_variables() {
+        let (vm, _) = run("var x = 2; var y = 0 - x * 5; x = y + 1;");
+
+        assert_eq!(vm.registers[0], -9);
+        assert_eq!(vm.registers[1], -10);
+    }
+
+    #[test]
+    fn test_generate_negation() {
+        let (vm, _) = run("var x = 2; var y = x * -5; var z = -x;");
+
+        assert_eq!(vm.registers[1], -10);
+        assert_eq!(vm.registers[2], -2);
+    }
+
+    #[test]
+    fn test_generate_comparison() {
+        let (vm, result) = run("3 < 4;");
+        assert_eq!(vm.registers[result.unwrap() as usize], 1);
+
+        let (vm, result) = run("!true;");
+        assert_eq!(vm.registers[result.unwrap() as usize], 0);
+
+        let (vm, result) = run("-300;");
+        assert_eq!(vm.registers[result.unwrap() as usize], -300);
+
+        let (vm, result) = run("-40000;");
+        assert_eq!(vm.registers[result.unwrap() as usize], -40000);
+    }
+
+    #[test]
+    fn test_generate_signed_comparison() {
+        let (vm, _) = run("var a = 0 - 1; var b = a < 2; var c = a >= 0; var d = -5 > a - 10;");
+
+        assert_eq!(vm.registers[1], 1);
+        assert_eq!(vm.registers[2], 0);
+        assert_eq!(vm.registers[3], 1);
+    }
+
+    #[test]
+    fn test_generate_short_circuit() {
+        let (vm, _) = run("var hit = false; var a = false && (hit = true); var b = true || (hit = true);");
+
+        assert_eq!(vm.registers[0], 0);
+        assert_eq!(vm.registers[1], 0);
+        assert_eq!(vm.registers[2], 1);
+
+        let (vm, _) = run("var hit = false; var a = true && (hit = true);");
+
+        assert_eq!(vm.registers[0], 1);
+        assert_eq!(vm.registers[1], 1);
+    }
+
+    #[test]
+    fn test_generate_break_and_continue() {
+        let (vm, _) = run("var n = 0; var after = false; while n < 10 { n = n + 1; break; after = true; }");
+
+        assert_eq!(vm.registers[0], 1);
+        assert_eq!(vm.registers[1], 0);
+
+        let (vm, _) = run("var n = 0; var after = false; while n < 5 { n = n + 1; continue; after = true; }");
+
+        assert_eq!(vm.registers[0], 5);
+        assert_eq!(vm.registers[1], 0);
+    }
+}
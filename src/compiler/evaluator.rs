@@ -0,0 +1,513 @@
+use std::mem;
+
+use compiler::token::Token;
+use compiler::parser::Expression;
+use compiler::parser::ExpressionType;
+use compiler::parser::ForLoop;
+use compiler::runtime::RuntimeEnvironment;
+use compiler::runtime::Value;
+
+// How a statement finished, break and continue end every enclosing block up to their loop
+#[derive(Debug, PartialEq)]
+enum Flow {
+    Next,
+    Break,
+    Continue
+}
+
+// Tree walking interpreter over parsed expressions
+pub struct Evaluator {
+    vars: RuntimeEnvironment
+}
+
+impl Evaluator {
+    pub fn new() -> Evaluator {
+        Evaluator {
+            vars: RuntimeEnvironment::new()
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Value> {
+        self.vars.get(name)
+    }
+
+    // Runs a statement for its side effects
+    pub fn execute(&mut self, expr: &Expression) -> Result<(), String> {
+        self.run(expr)?;
+        return Ok(())
+    }
+
+    fn run(&mut self, expr: &Expression) -> Result<Flow, String> {
+        match expr.expression_type {
+            ExpressionType::VarExpression(ref var) => {
+                match var.expression_type {
+                    ExpressionType::LiteralExpression(ref name, ref value) => {
+                        let value = self.evaluate(value)?;
+                        self.vars.define(name.clone(), value);
+
+                        return Ok(Flow::Next)
+                    },
+                    _ => return Err("Malformed variable declaration".to_string())
+                }
+            },
+
+            ExpressionType::PrintExpression(ref s) => {
+                println!("{}", s);
+                return Ok(Flow::Next)
+            },
+
+            ExpressionType::BlockExpression(ref exprs) => {
+                for e in exprs {
+                    let flow = self.run(e)?;
+                    if flow != Flow::Next {
+                        return Ok(flow)
+                    }
+                }
+                return Ok(Flow::Next)
+            },
+
+            ExpressionType::ConditionalExpression(ref cond, ref body, ref otherwise) => {
+                match self.evaluate(cond)? {
+                    Value::Bool(true) => return self.run(body),
+                    Value::Bool(false) => {
+                        match *otherwise {
+                            Some(ref otherwise) => return self.run(otherwise),
+                            None => return Ok(Flow::Next)
+                        }
+                    },
+                    value => return Err(format!("If condition must be a bool, got {:?}", value))
+                }
+            },
+
+            ExpressionType::ForExpression(ref f) => return self.execute_for(f),
+
+            ExpressionType::WhileExpression(ref cond, ref body) => {
+                loop {
+                    match self.evaluate(cond)? {
+                        Value::Bool(true) => (),
+                        Value::Bool(false) => return Ok(Flow::Next),
+                        value => return Err(format!("While condition must be a bool, got {:?}", value))
+                    }
+
+                    if self.run(body)? == Flow::Break {
+                        return Ok(Flow::Next)
+                    }
+                }
+            },
+
+            ExpressionType::BreakExpression => return Ok(Flow::Break),
+
+            ExpressionType::ContinueExpression => return Ok(Flow::Continue),
+
+            ExpressionType::FunctionHeaderExpression(_) |
+            ExpressionType::FunctionExpression(_) |
+            ExpressionType::StructExpression(..) => return Ok(Flow::Next),
+
+            _ => {
+                self.evaluate(expr)?;
+                return Ok(Flow::Next)
+            }
+        }
+    }
+
+    // Runs the body once per value of the loop variable, which lives in a scope of its own
+    fn execute_for(&mut self, f: &ForLoop) -> Result<Flow, String> {
+        let start = self.evaluate_integer(&f.start)?;
+        let end = self.evaluate_integer(&f.end)?;
+        let step = self.evaluate_integer(&f.step)?;
+
+        if step <= 0 {
+            return Err(format!("For loop step must be positive, got {}", step))
+        }
+
+        let outer = mem::replace(&mut self.vars, RuntimeEnvironment::new());
+        self.vars = RuntimeEnvironment::new_sub(outer);
+
+        let mut result = Ok(Flow::Next);
+        let mut i = start;
+        while i < end || (f.inclusive && i == end) {
+            self.vars.define(f.var.clone(), Value::Int(i));
+
+            match self.run(&f.body) {
+                Ok(Flow::Break) => break,
+                Ok(_) => (),
+                Err(e) => {
+                    result = Err(e);
+                    break;
+                }
+            }
+
+            // Stepping past i32::MAX ends the loop rather than wrapping round
+            match i.checked_add(step) {
+                Some(next) => i = next,
+                None => break
+            }
+        }
+
+        let inner = mem::replace(&mut self.vars, RuntimeEnvironment::new());
+        self.vars = *inner.enclosing.expect("Loop scope lost its enclosing environment");
+
+        return result
+    }
+
+    fn evaluate_integer(&mut self, expr: &Expression) -> Result<i32, String> {
+        match self.evaluate(expr)? {
+            Value::Int(i) => return Ok(i),
+            value => return Err(format!("Expected an integer, got {:?}", value))
+        }
+    }
+
+    // Evaluates an expression down to a value
+    pub fn evaluate(&mut self, expr: &Expression) -> Result<Value, String> {
+        match expr.expression_type {
+            ExpressionType::Literal(Token::Identifier(ref name)) => {
+                match self.vars.get(name) {
+                    Some(value) => return Ok(value.clone()),
+                    None => return Err(format!("Undefined variable '{}'", name))
+                }
+            },
+
+            ExpressionType::Literal(ref t) => {
+                match Value::from_literal(t) {
+                    Some(value) => return Ok(value),
+                    None => return Err(format!("{:?} can't be evaluated", t))
+                }
+            },
+
+            ExpressionType::AssignmentExpression(ref name, ref rhs) => {
+                let value = self.evaluate(rhs)?;
+                self.vars.assign(name, value.clone())?;
+
+                return Ok(value)
+            },
+
+            ExpressionType::UnaryExpression(ref op, ref rhs) => {
+                let value = self.evaluate(rhs)?;
+                return evaluate_unary(op, value)
+            },
+
+            ExpressionType::BinaryExpression(ref op, ref lhs, ref rhs) => {
+                let left = self.evaluate(lhs)?;
+
+                // && and || skip the right operand when the left one settles the result
+                match (op, &left) {
+                    (&Token::LogicalAnd, &Value::Bool(false)) |
+                    (&Token::LogicalOr, &Value::Bool(true)) => return Ok(left),
+                    _ => ()
+                }
+
+                let right = self.evaluate(rhs)?;
+                return evaluate_binary(op, left, right)
+            },
+
+            ExpressionType::PromoteExpression(ref value) => {
+                let value = self.evaluate(value)?;
+                return promote(value)
+            },
+
+            _ => return Err("Expression can't be evaluated".to_string())
+        }
+    }
+}
+
+pub fn evaluate_unary(op: &Token, value: Value) -> Result<Value, String> {
+    match (op, value) {
+        (&Token::Subtract, Value::Int(i)) => return checked(i.checked_neg()),
+        (&Token::Subtract, Value::Float(f)) => return Ok(Value::Float(-f)),
+        (&Token::Bang, Value::Bool(b)) => return Ok(Value::Bool(!b)),
+        (op, value) => return Err(format!("Invalid operand {:?} for {:?}", value, op))
+    }
+}
+
+// An integer result, or an error if the operation overflowed
+fn checked(result: Option<i32>) -> Result<Value, String> {
+    match result {
+        Some(value) => return Ok(Value::Int(value)),
+        None => return Err("Integer overflow".to_string())
+    }
+}
+
+// Widens an integer so it can be combined with a float
+pub fn promote(value: Value) -> Result<Value, String> {
+    match value {
+        Value::Int(i) => return Ok(Value::Float(f64::from(i))),
+        value => return Err(format!("Can't promote {:?} to a float", value))
+    }
+}
+
+pub fn evaluate_binary(op: &Token, left: Value, right: Value) -> Result<Value, String> {
+    match (left, right) {
+        (Value::Int(l), Value::Int(r)) => {
+            match *op {
+                Token::Add => return checked(l.checked_add(r)),
+                Token::Subtract => return checked(l.checked_sub(r)),
+                Token::Multiply => return checked(l.checked_mul(r)),
+                Token::Divide => {
+                    if r == 0 {
+                        return Err("Division by zero".to_string())
+                    }
+                    return checked(l.checked_div(r))
+                },
+                Token::Modulo => {
+                    if r == 0 {
+                        return Err("Division by zero".to_string())
+                    }
+                    return checked(l.checked_rem(r))
+                },
+                Token::Or => return Ok(Value::Int(l | r)),
+                Token::Xor => return Ok(Value::Int(l ^ r)),
+                Token::And => return Ok(Value::Int(l & r)),
+                Token::ShiftLeft | Token::ShiftRight => {
+                    let shifted = if r < 0 {
+                        None
+                    } else if *op == Token::ShiftLeft {
+                        l.checked_shl(r as u32)
+                    } else {
+                        l.checked_shr(r as u32)
+                    };
+
+                    match shifted {
+                        Some(value) => return Ok(Value::Int(value)),
+                        None => return Err(format!("Shift amount {} is out of range", r))
+                    }
+                },
+                _ => return compare(op, &l, &r)
+            }
+        },
+
+        (Value::Float(l), Value::Float(r)) => {
+            match *op {
+                Token::Add => return Ok(Value::Float(l + r)),
+                Token::Subtract => return Ok(Value::Float(l - r)),
+                Token::Multiply => return Ok(Value::Float(l * r)),
+                Token::Divide => return Ok(Value::Float(l / r)),
+                _ => return compare(op, &l, &r)
+            }
+        },
+
+        (Value::Str(l), Value::Str(r)) => {
+            match *op {
+                Token::Add => return Ok(Value::Str(l + &r)),
+                _ => return compare(op, &l, &r)
+            }
+        },
+
+        (Value::Bool(l), Value::Bool(r)) => {
+            match *op {
+                Token::LogicalAnd => return Ok(Value::Bool(l && r)),
+                Token::LogicalOr => return Ok(Value::Bool(l || r)),
+                _ => return compare(op, &l, &r)
+            }
+        },
+
+        // Nullable variables are tested against null, whatever type they otherwise hold
+        (left, right) if left == Value::Void || right == Value::Void => {
+            match *op {
+                Token::Equality => return Ok(Value::Bool(left == right)),
+                Token::NotEquality => return Ok(Value::Bool(left != right)),
+                _ => return Err(format!("Invalid operands {:?} and {:?} for {:?}", left, right, op))
+            }
+        },
+
+        (left, right) => return Err(format!("Invalid operands {:?} and {:?} for {:?}", left, right, op))
+    }
+}
+
+fn compare<T: PartialOrd>(op: &Token, l: &T, r: &T) -> Result<Value, String> {
+    let result = match *op {
+        Token::Equality => l == r,
+        Token::NotEquality => l != r,
+        Token::LessThan => l < r,
+        Token::GreaterThan => l > r,
+        Token::LessThanEqual => l <= r,
+        Token::GreaterThanEqual => l >= r,
+        _ => return Err(format!("Unsupported operator {:?}", op))
+    };
+
+    return Ok(Value::Bool(result))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use compiler::Scanner;
+    use compiler::parser::Parser;
+    use compiler::parser::ParseResult;
+
+    fn parse(source: &str) -> Parser {
+        let tokens = Scanner::new(source).tokenize();
+
+        return Parser::new(tokens)
+    }
+
+    fn eval(source: &str) -> Result<Value, String> {
+        match parse(source).parse_bare_expression() {
+            ParseResult::Success(expr) => return Evaluator::new().evaluate(&expr),
+            ParseResult::Failed(f) => return Err(f)
+        }
+    }
+
+    #[test]
+    fn test_evaluate_arithmetic() {
+        assert_eq!(eval("1 + 2 * 3"), Ok(Value::Int(7)));
+        assert_eq!(eval("10 / 4 - 1"), Ok(Value::Int(1)));
+        assert_eq!(eval("1.5 * 2.0"), Ok(Value::Float(3.0)));
+        assert_eq!(eval("1 + 2.5"), Ok(Value::Float(3.5)));
+    }
+
+    #[test]
+    fn test_evaluate_comparison() {
+        assert_eq!(eval("2 < 3"), Ok(Value::Bool(true)));
+        assert_eq!(eval("2 == 3"), Ok(Value::Bool(false)));
+    }
+
+    #[test]
+    fn test_evaluate_division_by_zero() {
+        assert!(eval("1 / 0").is_err());
+    }
+
+    #[test]
+    fn test_execute_declaration_and_assignment() {
+        let mut parser = parse("var x : int = 2; x = x * 5;");
+        let mut evaluator = Evaluator::new();
+
+        while let Some(res) = parser.parse_next() {
+            match res {
+                ParseResult::Success(expr) => evaluator.execute(&expr).unwrap(),
+                ParseResult::Failed(f) => panic!("{}", f)
+            }
+        }
+
+        assert_eq!(evaluator.get("x"), Some(&Value::Int(10)));
+    }
+
+    #[test]
+    fn test_short_circuit() {
+        let mut parser = parse("var hit = false; var a = false && (hit = true); var b = true || (hit = true);");
+        let mut evaluator = Evaluator::new();
+
+        while let Some(res) = parser.parse_next() {
+            match res {
+                ParseResult::Success(expr) => evaluator.execute(&expr).unwrap(),
+                ParseResult::Failed(f) => panic!("{}", f)
+            }
+        }
+
+        assert_eq!(evaluator.get("a"), Some(&Value::Bool(false)));
+        assert_eq!(evaluator.get("b"), Some(&Value::Bool(true)));
+        assert_eq!(evaluator.get("hit"), Some(&Value::Bool(false)));
+
+        assert_eq!(eval("true && 1 < 2"), Ok(Value::Bool(true)));
+        assert_eq!(eval("false || 2 < 1"), Ok(Value::Bool(false)));
+    }
+
+    #[test]
+    fn test_for_step() {
+        let mut parser = parse("var sum = 0; var last = 0; for i in 0..=10 step 2 { sum = sum + i; last = i; }");
+        let mut evaluator = Evaluator::new();
+
+        while let Some(res) = parser.parse_next() {
+            match res {
+                ParseResult::Success(expr) => evaluator.execute(&expr).unwrap(),
+                ParseResult::Failed(f) => panic!("{}", f)
+            }
+        }
+
+        assert_eq!(evaluator.get("sum"), Some(&Value::Int(30)));
+        assert_eq!(evaluator.get("last"), Some(&Value::Int(10)));
+        assert_eq!(evaluator.get("i"), None);
+    }
+
+    #[test]
+    fn test_break_and_continue() {
+        let mut parser = parse("
+            var n = 0; var after_break = false;
+            while n < 10 { n = n + 1; break; after_break = true; }
+
+            var m = 0; var after_continue = false;
+            while m < 5 { m = m + 1; continue; after_continue = true; }
+
+            var last = 0;
+            for i in 0..10 { last = i; break; }
+        ");
+        let mut evaluator = Evaluator::new();
+
+        while let Some(res) = parser.parse_next() {
+            match res {
+                ParseResult::Success(expr) => evaluator.execute(&expr).unwrap(),
+                ParseResult::Failed(f) => panic!("{}", f)
+            }
+        }
+
+        assert_eq!(evaluator.get("n"), Some(&Value::Int(1)));
+        assert_eq!(evaluator.get("after_break"), Some(&Value::Bool(false)));
+        assert_eq!(evaluator.get("m"), Some(&Value::Int(5)));
+        assert_eq!(evaluator.get("after_continue"), Some(&Value::Bool(false)));
+        assert_eq!(evaluator.get("last"), Some(&Value::Int(0)));
+    }
+
+    #[test]
+    fn test_else_if() {
+        let mut parser = parse("
+            var small = 0; var medium = 0; var large = 0;
+            for i in 0..10 {
+                if i < 3 { small = small + 1; }
+                else if i < 7 { medium = medium + 1; }
+                else { large = large + 1; }
+            }
+        ");
+        let mut evaluator = Evaluator::new();
+
+        while let Some(res) = parser.parse_next() {
+            match res {
+                ParseResult::Success(expr) => evaluator.execute(&expr).unwrap(),
+                ParseResult::Failed(f) => panic!("{}", f)
+            }
+        }
+
+        assert_eq!(evaluator.get("small"), Some(&Value::Int(3)));
+        assert_eq!(evaluator.get("medium"), Some(&Value::Int(4)));
+        assert_eq!(evaluator.get("large"), Some(&Value::Int(3)));
+    }
+
+    #[test]
+    fn test_evaluate_integer_operators() {
+        assert_eq!(eval("7 % 3 + (1 << 4) | 2 ^ 3 & 1"), Ok(Value::Int(19)));
+        assert_eq!(eval("-16 >> 2"), Ok(Value::Int(-4)));
+        assert_eq!(eval("5 & 1 == 1"), Ok(Value::Bool(true)));
+        assert_eq!(eval("1 % 0"), Err("Division by zero".to_string()));
+        assert_eq!(eval("1 << 32"), Err("Shift amount 32 is out of range".to_string()));
+    }
+
+    #[test]
+    fn test_null_checks() {
+        let mut parser = parse("var x : int? = null; var a = x == null; x = 3; var b = x == null; var c = x != null;");
+        let mut evaluator = Evaluator::new();
+
+        while let Some(res) = parser.parse_next() {
+            match res {
+                ParseResult::Success(expr) => evaluator.execute(&expr).unwrap(),
+                ParseResult::Failed(f) => panic!("{}", f)
+            }
+        }
+
+        assert_eq!(evaluator.get("a"), Some(&Value::Bool(true)));
+        assert_eq!(evaluator.get("b"), Some(&Value::Bool(false)));
+        assert_eq!(evaluator.get("c"), Some(&Value::Bool(true)));
+    }
+
+    #[test]
+    fn test_evaluate_integer_overflow() {
+        let overflow = Err("Integer overflow".to_string());
+
+        assert_eq!(eval("2147483647 + 1"), overflow);
+        assert_eq!(eval("-2147483647 - 2"), overflow);
+        assert_eq!(eval("65536 * 65536"), overflow);
+        assert_eq!(eval("(-2147483647 - 1) / -1"), overflow);
+        assert_eq!(eval("(-2147483647 - 1) % -1"), overflow);
+        assert_eq!(eval("-(-2147483647 - 1)"), overflow);
+
+        assert_eq!(eval("-2147483647 - 1"), Ok(Value::Int(i32::MIN)));
+    }
+}
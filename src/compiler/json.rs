@@ -0,0 +1,253 @@
+use compiler::token::Token;
+use compiler::parser::AstProgram;
+use compiler::parser::Expression;
+use compiler::parser::ExpressionType;
+
+// Quotes a string for JSON, escaping anything that can't appear raw
+pub fn quote(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c)
+        }
+    }
+
+    out.push('"');
+    return out
+}
+
+// The value carried by a token as a JSON value, None for tokens without one
+pub fn token_value(tok: &Token) -> Option<String> {
+    match *tok {
+        Token::Identifier(ref s) | Token::StringLiteral(ref s) | Token::Error(ref s) => return Some(quote(s)),
+        Token::IntegerLiteral(i) => return Some(i.to_string()),
+        // JSON has no NaN or infinity, so those come out as null
+        Token::FloatLiteral(f) if !f.is_finite() => return Some("null".to_string()),
+        Token::FloatLiteral(f) => return Some(format!("{:?}", f)),
+        Token::BooleanLiteral(b) => return Some(b.to_string()),
+        _ => return None
+    }
+}
+
+// Renders each token as {"kind"} plus its "value" when it carries one
+pub fn tokens_to_json(tokens: &[Token]) -> String {
+    let rendered: Vec<String> = tokens.iter()
+        .map(|t| {
+            // The variant name, without any payload Debug prints after it
+            let debug = format!("{:?}", t);
+            let kind = debug.split('(').next().unwrap_or("");
+
+            match token_value(t) {
+                Some(value) => format!("{{\"kind\":{},\"value\":{}}}", quote(kind), value),
+                None => format!("{{\"kind\":{}}}", quote(kind))
+            }
+        })
+        .collect();
+
+    return format!("[{}]", rendered.join(","))
+}
+
+// Renders every statement of the program as an array of expression objects
+pub fn program_to_json(program: &AstProgram) -> String {
+    let statements: Vec<String> = program.statements.iter()
+        .map(|s| expression_to_json(&s.expr))
+        .collect();
+
+    return format!("{{\"statements\":[{}]}}", statements.join(","))
+}
+
+// Renders an expression as {"id", "kind", "return_type", ...details, "children"}
+pub fn expression_to_json(expr: &Expression) -> String {
+    // Fields particular to the kind of node, between the common ones and the children
+    let mut details: Vec<String> = vec![];
+
+    let (kind, children): (&str, Vec<&Expression>) = match expr.expression_type {
+        ExpressionType::Literal(ref t) => {
+            if let Some(value) = token_value(t) {
+                let key = if let Token::Identifier(_) = *t { "name" } else { "value" };
+                details.push(format!("\"{}\":{}", key, value));
+            }
+            ("Literal", vec![])
+        },
+
+        ExpressionType::LiteralExpression(ref name, ref value) => {
+            details.push(format!("\"name\":{}", quote(name)));
+            ("Named", vec![value])
+        },
+
+        ExpressionType::AssignmentExpression(ref name, ref value) => {
+            details.push(format!("\"name\":{}", quote(name)));
+            ("Assign", vec![value])
+        },
+
+        ExpressionType::PrintExpression(ref s) => {
+            details.push(format!("\"value\":{}", quote(s)));
+            ("Print", vec![])
+        },
+
+        ExpressionType::BlockExpression(ref exprs) => ("Block", exprs.iter().collect()),
+
+        ExpressionType::VarExpression(ref var) => ("Var", vec![var]),
+
+        ExpressionType::ConstExpression(ref var) => ("Const", vec![var]),
+
+        ExpressionType::UnaryExpression(ref op, ref rhs) => {
+            details.push(format!("\"operator\":{}", quote(&op.to_string())));
+            ("Unary", vec![rhs])
+        },
+
+        ExpressionType::BinaryExpression(ref op, ref lhs, ref rhs) => {
+            details.push(format!("\"operator\":{}", quote(&op.to_string())));
+            ("Binary", vec![lhs, rhs])
+        },
+
+        ExpressionType::ConditionalExpression(ref cond, ref body, ref otherwise) => {
+            let mut children = vec![&**cond, &**body];
+            children.extend(otherwise.iter().map(|e| &**e));
+            ("Conditional", children)
+        },
+
+        ExpressionType::LoopExpression(ref body) => ("Loop", vec![body]),
+
+        ExpressionType::ForExpression(ref f) => {
+            details.push(format!("\"name\":{}", quote(&f.var)));
+            details.push(format!("\"inclusive\":{}", f.inclusive));
+            ("For", vec![&f.start, &f.end, &f.step, &f.body])
+        },
+
+        ExpressionType::WhileExpression(ref cond, ref body) => ("While", vec![cond, body]),
+
+        ExpressionType::BreakExpression => ("Break", vec![]),
+
+        ExpressionType::ContinueExpression => ("Continue", vec![]),
+
+        ExpressionType::FunctionExpression(ref f) => {
+            details.push(format!("\"name\":{}", quote(&f.header.name)));
+            ("Function", vec![&f.body])
+        },
+
+        ExpressionType::FunctionHeaderExpression(ref header) => {
+            details.push(format!("\"name\":{}", quote(&header.name)));
+            ("FunctionHeader", vec![])
+        },
+
+        ExpressionType::StructExpression(ref name, ref fields) => {
+            let rendered: Vec<String> = fields.iter()
+                .map(|(field, rt)| format!("{{\"name\":{},\"return_type\":{}}}", quote(field), quote(&format!("{:?}", rt))))
+                .collect();
+
+            details.push(format!("\"name\":{}", quote(name)));
+            details.push(format!("\"fields\":[{}]", rendered.join(",")));
+            ("Struct", vec![])
+        },
+
+        ExpressionType::CollectionExpression(ref elements) => ("Collection", elements.iter().collect()),
+
+        ExpressionType::CallExpression(ref name, ref args) => {
+            details.push(format!("\"name\":{}", quote(name)));
+            ("Call", args.iter().collect())
+        },
+
+        ExpressionType::StructLiteral(ref name, ref values) => {
+            let names: Vec<String> = values.iter().map(|v| quote(&v.0)).collect();
+
+            details.push(format!("\"name\":{}", quote(name)));
+            details.push(format!("\"fields\":[{}]", names.join(",")));
+            ("StructLiteral", values.iter().map(|v| &v.1).collect())
+        },
+
+        ExpressionType::FieldAccess(ref receiver, ref field) => {
+            details.push(format!("\"name\":{}", quote(field)));
+            ("FieldAccess", vec![receiver])
+        },
+
+        ExpressionType::IndexExpression(ref receiver, ref index) => ("Index", vec![receiver, index]),
+
+        ExpressionType::ReturnExpression(ref value) => ("Return", value.iter().map(|v| &**v).collect()),
+
+        ExpressionType::PromoteExpression(ref value) => ("Promote", vec![value])
+    };
+
+    let mut fields = vec![
+        format!("\"id\":{}", expr.id()),
+        format!("\"kind\":{}", quote(kind)),
+        format!("\"return_type\":{}", quote(&format!("{:?}", expr.return_type)))
+    ];
+    fields.append(&mut details);
+
+    let children: Vec<String> = children.into_iter().map(expression_to_json).collect();
+    fields.push(format!("\"children\":[{}]", children.join(",")));
+
+    return format!("{{{}}}", fields.join(","))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use compiler::Scanner;
+    use compiler::parser::Parser;
+    use compiler::parser::ParseResult;
+
+    #[test]
+    fn test_expression_to_json() {
+        let tokens = Scanner::new("1 + 2.5").tokenize();
+
+        let expr = match Parser::new(tokens).parse_bare_expression() {
+            ParseResult::Success(expr) => expr,
+            ParseResult::Failed(f) => panic!("{}", f)
+        };
+
+        assert_eq!(expression_to_json(&expr), concat!(
+            r#"{"id":4,"kind":"Binary","return_type":"ReturnFloat","operator":"+","children":["#,
+            r#"{"id":3,"kind":"Promote","return_type":"ReturnFloat","children":["#,
+            r#"{"id":1,"kind":"Literal","return_type":"ReturnInteger","value":1,"children":[]}]},"#,
+            r#"{"id":2,"kind":"Literal","return_type":"ReturnFloat","value":2.5,"children":[]}]}"#
+        ));
+    }
+
+    #[test]
+    fn test_program_to_json() {
+        let (program, errors) = Parser::new(Scanner::new("var s = \"hi\";").tokenize()).parse();
+        assert!(errors.is_empty());
+
+        assert_eq!(program.to_json(), concat!(
+            r#"{"statements":[{"id":3,"kind":"Var","return_type":"ReturnString","children":["#,
+            r#"{"id":2,"kind":"Named","return_type":"ReturnString","name":"s","children":["#,
+            r#"{"id":1,"kind":"Literal","return_type":"ReturnString","value":"hi","children":[]}]}]}]}"#
+        ));
+    }
+
+    #[test]
+    fn test_tokens_to_json() {
+        let tokens = Scanner::new("var x = 5;").tokenize();
+
+        assert_eq!(tokens_to_json(&tokens), concat!(
+            r#"[{"kind":"VarDecl"},{"kind":"Identifier","value":"x"},{"kind":"Assign"},"#,
+            r#"{"kind":"IntegerLiteral","value":5},{"kind":"Semicolon"},{"kind":"EOF"}]"#
+        ));
+    }
+
+    #[test]
+    fn test_non_finite_floats() {
+        assert_eq!(token_value(&Token::FloatLiteral(f64::NAN)), Some("null".to_string()));
+        assert_eq!(token_value(&Token::FloatLiteral(f64::INFINITY)), Some("null".to_string()));
+        assert_eq!(token_value(&Token::FloatLiteral(f64::NEG_INFINITY)), Some("null".to_string()));
+        assert_eq!(token_value(&Token::FloatLiteral(2.5)), Some("2.5".to_string()));
+
+        assert_eq!(tokens_to_json(&[Token::FloatLiteral(f64::NAN)]), r#"[{"kind":"FloatLiteral","value":null}]"#);
+    }
+
+    #[test]
+    fn test_quote_escapes() {
+        assert_eq!(quote("a\"b\\c\nd\u{1}"), r#""a\"b\\c\nd\u0001""#);
+    }
+}
@@ -1,14 +1,73 @@
 use std::str::Chars;
-use std::iter::Peekable;
+use std::collections::VecDeque;
 
 pub mod token;
 pub mod parser;
+pub mod evaluator;
+pub mod runtime;
+pub mod printer;
+pub mod codegen;
+pub mod optimize;
+pub mod json;
+pub mod visitor;
 
 use compiler::token::Token;
+use compiler::parser::Parser;
+use vm::VM;
+
+// Scans, parses and generates code for a whole program in one go. Line breaks
+// end statements, so a lone expression doesn't need a ';'.
+pub fn compile_source(source: &str) -> Result<Vec<u8>, String> {
+    let mut scanner = Scanner::new(source);
+    scanner.set_implicit_semicolons(true);
+
+    let (program, errors) = Parser::new(scanner.tokenize()).parse();
+    if !errors.is_empty() {
+        return Err(errors.join("\n"))
+    }
+
+    return program.to_bytecode()
+}
+
+// Compiles the source and runs it on a fresh VM, handing back the VM once it's done
+pub fn run_source(source: &str) -> Result<VM, String> {
+    let mut vm = VM::new();
+    vm.load_bytes(compile_source(source)?);
+
+    match vm.run() {
+        Ok(_) => return Ok(vm),
+        Err(e) => return Err(e.to_string())
+    }
+}
+
+// Where a token sits in the source: its line, the column it starts at and its
+// length, both counted in characters
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Span {
+    pub line: usize,
+    pub col: usize,
+    pub len: usize,
+}
 
 pub struct Scanner<'a> {
     line: usize,
-    source: Peekable<Chars<'a>>
+    // Characters consumed since the last line break
+    char_col: usize,
+    // Bytes consumed from the start of the source, for slicing out spans
+    byte_offset: usize,
+    source: Chars<'a>,
+    // Characters read ahead of the cursor but not yet consumed
+    lookahead: VecDeque<char>,
+    // Treat line ends as statement terminators where a ';' could go
+    implicit_semicolons: bool,
+    paren_depth: usize,
+    // Last token other than a comment, and the line it ended on
+    last: Option<Token>,
+    last_line: usize,
+    // Line and column the token being scanned started at
+    token_start: (usize, usize),
+    // Span of every token tokenize has returned
+    spans: Vec<Span>
 }
 
 fn is_letter(c: char) -> bool {
@@ -17,19 +76,107 @@ fn is_letter(c: char) -> bool {
 
 impl<'a> Scanner<'a> {
 
-    pub fn new(input: &str) -> Scanner {
+    pub fn new(input: &'a str) -> Scanner<'a> {
         Scanner {
             line: 0,
-            source: input.chars().peekable()
+            char_col: 0,
+            byte_offset: 0,
+            source: input.chars(),
+            lookahead: VecDeque::new(),
+            implicit_semicolons: false,
+            paren_depth: 0,
+            last: None,
+            last_line: 0,
+            token_start: (0, 0),
+            spans: vec![]
+        }
+    }
+
+    pub fn set_implicit_semicolons(&mut self, on: bool) {
+        self.implicit_semicolons = on;
+    }
+
+    // Column of the next character, counted in characters rather than bytes
+    pub fn char_col(&self) -> usize {
+        self.char_col
+    }
+
+    // Offset of the next character into the source in bytes
+    pub fn byte_offset(&self) -> usize {
+        self.byte_offset
+    }
+
+    // Spans of the tokens from tokenize, index for index
+    pub fn spans(&self) -> &[Span] {
+        &self.spans
+    }
+
+    // Span of the token next_token last returned. Tokens running over a line
+    // break, like multi-line strings, are cut off at the end of their first line.
+    fn last_span(&self) -> Span {
+        let (line, col) = self.token_start;
+        let len = if self.line == line { self.char_col.saturating_sub(col) } else { 1 };
+
+        return Span { line, col, len }
+    }
+
+    // A line break (or the end of input) outside parentheses or brackets ends a statement
+    // when the line finished on something that can end an expression
+    fn at_implicit_semicolon(&mut self) -> bool {
+        if !self.implicit_semicolons || self.paren_depth > 0 {
+            return false
+        }
+
+        if self.line == self.last_line && self.peek_char().is_some() {
+            return false
+        }
+
+        match self.last {
+            Some(Token::Identifier(_)) | Some(Token::IntegerLiteral(_)) |
+            Some(Token::FloatLiteral(_)) | Some(Token::StringLiteral(_)) |
+            Some(Token::BooleanLiteral(_)) | Some(Token::Null) |
+            Some(Token::RightParenthesis) | Some(Token::RightBracket) |
+            Some(Token::Break) | Some(Token::Continue) | Some(Token::Return) => return true,
+            _ => return false
+        }
+    }
+
+    // Buffers characters until n are available to peek at, or the input runs out
+    fn fill_lookahead(&mut self, n: usize) {
+        while self.lookahead.len() < n {
+            match self.source.next() {
+                Some(c) => self.lookahead.push_back(c),
+                None => break
+            }
         }
     }
 
     fn read_char(&mut self) -> Option<char> {
-        self.source.next()
+        self.fill_lookahead(1);
+        let ch = self.lookahead.pop_front();
+
+        if let Some(c) = ch {
+            self.byte_offset += c.len_utf8();
+
+            if c == '\n' {
+                self.char_col = 0;
+            } else {
+                self.char_col += 1;
+            }
+        }
+
+        return ch
     }
 
     fn peek_char(&mut self) -> Option<&char> {
-        self.source.peek()
+        self.fill_lookahead(1);
+        self.lookahead.front()
+    }
+
+    // The character after the one peek_char returns
+    fn peek_next(&mut self) -> Option<char> {
+        self.fill_lookahead(2);
+        self.lookahead.get(1).cloned()
     }
 
     fn peek_match(&mut self, ch: char) -> bool {
@@ -107,15 +254,36 @@ impl<'a> Scanner<'a> {
 
         // Check whether we're dealing with floating point
 
-        if self.peek_char() == Some(&'.') {
+        // A second '.' makes this the start of a range like 0..10 rather than a float
+        if self.peek_char() == Some(&'.') && self.peek_next() != Some('.') {
             s.push(self.read_char().unwrap());
 
+            if !self.peek_digit() {
+                return Token::Error(format!("Invalid float literal '{}': expected digits after '.'", s))
+            }
+
             while self.peek_digit() {
                 s.push(self.read_char().unwrap());
             }
-            return Token::FloatLiteral(s.parse().expect("Invalid floating pt number"))
+
+            // Swallow the rest of something like 1.2.3 so it's reported as one token
+            if self.peek_match('.') {
+                while self.peek_match('.') || self.peek_digit() {
+                    s.push(self.read_char().unwrap());
+                }
+                return Token::Error(format!("Invalid float literal '{}': more than one '.'", s))
+            }
+
+            match s.parse() {
+                Ok(f) => return Token::FloatLiteral(f),
+                Err(_) => return Token::Error(format!("Invalid float literal '{}'", s))
+            }
+        }
+        // Only digits were read, so the only way parsing fails is overflow
+        match s.parse() {
+            Ok(i) => return Token::IntegerLiteral(i),
+            Err(_) => return Token::Error(format!("Integer literal '{}' too large", s))
         }
-        return Token::IntegerLiteral(s.parse().expect("Invalid number"))
     }
 
     fn read_string(&mut self) -> Token {
@@ -136,13 +304,10 @@ impl<'a> Scanner<'a> {
         return Token::StringLiteral(s)
     }
 
+    // Consumes up to num characters, stopping early at the end of the source
     fn skip(&mut self, num: usize) {
-        let mut i = num;
-        loop {
-            self.source.next();
-
-            i -= 1;
-            if i == 0 {
+        for _ in 0..num {
+            if self.read_char().is_none() {
                 break;
             }
         }
@@ -150,6 +315,57 @@ impl<'a> Scanner<'a> {
 
     pub fn next_token(&mut self) -> Token {
         self.skip_whitespace();
+        self.token_start = (self.line, self.char_col);
+
+        if self.at_implicit_semicolon() {
+            self.last = Some(Token::Semicolon);
+            return Token::Semicolon
+        }
+
+        let tok = self.scan_token();
+
+        match tok {
+            Token::LeftParenthesis | Token::LeftBracket => self.paren_depth += 1,
+            Token::RightParenthesis | Token::RightBracket => self.paren_depth = self.paren_depth.saturating_sub(1),
+            _ => ()
+        }
+
+        if tok != Token::Comment {
+            self.last = Some(tok.clone());
+            self.last_line = self.line;
+        }
+
+        return tok
+    }
+
+    // Operators spelt with two characters, recognised by peeking at both before
+    // consuming either
+    fn scan_double(&mut self) -> Option<Token> {
+        let first = match self.peek_char() {
+            Some(&c) => c,
+            None => return None
+        };
+
+        let tok = match (first, self.peek_next()) {
+            ('=', Some('=')) => Token::Equality,
+            ('!', Some('=')) => Token::NotEquality,
+            ('<', Some('=')) => Token::LessThanEqual,
+            ('>', Some('=')) => Token::GreaterThanEqual,
+            ('<', Some('<')) => Token::ShiftLeft,
+            ('>', Some('>')) => Token::ShiftRight,
+            ('&', Some('&')) => Token::LogicalAnd,
+            ('|', Some('|')) => Token::LogicalOr,
+            _ => return None
+        };
+
+        self.skip(2);
+        return Some(tok)
+    }
+
+    fn scan_token(&mut self) -> Token {
+        if let Some(tok) = self.scan_double() {
+            return tok
+        }
 
         match self.read_char() {
 
@@ -167,89 +383,51 @@ impl<'a> Scanner<'a> {
 
             Some(',') => Token::Comma,
             Some('.') => {
-                if self.peek_match('.') {
+                if self.peek_match('.') && self.peek_next() == Some('=') {
+                    self.skip(2);
+                    Token::DotDotEqual
+                } else if self.peek_match('.') {
                     self.read_char();
-                    return Token::DotDot
+                    Token::DotDot
+                } else {
+                    Token::Dot
                 }
-                Token::Dot
             },
 
             Some('(') => Token::LeftParenthesis,
             Some(')') => Token::RightParenthesis,
             Some('{') => Token::LeftBrace,
             Some('}') => Token::RightBrace,
+            Some('[') => Token::LeftBracket,
+            Some(']') => Token::RightBracket,
 
             Some(';') => Token::Semicolon,
 
             Some(':') => Token::Colon,
 
+            Some('?') => Token::Question,
+
             Some('"') => self.read_string(),
 
             Some('^') => Token::Xor,
 
             Some('%') =>Token::Modulo,
 
-            Some('|') => {
-                if self.peek_match('|') {
-                    self.read_char();
-                    Token::LogicalOr
-                } else {
-                    Token::Or
-                }
-            },
+            Some('|') => Token::Or,
+            Some('&') => Token::And,
+            Some('=') => Token::Assign,
+            Some('!') => Token::Bang,
+            Some('<') => Token::LessThan,
+            Some('>') => Token::GreaterThan,
 
-            Some('&') => {
-                if self.peek_match('&') {
-                    self.read_char();
-                    Token::LogicalAnd
-                } else {
-                    Token::And
-                }
-            },
-
-            Some('=') => {
-                if self.peek_match('=') {
-                    self.read_char();
-                    Token::Equality
-                } else {
-                    Token::Assign
-                }
-            },
-
-            Some('!') => {
-                if self.peek_match('=') {
-                    self.read_char();
-                    Token::NotEquality
-                } else {
-                    Token::Bang
-                }
-            },
-
-            Some('<') => {
-                if self.peek_match('=') {
-                    self.read_char();
-                    Token::LessThanEqual
-                } else if self.peek_match('<') {
-                    self.read_char();
-                    Token::ShiftLeft
-                } else {
-                    Token::LessThan
-                }
-            },
-
-            Some('>') => {
-                if self.peek_match('=') {
-                    self.read_char();
-                    Token::GreaterThanEqual
-                } else if self.peek_match('>') {
-                    self.read_char();
-                    Token::ShiftRight
-                } else {
-                    Token::GreaterThan
-                }
+            // Raw strings like r"C:\path" take their contents verbatim. Plain strings
+            // don't process escapes yet either, so both share the same reader for now
+            Some('r') if self.peek_match('"') => {
+                self.read_char();
+                self.read_string()
             },
 
-            Some(ch @ _) => {
+            Some(ch) => {
                 if is_letter(ch) {
                     let ident = self.read_word(ch);
                     token::lookup(&ident)
@@ -266,6 +444,23 @@ impl<'a> Scanner<'a> {
         }
     }
 
+    // Scans the whole input, the returned tokens always end with EOF
+    pub fn tokenize(&mut self) -> Vec<Token> {
+        let mut tokens = vec!();
+
+        loop {
+            let tok = self.next_token();
+            tokens.push(tok.clone());
+            self.spans.push(self.last_span());
+
+            if tok == Token::EOF {
+                break;
+            }
+        }
+
+        return tokens
+    }
+
 }
 
 #[cfg(test)]
@@ -293,6 +488,34 @@ mod tests {
         assert_eq!(test_scanner.peek_char(), Some(&'a'));
     }
 
+    #[test]
+    fn test_peek_next() {
+        let mut test_scanner = get_test_scanner();
+
+        assert_eq!(test_scanner.peek_char(), Some(&'a'));
+        assert_eq!(test_scanner.peek_next(), Some('b'));
+        assert_eq!(test_scanner.read_char(), Some('a'));
+        assert_eq!(test_scanner.peek_next(), Some('1'));
+
+        assert_eq!(Scanner::new("x").peek_next(), None);
+    }
+
+    #[test]
+    fn test_double_char_operators() {
+        let tokens = Scanner::new("== != <= >= << >> && || = ! < > & |").tokenize();
+
+        assert_eq!(tokens, vec![
+            Token::Equality, Token::NotEquality, Token::LessThanEqual, Token::GreaterThanEqual,
+            Token::ShiftLeft, Token::ShiftRight, Token::LogicalAnd, Token::LogicalOr,
+            Token::Assign, Token::Bang, Token::LessThan, Token::GreaterThan, Token::And, Token::Or,
+            Token::EOF
+        ]);
+
+        // The longest operator wins, a trailing single character is left alone
+        assert_eq!(Scanner::new("<<=").tokenize(), vec![Token::ShiftLeft, Token::Assign, Token::EOF]);
+        assert_eq!(Scanner::new("x>").tokenize(), vec![Token::Identifier("x".to_string()), Token::GreaterThan, Token::EOF]);
+    }
+
     #[test]
     fn test_peek_match() {
         let mut test_scanner = get_test_scanner();
@@ -317,6 +540,142 @@ mod tests {
         assert_eq!(test_scanner.peek_digit(), true);
     }
 
+    #[test]
+    fn test_tokenize() {
+        let mut test_scanner = Scanner::new("1 + 2");
+
+        assert_eq!(test_scanner.tokenize(), vec![
+            Token::IntegerLiteral(1),
+            Token::Add,
+            Token::IntegerLiteral(2),
+            Token::EOF
+        ]);
+    }
+
+    #[test]
+    fn test_read_float() {
+        assert_eq!(Scanner::new("1.5").next_token(), Token::FloatLiteral(1.5));
+    }
+
+    #[test]
+    fn test_read_malformed_float() {
+        assert_eq!(Scanner::new("1.2.3").tokenize(), vec![
+            Token::Error("Invalid float literal '1.2.3': more than one '.'".to_string()),
+            Token::EOF
+        ]);
+        assert_eq!(Scanner::new("1.").tokenize(), vec![
+            Token::Error("Invalid float literal '1.': expected digits after '.'".to_string()),
+            Token::EOF
+        ]);
+    }
+
+    #[test]
+    fn test_read_integer_overflow() {
+        assert_eq!(Scanner::new("2147483647").next_token(), Token::IntegerLiteral(i32::MAX));
+        assert_eq!(Scanner::new("999999999999").next_token(),
+                   Token::Error("Integer literal '999999999999' too large".to_string()));
+    }
+
+    #[test]
+    fn test_range_literals() {
+        assert_eq!(Scanner::new("0..10").tokenize(), vec![
+            Token::IntegerLiteral(0),
+            Token::DotDot,
+            Token::IntegerLiteral(10),
+            Token::EOF
+        ]);
+        assert_eq!(Scanner::new("0..=10").tokenize(), vec![
+            Token::IntegerLiteral(0),
+            Token::DotDotEqual,
+            Token::IntegerLiteral(10),
+            Token::EOF
+        ]);
+    }
+
+    #[test]
+    fn test_raw_string() {
+        assert_eq!(Scanner::new(r#"r"a\nb""#).tokenize(), vec![
+            Token::StringLiteral("a\\nb".to_string()),
+            Token::EOF
+        ]);
+        assert_eq!(Scanner::new("r + rx").tokenize(), vec![
+            Token::Identifier("r".to_string()),
+            Token::Add,
+            Token::Identifier("rx".to_string()),
+            Token::EOF
+        ]);
+    }
+
+    #[test]
+    fn test_implicit_semicolons() {
+        let mut test_scanner = Scanner::new("var x = (1 +\n2) // sum\nx = x * 2\n\n");
+        test_scanner.set_implicit_semicolons(true);
+
+        assert_eq!(test_scanner.tokenize(), vec![
+            Token::VarDecl,
+            Token::Identifier("x".to_string()),
+            Token::Assign,
+            Token::LeftParenthesis,
+            Token::IntegerLiteral(1),
+            Token::Add,
+            Token::IntegerLiteral(2),
+            Token::RightParenthesis,
+            Token::Comment,
+            Token::Semicolon,
+            Token::Identifier("x".to_string()),
+            Token::Assign,
+            Token::Identifier("x".to_string()),
+            Token::Multiply,
+            Token::IntegerLiteral(2),
+            Token::Semicolon,
+            Token::EOF
+        ]);
+    }
+
+    #[test]
+    fn test_implicit_semicolons_after_keywords() {
+        let mut test_scanner = Scanner::new("while true {\nbreak\n}\nvar c = [1,\n2]\nreturn\n");
+        test_scanner.set_implicit_semicolons(true);
+
+        assert_eq!(test_scanner.tokenize(), vec![
+            Token::While,
+            Token::BooleanLiteral(true),
+            Token::LeftBrace,
+            Token::Break,
+            Token::Semicolon,
+            Token::RightBrace,
+            Token::VarDecl,
+            Token::Identifier("c".to_string()),
+            Token::Assign,
+            Token::LeftBracket,
+            Token::IntegerLiteral(1),
+            Token::Comma,
+            Token::IntegerLiteral(2),
+            Token::RightBracket,
+            Token::Semicolon,
+            Token::Return,
+            Token::Semicolon,
+            Token::EOF
+        ]);
+
+        let mut test_scanner = Scanner::new("continue\n");
+        test_scanner.set_implicit_semicolons(true);
+        assert_eq!(test_scanner.tokenize(), vec![Token::Continue, Token::Semicolon, Token::EOF]);
+    }
+
+    #[test]
+    fn test_brackets() {
+        assert_eq!(Scanner::new("[").next_token(), Token::LeftBracket);
+        assert_eq!(Scanner::new("]").next_token(), Token::RightBracket);
+
+        assert_eq!(Scanner::new("[1]").tokenize(), vec![
+            Token::LeftBracket,
+            Token::IntegerLiteral(1),
+            Token::RightBracket,
+            Token::EOF
+        ]);
+    }
+
     #[test]
     fn test_skip_whitespace() {
         let mut test_scanner = get_test_scanner();
@@ -328,4 +687,73 @@ mod tests {
         assert_eq!(test_scanner.peek_char(), Some(&'a'));
         assert_eq!(test_scanner.line, 1);
     }
+
+    #[test]
+    fn test_multibyte_offsets() {
+        let mut test_scanner = Scanner::new("\"h\u{e9}llo \u{1f600}\"\nx");
+
+        assert_eq!(test_scanner.next_token(), Token::StringLiteral("h\u{e9}llo \u{1f600}".to_string()));
+        // Seven single byte characters, quotes included, a two byte 'é' and a four byte emoji
+        assert_eq!(test_scanner.byte_offset(), 13);
+        assert_eq!(test_scanner.char_col(), 9);
+
+        assert_eq!(test_scanner.next_token(), Token::Identifier("x".to_string()));
+        assert_eq!(test_scanner.byte_offset(), 15);
+        assert_eq!(test_scanner.char_col(), 1);
+    }
+
+    #[test]
+    fn test_token_spans() {
+        let mut test_scanner = Scanner::new("var x = 10;\n  \"h\u{e9}\" >= y");
+        test_scanner.tokenize();
+
+        let span = |line, col, len| Span { line, col, len };
+
+        assert_eq!(test_scanner.spans(), &[
+            span(0, 0, 3), span(0, 4, 1), span(0, 6, 1), span(0, 8, 2), span(0, 10, 1),
+            span(1, 2, 4), span(1, 7, 2), span(1, 10, 1), span(1, 11, 0)
+        ]);
+    }
+
+    #[test]
+    fn test_skip_past_end() {
+        let mut test_scanner = Scanner::new("ab");
+
+        test_scanner.skip(0);
+        assert_eq!(test_scanner.peek_char(), Some(&'a'));
+
+        test_scanner.skip(1000);
+        assert_eq!(test_scanner.peek_char(), None);
+        assert_eq!(test_scanner.next_token(), Token::EOF);
+    }
+
+    #[test]
+    fn test_compile_source() {
+        let mut vm = VM::new();
+        vm.load_bytes(compile_source("1 + 2").unwrap());
+        vm.run().unwrap();
+
+        // 1 and 2 are loaded into $0 and $1, their sum into $2
+        assert_eq!(vm.registers[2], 3);
+
+        assert_eq!(compile_source("1 && true"), Err("'&&' expects ReturnBool operands, got ReturnInteger and ReturnBool".to_string()));
+    }
+
+    #[test]
+    fn test_run_source() {
+        let vm = run_source("var x = 2 + 3;\nvar y = x * 2").unwrap();
+
+        // Variables take the low registers in declaration order
+        assert_eq!(vm.registers[0], 5);
+        assert_eq!(vm.registers[1], 10);
+
+        assert!(run_source("var x = ;").is_err());
+    }
+
+    #[test]
+    fn test_run_source_newline_terminated_break() {
+        let vm = run_source("var n = 0\nwhile n < 10 {\n    n = n + 1\n    break\n}\n").unwrap();
+
+        assert_eq!(vm.registers[0], 1);
+    }
 }
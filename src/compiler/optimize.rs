@@ -0,0 +1,297 @@
+use compiler::parser::Expression;
+use compiler::parser::ExpressionType;
+use compiler::parser::ReturnType;
+use compiler::evaluator;
+use compiler::runtime::Value;
+
+// The value of a literal node, identifiers aren't static so they don't count
+fn literal_value(expr: &Expression) -> Option<Value> {
+    match expr.expression_type {
+        ExpressionType::Literal(ref t) => Value::from_literal(t),
+        _ => None
+    }
+}
+
+// Applies a pass to each direct child of the node, leaving the node itself alone
+fn map_children(expr: Expression, pass: fn(Expression) -> Expression) -> Expression {
+    let mut expr = expr;
+
+    let apply = |mut child: Box<Expression>| {
+        *child = pass(*child);
+        child
+    };
+
+    expr.expression_type = match expr.expression_type {
+        ExpressionType::UnaryExpression(op, rhs) => ExpressionType::UnaryExpression(op, apply(rhs)),
+
+        ExpressionType::BinaryExpression(op, lhs, rhs) => ExpressionType::BinaryExpression(op, apply(lhs), apply(rhs)),
+
+        ExpressionType::LiteralExpression(name, value) => ExpressionType::LiteralExpression(name, apply(value)),
+
+        ExpressionType::AssignmentExpression(name, value) => ExpressionType::AssignmentExpression(name, apply(value)),
+
+        ExpressionType::BlockExpression(exprs) => {
+            ExpressionType::BlockExpression(exprs.into_iter().map(pass).collect())
+        },
+
+        ExpressionType::VarExpression(var) => ExpressionType::VarExpression(apply(var)),
+
+        ExpressionType::ConstExpression(var) => ExpressionType::ConstExpression(apply(var)),
+
+        ExpressionType::ConditionalExpression(cond, body, otherwise) => {
+            ExpressionType::ConditionalExpression(apply(cond), apply(body), otherwise.map(apply))
+        },
+
+        ExpressionType::LoopExpression(body) => ExpressionType::LoopExpression(apply(body)),
+
+        ExpressionType::ForExpression(mut f) => {
+            f.start = apply(f.start);
+            f.end = apply(f.end);
+            f.step = apply(f.step);
+            f.body = apply(f.body);
+            ExpressionType::ForExpression(f)
+        },
+
+        ExpressionType::WhileExpression(cond, body) => ExpressionType::WhileExpression(apply(cond), apply(body)),
+
+        ExpressionType::FunctionExpression(mut f) => {
+            f.body = apply(f.body);
+            ExpressionType::FunctionExpression(f)
+        },
+
+        ExpressionType::CollectionExpression(elements) => {
+            ExpressionType::CollectionExpression(elements.into_iter().map(pass).collect())
+        },
+
+        ExpressionType::CallExpression(name, args) => {
+            ExpressionType::CallExpression(name, args.into_iter().map(pass).collect())
+        },
+
+        ExpressionType::StructLiteral(name, values) => {
+            ExpressionType::StructLiteral(name, values.into_iter().map(|(f, v)| (f, pass(v))).collect())
+        },
+
+        ExpressionType::FieldAccess(receiver, field) => ExpressionType::FieldAccess(apply(receiver), field),
+
+        ExpressionType::IndexExpression(receiver, index) => ExpressionType::IndexExpression(apply(receiver), apply(index)),
+
+        ExpressionType::ReturnExpression(value) => ExpressionType::ReturnExpression(value.map(apply)),
+
+        ExpressionType::PromoteExpression(value) => ExpressionType::PromoteExpression(apply(value)),
+
+        t => t
+    };
+
+    return expr
+}
+
+// Runs every pass over the tree, folding first so conditions are as static as possible
+pub fn optimize(expr: Expression) -> Expression {
+    return eliminate_dead_code(fold_constants(expr))
+}
+
+// Collapses operators whose operands are all literals into a single literal.
+// Anything that would fail at runtime, like division by zero or integer overflow,
+// is left as is.
+// Folded nodes keep their id, only the type and contents change.
+pub fn fold_constants(expr: Expression) -> Expression {
+    let mut expr = map_children(expr, fold_constants);
+
+    let value = match expr.expression_type {
+        ExpressionType::UnaryExpression(ref op, ref rhs) => {
+            literal_value(rhs)
+                .and_then(|v| evaluator::evaluate_unary(op, v).ok())
+        },
+
+        ExpressionType::PromoteExpression(ref value) => {
+            literal_value(value)
+                .and_then(|v| evaluator::promote(v).ok())
+        },
+
+        ExpressionType::BinaryExpression(ref op, ref lhs, ref rhs) => {
+            match (literal_value(lhs), literal_value(rhs)) {
+                (Some(l), Some(r)) => evaluator::evaluate_binary(op, l, r).ok(),
+                _ => None
+            }
+        },
+
+        _ => None
+    };
+
+    if let Some(value) = value {
+        expr.return_type = value.return_type();
+        expr.expression_type = ExpressionType::Literal(value.to_literal());
+    }
+
+    return expr
+}
+
+// Removes conditionals whose guard is a constant. A false guard can never run its
+// body so the whole conditional becomes its else branch, or an empty block without
+// one. A true guard is replaced by its body. Guards that aren't literals are left
+// for runtime, whatever they contain.
+pub fn eliminate_dead_code(expr: Expression) -> Expression {
+    let mut expr = map_children(expr, eliminate_dead_code);
+
+    let guard = match expr.expression_type {
+        ExpressionType::ConditionalExpression(ref cond, _, _) => literal_value(cond),
+        _ => None
+    };
+
+    match guard {
+        Some(Value::Bool(false)) => {
+            if let ExpressionType::ConditionalExpression(_, _, Some(otherwise)) = expr.expression_type {
+                return *otherwise
+            }
+
+            expr.expression_type = ExpressionType::BlockExpression(vec![]);
+            expr.return_type = ReturnType::ReturnBlock;
+        },
+
+        Some(Value::Bool(true)) => {
+            if let ExpressionType::ConditionalExpression(_, body, _) = expr.expression_type {
+                return *body
+            }
+        },
+
+        _ => ()
+    }
+
+    return expr
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use compiler::Scanner;
+    use compiler::token::Token;
+    use compiler::parser::Parser;
+    use compiler::parser::ParseResult;
+    use compiler::printer;
+
+    fn fold(source: &str) -> Expression {
+        let tokens = Scanner::new(source).tokenize();
+
+        let mut parser = Parser::new(tokens);
+
+        // Declare x so expressions can refer to it
+        if let Some(ParseResult::Failed(f)) = parser.parse_next() {
+            panic!("{}", f)
+        }
+
+        match parser.parse_bare_expression() {
+            ParseResult::Success(expr) => return fold_constants(expr),
+            ParseResult::Failed(f) => panic!("{}", f)
+        }
+    }
+
+    #[test]
+    fn test_fold_literals() {
+        let expr = fold("var x = 1; 2 + 3");
+
+        match expr.expression_type {
+            ExpressionType::Literal(Token::IntegerLiteral(5)) => (),
+            ref t => panic!("Expected a folded literal, got {:?}", t)
+        }
+        assert_eq!(expr.return_type, ReturnType::ReturnInteger);
+
+        let expr = fold("var x = 1; 2 + 3 * 4 < 10");
+        assert_eq!(printer::pretty_print(&expr), "Literal BooleanLiteral(false) : ReturnBool\n");
+    }
+
+    #[test]
+    fn test_fold_leaves_variables() {
+        let expr = fold("var x = 1; x + 3");
+
+        assert_eq!(printer::pretty_print(&expr), "\
+Binary Add : ReturnInteger
+  Literal Identifier(\"x\") : ReturnInteger
+  Literal IntegerLiteral(3) : ReturnInteger
+");
+
+        // Only the static half of the tree is folded
+        let expr = fold("var x = 1; x * 2 * 3 + 1 * 4");
+        assert_eq!(printer::pretty_print(&expr), "\
+Binary Add : ReturnInteger
+  Binary Multiply : ReturnInteger
+    Binary Multiply : ReturnInteger
+      Literal Identifier(\"x\") : ReturnInteger
+      Literal IntegerLiteral(2) : ReturnInteger
+    Literal IntegerLiteral(3) : ReturnInteger
+  Literal IntegerLiteral(4) : ReturnInteger
+");
+    }
+
+    #[test]
+    fn test_fold_leaves_overflow() {
+        let expr = fold("var x = 1; 2147483647 + 1");
+
+        assert_eq!(printer::pretty_print(&expr), "\
+Binary Add : ReturnInteger
+  Literal IntegerLiteral(2147483647) : ReturnInteger
+  Literal IntegerLiteral(1) : ReturnInteger
+");
+
+        let expr = fold("var x = 1; -(-2147483647 - 1)");
+        match expr.expression_type {
+            ExpressionType::UnaryExpression(Token::Subtract, _) => (),
+            ref t => panic!("Expected the negation to survive, got {:?}", t)
+        }
+    }
+
+    fn conditional(source: &str) -> Expression {
+        let tokens = Scanner::new(source).tokenize();
+
+        let mut parser = Parser::new(tokens);
+
+        let cond = match parser.parse_bare_expression() {
+            ParseResult::Success(expr) => expr,
+            ParseResult::Failed(f) => panic!("{}", f)
+        };
+
+        let body = Expression::new(0, ExpressionType::PrintExpression("reached".to_string()), ReturnType::ReturnString);
+
+        return Expression::new(0, ExpressionType::ConditionalExpression(Box::new(cond), Box::new(body), None), ReturnType::ReturnBlock)
+    }
+
+    #[test]
+    fn test_eliminate_false_conditional() {
+        let expr = optimize(conditional("1 > 2"));
+
+        assert_eq!(printer::pretty_print(&expr), "Block\n");
+    }
+
+    #[test]
+    fn test_inline_true_conditional() {
+        let expr = optimize(conditional("2 * 2 == 4"));
+
+        assert_eq!(printer::pretty_print(&expr), "Print \"reached\"\n");
+    }
+
+    #[test]
+    fn test_keep_runtime_conditional() {
+        // A guard that isn't static has to stay, body and all
+        let mut expr = conditional("true");
+        if let ExpressionType::ConditionalExpression(ref mut cond, _, _) = expr.expression_type {
+            cond.expression_type = ExpressionType::Literal(Token::Identifier("x".to_string()));
+        }
+
+        let expr = optimize(expr);
+        assert_eq!(printer::pretty_print(&expr), "\
+Conditional
+  Literal Identifier(\"x\") : ReturnBool
+  Print \"reached\"
+");
+    }
+
+    #[test]
+    fn test_fold_keeps_division_by_zero() {
+        let expr = fold("var x = 1; 1 / 0");
+
+        match expr.expression_type {
+            ExpressionType::BinaryExpression(..) => (),
+            ref t => panic!("Expected the division to survive, got {:?}", t)
+        }
+    }
+}
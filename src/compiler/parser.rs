@@ -1,6 +1,9 @@
 use std::collections::HashMap;
+use std::mem;
 
 use compiler::token::Token;
+use compiler::json;
+use compiler::codegen::Codegen;
 
 use std::clone::Clone;
 
@@ -20,6 +23,10 @@ impl Expression {
         }
     }
 
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
     pub fn to_string(&self) -> String {
         let mut ret = "Expr: ".to_string();
         ret.push_str(&self.id.to_string());
@@ -29,6 +36,25 @@ impl Expression {
     }
 }
 
+// Hands out expression ids, each one only once
+#[derive(Debug, Clone)]
+pub struct IdGen {
+    last: u32
+}
+
+impl IdGen {
+    pub fn new() -> IdGen {
+        IdGen {
+            last: 0
+        }
+    }
+
+    pub fn next_id(&mut self) -> u32 {
+        self.last += 1;
+        return self.last
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum ExpressionType {
 
@@ -48,49 +74,91 @@ pub enum ExpressionType {
     UnaryExpression(Token, Box<Expression>),
     BinaryExpression(Token, Box<Expression>, Box<Expression>),
 
-    ConditionalExpression(Box<Expression>, Box<Expression>),
+    // Condition, the body run when it holds and the else branch, which is
+    // another conditional for an `else if`
+    ConditionalExpression(Box<Expression>, Box<Expression>, Option<Box<Expression>>),
 
     LoopExpression(Box<Expression>),
 
+    // Loop counting a variable over an integer range
+    ForExpression(Box<ForLoop>),
+
+    // Loop running its body while the boolean condition holds
+    WhileExpression(Box<Expression>, Box<Expression>),
+
+    // Leaves the innermost loop
+    BreakExpression,
+
+    // Skips to the next iteration of the innermost loop
+    ContinueExpression,
+
     FunctionExpression(Box<Function>),
 
-    FunctionHeaderExpression(FunctionHeader)
+    FunctionHeaderExpression(FunctionHeader),
+
+    // Struct name and its fields in declaration order
+    StructExpression(String, Vec<(String, ReturnType)>),
+
+    // Elements of a collection literal, all of the same type
+    CollectionExpression(Vec<Expression>),
+
+    // Call to a declared function with its arguments
+    CallExpression(String, Vec<Expression>),
+
+    // An instance of a declared struct with a value for each field
+    StructLiteral(String, Vec<(String, Expression)>),
+
+    // Receiver and the name of the field read from it
+    FieldAccess(Box<Expression>, String),
+
+    // Collection and the position read from it
+    IndexExpression(Box<Expression>, Box<Expression>),
+
+    // Value returned from the enclosing function, None for a bare `return;`
+    ReturnExpression(Option<Box<Expression>>),
+
+    // Integer operand converted to a float so it can be combined with one
+    PromoteExpression(Box<Expression>)
 }
 
 #[derive(Clone, Debug)]
 pub struct Environment {
     pub enclosing: Option<Box<Environment>>,
-    pub node_count: u32,
-    pub vars: HashMap<String, Expression>,
-
+    pub vars: HashMap<String, VarEntry>,
+    // Declared struct types and their fields
+    pub structs: HashMap<String, Vec<(String, ReturnType)>>,
+    // Declared function signatures
+    pub functions: HashMap<String, FunctionHeader>,
 }
 
 impl Environment {
     pub fn new() -> Environment {
         Environment {
             enclosing: None,
-            node_count: 0,
-            vars: HashMap::new()
+            vars: HashMap::new(),
+            structs: HashMap::new(),
+            functions: HashMap::new()
         }
     }
 
     pub fn new_sub(env: Environment) -> Environment {
         Environment {
             enclosing: Some(Box::new(env)),
-            node_count: 0,
-            vars: HashMap::new()
+            vars: HashMap::new(),
+            structs: HashMap::new(),
+            functions: HashMap::new()
         }
     }
 
-    pub fn define(&mut self, var: Variable) -> ParseResult {
+    // Ids of the expressions built here come from the parser's generator, so they're unique across the program
+    pub fn define(&mut self, ids: &mut IdGen, var: Variable) -> ParseResult {
         match self.vars.get(&var.ident.clone()) {
             Some(_) => return ParseResult::Failed("Variable already defined".to_string()),
             _ => {
-                self.node_count += 1;
-                self.vars.insert(var.ident.clone(), var.value.clone());
+                self.vars.insert(var.ident.clone(), VarEntry::new(var.value.clone(), var.nullable));
                 return ParseResult::Success(
                     Expression::new(
-                        self.node_count,
+                        ids.next_id(),
                         ExpressionType::LiteralExpression(var.ident.clone(), Box::new(var.value.clone())),
                         var.value.return_type.clone()
                     )
@@ -99,14 +167,13 @@ impl Environment {
         }
     }
 
-    pub fn assign_value(&mut self, var: Variable) -> ParseResult {
+    pub fn assign_value(&mut self, ids: &mut IdGen, var: Variable) -> ParseResult {
         match self.vars.get_mut(&var.ident.clone()) {
-            Some(val) => {
-                self.node_count += 1;
-                *val = var.value.clone();
+            Some(entry) => {
+                entry.value = var.value.clone();
                 return ParseResult::Success(
                     Expression::new(
-                        self.node_count,
+                        ids.next_id(),
                         ExpressionType::LiteralExpression(var.ident.clone(), Box::new(var.value.clone())),
                         var.value.return_type.clone()
                     )
@@ -115,7 +182,7 @@ impl Environment {
             _ => {
                 match self.enclosing {
                     Some(ref mut env) => {
-                        return env.assign_value(var)
+                        return env.assign_value(ids, var)
                     },
                     _ => return ParseResult::Failed("Variable not defined".to_string())
                 }
@@ -123,30 +190,129 @@ impl Environment {
         }
     }
 
-    pub fn get_value(&mut self, var: String) -> ParseResult {
+    pub fn define_struct(&mut self, name: String, fields: Vec<(String, ReturnType)>) -> Result<(), String> {
+        if self.get_struct(&name).is_some() {
+            return Err(format!("Struct '{}' already defined", name))
+        }
+
+        self.structs.insert(name, fields);
+
+        return Ok(())
+    }
+
+    pub fn get_struct(&self, name: &str) -> Option<&Vec<(String, ReturnType)>> {
+        match self.structs.get(name) {
+            Some(fields) => return Some(fields),
+            None => {
+                match self.enclosing {
+                    Some(ref env) => return env.get_struct(name),
+                    None => return None
+                }
+            }
+        }
+    }
+
+    pub fn define_function(&mut self, header: FunctionHeader) -> Result<(), String> {
+        if self.get_function(&header.name).is_some() {
+            return Err(format!("Function '{}' already defined", header.name))
+        }
+
+        self.functions.insert(header.name.clone(), header);
+
+        return Ok(())
+    }
+
+    pub fn get_function(&self, name: &str) -> Option<&FunctionHeader> {
+        match self.functions.get(name) {
+            Some(header) => return Some(header),
+            None => {
+                match self.enclosing {
+                    Some(ref env) => return env.get_function(name),
+                    None => return None
+                }
+            }
+        }
+    }
+
+    pub fn get_value(&self, var: String) -> ParseResult {
         match self.vars.get(&var) {
-            Some(val) => return ParseResult::Success(val.clone()),
+            Some(entry) => return ParseResult::Success(entry.value.clone()),
             _ => {
                 match self.enclosing {
-                    Some(ref mut env) => return env.get_value(var),
+                    Some(ref env) => return env.get_value(var),
                     _ => return ParseResult::Failed("Variable doesn't exist".to_string())
                 }
             }
         }
     }
+
+    // Every variable in scope sorted by name, walking out through the enclosing
+    // scopes. Variables shadowed by an inner scope are left out.
+    pub fn variables(&self) -> Vec<(&String, &VarEntry)> {
+        let mut vars: Vec<(&String, &VarEntry)> = vec![];
+        let mut scope = Some(self);
+
+        while let Some(env) = scope {
+            for (name, entry) in &env.vars {
+                if !vars.iter().any(|v| v.0 == name) {
+                    vars.push((name, entry));
+                }
+            }
+            scope = env.enclosing.as_deref();
+        }
+
+        vars.sort_by(|a, b| a.0.cmp(b.0));
+        return vars
+    }
+
+    // Whether the variable was declared with a `?` type, None when it isn't declared
+    pub fn is_nullable(&self, var: &str) -> Option<bool> {
+        match self.vars.get(var) {
+            Some(entry) => return Some(entry.nullable),
+            None => {
+                match self.enclosing {
+                    Some(ref env) => return env.is_nullable(var),
+                    None => return None
+                }
+            }
+        }
+    }
+}
+
+// What the environment knows about a variable: the expression it was last given
+// and whether it can hold null
+#[derive(Clone, Debug)]
+pub struct VarEntry {
+    pub value: Expression,
+    pub nullable: bool
+}
+
+impl VarEntry {
+    pub fn new(value: Expression, nullable: bool) -> VarEntry {
+        VarEntry {
+            value,
+            nullable
+        }
+    }
 }
 
 #[derive(Clone, Debug)]
 pub struct Variable {
     ident: String,
-    value: Expression
+    value: Expression,
+    nullable: bool
 }
 
 impl Variable {
     fn new(name: String, val: Expression) -> Variable {
+        Variable::with_nullable(name, val, false)
+    }
+
+    fn with_nullable(name: String, val: Expression, nullable: bool) -> Variable {
         Variable {
             ident: name,
-            value: val
+            value: val,
+            nullable
         }
     }
 }
@@ -166,6 +332,8 @@ pub enum ReturnType {
     ReturnFunction,
     ReturnFunctionHeader,
     ReturnBlock,
+    // The null literal, only accepted where a nullable type is expected
+    ReturnNull,
     ReturnEOF
 }
 
@@ -182,6 +350,7 @@ impl From<Token> for ReturnType {
             Token::RightParenthesis => ReturnType::ReturnArguments,
             Token::Comma => ReturnType::ReturnContinue,
             Token::EOF => ReturnType::ReturnEOF,
+            Token::Null => ReturnType::ReturnNull,
             _ => ReturnType::ReturnInvalid
         }
     }
@@ -189,8 +358,8 @@ impl From<Token> for ReturnType {
 
 #[derive(Debug, Clone)]
 pub struct Argument {
-    return_type: ReturnType,
-    ident: String
+    pub return_type: ReturnType,
+    pub ident: String
 }
 
 impl Argument {
@@ -225,6 +394,17 @@ pub struct Function {
     pub body: Box<Expression>
 }
 
+// for var in start..end step n { body }, the end is only reached when inclusive
+#[derive(Debug, Clone)]
+pub struct ForLoop {
+    pub var: String,
+    pub start: Box<Expression>,
+    pub end: Box<Expression>,
+    pub inclusive: bool,
+    pub step: Box<Expression>,
+    pub body: Box<Expression>
+}
+
 #[derive(Debug, Clone)]
 pub enum ParseResult {
     Success(Expression),
@@ -259,51 +439,170 @@ impl AstProgram {
             env: Environment::new()
         }
     }
+
+    pub fn to_json(&self) -> String {
+        return json::program_to_json(self)
+    }
+
+    // Compiles every statement to VM bytecode
+    pub fn to_bytecode(&self) -> Result<Vec<u8>, String> {
+        return Codegen::new().compile(self)
+    }
+}
+
+// Failure for a keyword found where a name was expected
+fn reserved_keyword(t: &Token) -> ParseResult {
+    return ParseResult::Failed(format!("'{}' is a reserved keyword", t.keyword().unwrap_or("?")))
+}
+
+// Left and right binding powers of the binary operators, loosest first. Every
+// level is left associative, its right power being one above its left.
+pub fn binding_power(tok: &Token) -> Option<(u8, u8)> {
+    match *tok {
+        Token::LogicalOr => return Some((1, 2)),
+        Token::LogicalAnd => return Some((3, 4)),
+        Token::Equality | Token::NotEquality => return Some((5, 6)),
+        Token::LessThan | Token::GreaterThan |
+        Token::LessThanEqual | Token::GreaterThanEqual => return Some((7, 8)),
+        Token::Or => return Some((9, 10)),
+        Token::Xor => return Some((11, 12)),
+        Token::And => return Some((13, 14)),
+        Token::ShiftLeft | Token::ShiftRight => return Some((15, 16)),
+        Token::Add | Token::Subtract => return Some((17, 18)),
+        Token::Multiply | Token::Divide | Token::Modulo => return Some((19, 20)),
+        _ => return None
+    }
+}
+
+// Names the kind of operation for error messages
+fn operator_class(op: &Token) -> &'static str {
+    match *op {
+        Token::LogicalOr | Token::LogicalAnd => return "logical",
+        Token::Equality | Token::NotEquality => return "equality",
+        Token::Or | Token::Xor | Token::And => return "bitwise",
+        Token::ShiftLeft | Token::ShiftRight => return "shift",
+        Token::Add | Token::Subtract => return "addition",
+        Token::Multiply | Token::Divide | Token::Modulo => return "multiplication",
+        _ => return "comparison"
+    }
+}
+
+// The token stream ran dry part way through a construct
+fn unexpected_end() -> ParseResult {
+    return ParseResult::Failed("unexpected end of input".to_string())
 }
 
 pub struct Parser {
     program: AstProgram,
     tokens: Vec<Token>,
-    node_count: u32
+    // Index of the next token to be consumed
+    pos: usize,
+    ids: IdGen,
+    // Function whose body is being parsed, returns are checked against it
+    current_function: Option<FunctionHeader>,
+    // Number of loops around the current position, break and continue need one
+    loop_depth: usize,
+    // Failures recovered from while parsing a whole program
+    errors: Vec<String>
 }
 
 impl Parser {
-    pub fn new(mut toks: Vec<Token>) -> Parser {
+    pub fn new(toks: Vec<Token>) -> Parser {
+        Parser::with_environment(toks, Environment::new())
+    }
+
+    // Parses against an existing environment so earlier definitions stay visible
+    pub fn with_environment(mut toks: Vec<Token>, env: Environment) -> Parser {
+        toks.retain(|t| *t != Token::Comment);
+
+        let mut program = AstProgram::new();
+        program.env = env;
+
         Parser {
-            program: AstProgram::new(),
+            program,
             tokens: toks,
-            node_count: 0
+            pos: 0,
+            ids: IdGen::new(),
+            current_function: None,
+            loop_depth: 0,
+            errors: vec![]
+        }
+    }
+
+    // Next token without consuming it
+    pub fn peek(&self) -> Option<&Token> {
+        return self.tokens.get(self.pos)
+    }
+
+    // Consumes the next token
+    fn advance(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        if token.is_some() {
+            self.pos += 1;
         }
+
+        return token
     }
 
     fn parse_primary(&mut self) -> ParseResult {
 
-        let t = self.tokens.pop();
+        let t = self.advance();
 
         match t.clone() {
-            None => return ParseResult::Failed("Ran out of tokens".to_string()),
+            None => return unexpected_end(),
 
             Some(Token::StringLiteral(_)) | Some(Token::IntegerLiteral(_)) |
             Some(Token::FloatLiteral(_)) | Some(Token:: BooleanLiteral(_)) |
             Some(Token::CollectionLiteral) | Some(Token::RangeLiteral) |
-            Some(Token::Identifier(_)) | Some(Token::Null) => {
+            Some(Token::Null) => {
                 let rt = ReturnType::from(t.clone().unwrap());
-                self.node_count += 1;
 
                 return ParseResult::Success(Expression::new(
-                        self.node_count,
+                        self.ids.next_id(),
                         ExpressionType::Literal(t.unwrap()),
                         rt));
             },
 
+            Some(Token::Identifier(name)) => {
+                if self.peek() == Some(&Token::LeftBrace) && self.program.env.get_struct(&name).is_some() {
+                    return self.parse_struct_literal(name)
+                }
+
+                if self.peek() == Some(&Token::LeftParenthesis) && self.program.env.get_function(&name).is_some() {
+                    return self.parse_call(name)
+                }
+
+                match self.program.env.get_value(name.clone()) {
+                    ParseResult::Success(val) => {
+
+                        return ParseResult::Success(Expression::new(
+                                self.ids.next_id(),
+                                ExpressionType::Literal(Token::Identifier(name)),
+                                val.return_type));
+                    },
+                    _ => return ParseResult::Failed(format!("Undefined variable '{}'", name))
+                }
+            },
+
+            Some(Token::LeftBracket) => return self.parse_collection(),
+
+            Some(Token::LeftParenthesis) => {
+                let inner = self.parse_expression();
+                match self.advance() {
+                    Some(Token::RightParenthesis) => return inner,
+                    Some(_) => return ParseResult::Failed("Expected ')'".to_string()),
+                    None => return unexpected_end(),
+                }
+            },
+
             Some(Token::LeftBrace) => {
                 let rhs = self.parse_expression();
-                match self.tokens.pop() {
+                match self.advance() {
                     Some(Token::RightBrace) => {
                         return rhs
                     },
                     Some(_) => return ParseResult::Failed("Expected ')'".to_string()),
-                    None => return ParseResult::Failed("Ran out of tokens".to_string()),
+                    None => return unexpected_end(),
                 }
             },
 
@@ -314,318 +613,496 @@ impl Parser {
         }
     }
 
-    fn parse_unary(&mut self) -> ParseResult {
+    // [a, b, c] with every element the same type as the first
+    fn parse_collection(&mut self) -> ParseResult {
+        let mut elements: Vec<Expression> = vec![];
+
+        if self.peek() == Some(&Token::RightBracket) {
+            self.advance();
+        } else {
+            loop {
+                match self.parse_expression() {
+                    ParseResult::Success(element) => {
+                        if let Some(first) = elements.first() {
+                            if element.return_type != first.return_type {
+                                return ParseResult::Failed(format!("Mixed element types in collection: {:?} and {:?}",
+                                                                   first.return_type, element.return_type))
+                            }
+                        }
+                        elements.push(element);
+                    },
+                    failed => return failed
+                }
 
-        let t = self.tokens.pop();
-        let rt = ReturnType::from(t.clone().unwrap());
+                match self.advance() {
+                    Some(Token::Comma) => (),
+                    Some(Token::RightBracket) => break,
+                    _ => return ParseResult::Failed("Expected ',' or ']' after element".to_string())
+                }
+            }
+        }
 
-        match t.clone() {
-            None => return ParseResult::Failed("Ran out of tokens..".to_string()),
+        return ParseResult::Success(Expression::new(
+                self.ids.next_id(),
+                ExpressionType::CollectionExpression(elements),
+                ReturnType::ReturnCollection))
+    }
 
-            Some(Token::Bang) | Some(Token::Subtract) => {
-                let rcmp = self.parse_unary();
+    // name(arg, arg), checked against the declared header
+    fn parse_call(&mut self, name: String) -> ParseResult {
+        let header = match self.program.env.get_function(&name) {
+            Some(header) => header.clone(),
+            None => return ParseResult::Failed(format!("Undefined function '{}'", name))
+        };
 
-                match rcmp.clone() {
+        self.advance();
 
-                    ParseResult::Success(rhs) => {
-                        self.node_count += 1;
+        let mut args: Vec<Expression> = vec![];
 
-                        return ParseResult::Success(Expression::new(
-                                self.node_count,
-                                ExpressionType::UnaryExpression(t.unwrap(), Box::new(rhs)),
-                                rt))
-                    },
-                    _ => return ParseResult::Failed("Failed unary".to_string())
+        if self.peek() == Some(&Token::RightParenthesis) {
+            self.advance();
+        } else {
+            loop {
+                match self.parse_expression() {
+                    ParseResult::Success(arg) => args.push(arg),
+                    failed => return failed
                 }
-            },
-            Some(_) => return self.parse_primary()
+
+                match self.advance() {
+                    Some(Token::Comma) => (),
+                    Some(Token::RightParenthesis) => break,
+                    _ => return ParseResult::Failed("Expected ',' or ')' after argument".to_string())
+                }
+            }
+        }
+
+        if args.len() != header.args.len() {
+            return ParseResult::Failed(format!("Function '{}' expects {} arguments, got {}", name, header.args.len(), args.len()))
+        }
+
+        for (i, (arg, expected)) in args.iter().zip(header.args.iter()).enumerate() {
+            if arg.return_type != expected.return_type {
+                return ParseResult::Failed(format!("Argument {} of '{}' should be {:?}, got {:?}",
+                                                   i + 1, name, expected.return_type, arg.return_type))
+            }
         }
+
+        return ParseResult::Success(Expression::new(
+                self.ids.next_id(),
+                ExpressionType::CallExpression(name, args),
+                header.return_type))
     }
 
-    fn parse_multiplication(&mut self) -> ParseResult {
-        let mut cmp = self.parse_unary();
+    // Point { x: 1, y: 2 }, every field must be given exactly once
+    fn parse_struct_literal(&mut self, name: String) -> ParseResult {
+        let fields = self.program.env.get_struct(&name).cloned().unwrap_or_default();
+
+        self.advance();
+
+        let mut values: Vec<(String, Expression)> = vec![];
 
         loop {
-            match cmp.clone() {
-                ParseResult::Success(lr) => {
+            if self.peek() == Some(&Token::RightBrace) {
+                self.advance();
+                break;
+            }
 
-                let lhs = lr.clone();
+            let field = match self.advance() {
+                Some(Token::Identifier(ident)) => ident,
+                _ => return ParseResult::Failed("Expected field name".to_string())
+            };
 
-                    let t = self.tokens.pop();
-                    let rt = lhs.return_type.clone();
+            let field_type = match fields.iter().find(|f| f.0 == field) {
+                Some(f) => f.1.clone(),
+                None => return ParseResult::Failed(format!("Struct '{}' has no field '{}'", name, field))
+            };
 
-                    match t.clone() {
-                        None => return ParseResult::Failed("Ran out of tokens..".to_string()),
+            if values.iter().any(|v| v.0 == field) {
+                return ParseResult::Failed(format!("Field '{}' given more than once", field))
+            }
 
-                        Some(Token::Multiply) | Some(Token::Divide) => {
-                            let rcmp = self.parse_unary();
+            if self.advance() != Some(Token::Colon) {
+                return ParseResult::Failed("Expected ':' after field name".to_string())
+            }
 
-                            match rcmp.clone() {
+            let value = match self.parse_expression() {
+                ParseResult::Success(value) => value,
+                failed => return failed
+            };
 
-                                ParseResult::Success(rhs) => {
-                                    if rt != rhs.return_type.clone() {
-                                        return ParseResult::Failed("Comparing different return types!".to_string())
-                                    } else {
-                                        self.node_count += 1;
+            if value.return_type != field_type {
+                return ParseResult::Failed(format!("Mismatched type for field '{}'", field))
+            }
 
-                                        cmp = ParseResult::Success(Expression::new(
-                                                self.node_count,
-                                                ExpressionType::BinaryExpression(t.unwrap(), Box::new(lhs), Box::new(rhs)),
-                                                rt));
-                                    }
-                                },
-                                _ => return ParseResult::Failed("Failed multiplication RHS".to_string())
-                            }
-                        },
+            values.push((field, value));
 
-                        Some(_) => return cmp
-                    }
+            match self.peek() {
+                Some(&Token::Comma) => {
+                    self.advance();
                 },
+                Some(&Token::RightBrace) => (),
+                _ => return ParseResult::Failed("Expected ',' or '}' after field".to_string())
+            }
+        }
+
+        if let Some(missing) = fields.iter().find(|f| !values.iter().any(|v| v.0 == f.0)) {
+            return ParseResult::Failed(format!("Missing field '{}' for struct '{}'", missing.0, name))
+        }
 
-                _ => {
-                    println!("Failed multiplication");
-                    return cmp
+        return ParseResult::Success(Expression::new(
+                self.ids.next_id(),
+                ExpressionType::StructLiteral(name, values),
+                ReturnType::ReturnStruct))
+    }
+
+    // The struct a value was built from, when it can be known while parsing
+    fn struct_name_of(&self, expr: &Expression) -> Option<String> {
+        match expr.expression_type {
+            ExpressionType::StructLiteral(ref name, _) => return Some(name.clone()),
+
+            ExpressionType::Literal(Token::Identifier(ref var)) => {
+                match self.program.env.get_value(var.clone()) {
+                    ParseResult::Success(value) => return self.struct_name_of(&value),
+                    ParseResult::Failed(_) => return None
                 }
-            }
+            },
+
+            _ => return None
+        }
+    }
+
+    // Element type of a collection, when it can be known while parsing
+    fn element_type_of(&self, expr: &Expression) -> Option<ReturnType> {
+        match expr.expression_type {
+            ExpressionType::CollectionExpression(ref elements) => return elements.first().map(|e| e.return_type.clone()),
+
+            ExpressionType::Literal(Token::Identifier(ref var)) => {
+                match self.program.env.get_value(var.clone()) {
+                    ParseResult::Success(value) => return self.element_type_of(&value),
+                    ParseResult::Failed(_) => return None
+                }
+            },
+
+            _ => return None
         }
     }
 
-    fn parse_addition(&mut self) -> ParseResult {
-        let mut cmp = self.parse_multiplication();
+    // A primary followed by any number of .field accesses and [index] lookups
+    fn parse_postfix(&mut self) -> ParseResult {
+        let mut res = self.parse_primary();
 
         loop {
-            match cmp.clone() {
-                ParseResult::Success(lr) => {
+            let expr = match res {
+                ParseResult::Success(expr) => expr,
+                failed => return failed
+            };
+
+            res = match self.peek() {
+                Some(&Token::Dot) => self.parse_field_access(expr),
+                Some(&Token::LeftBracket) => self.parse_index(expr),
+                _ => return ParseResult::Success(expr)
+            };
+        }
+    }
 
-                let lhs = lr.clone();
+    fn parse_field_access(&mut self, receiver: Expression) -> ParseResult {
+        self.advance();
 
-                    let t = self.tokens.pop();
-                    let rt = lhs.return_type.clone();
+        let field = match self.advance() {
+            Some(Token::Identifier(field)) => field,
+            _ => return ParseResult::Failed("Expected field name after '.'".to_string())
+        };
 
-                    match t.clone() {
-                        None => return ParseResult::Failed("Ran out of tokens..".to_string()),
+        // Without a known struct the field can't be checked, or typed
+        let rt = match self.struct_name_of(&receiver) {
+            Some(name) => {
+                let fields = self.program.env.get_struct(&name).cloned().unwrap_or_default();
 
-                        Some(Token::Add) | Some(Token::Subtract) => {
-                            let rcmp = self.parse_multiplication();
+                match fields.iter().find(|f| f.0 == field) {
+                    Some(f) => f.1.clone(),
+                    None => return ParseResult::Failed(format!("Struct '{}' has no field '{}'", name, field))
+                }
+            },
+            None => ReturnType::ReturnInvalid
+        };
 
-                            match rcmp.clone() {
+        return ParseResult::Success(Expression::new(
+                self.ids.next_id(),
+                ExpressionType::FieldAccess(Box::new(receiver), field),
+                rt))
+    }
 
-                                ParseResult::Success(rhs) => {
-                                    if rt != rhs.return_type.clone() {
-                                        return ParseResult::Failed("Comparing different return types!".to_string())
-                                    } else {
-                                        self.node_count += 1;
+    fn parse_index(&mut self, receiver: Expression) -> ParseResult {
+        self.advance();
 
-                                        cmp = ParseResult::Success(Expression::new(
-                                                self.node_count,
-                                                ExpressionType::BinaryExpression(t.unwrap(), Box::new(lhs), Box::new(rhs)),
-                                                rt));
-                                    }
-                                },
-                                _ => return ParseResult::Failed("Failed addition RHS".to_string())
-                            }
-                        },
+        if receiver.return_type != ReturnType::ReturnCollection {
+            return ParseResult::Failed(format!("Only collections can be indexed, got {:?}", receiver.return_type))
+        }
 
-                        Some(_) => return cmp
-                    }
-                },
+        let index = match self.parse_expression() {
+            ParseResult::Success(index) => index,
+            failed => return failed
+        };
 
-                _ => {
-                    println!("Failed addition");
-                    return cmp
-                }
-            }
+        if index.return_type != ReturnType::ReturnInteger {
+            return ParseResult::Failed(format!("Collection index must be ReturnInteger, got {:?}", index.return_type))
         }
-    }
 
-    fn parse_comparison(&mut self) -> ParseResult {
-        let mut cmp = self.parse_addition();
+        if self.advance() != Some(Token::RightBracket) {
+            return ParseResult::Failed("Expected ']' after index".to_string())
+        }
 
-        loop {
-            match cmp.clone() {
-                ParseResult::Success(lr) => {
+        let rt = self.element_type_of(&receiver).unwrap_or(ReturnType::ReturnInvalid);
 
-                let lhs = lr.clone();
+        return ParseResult::Success(Expression::new(
+                self.ids.next_id(),
+                ExpressionType::IndexExpression(Box::new(receiver), Box::new(index)),
+                rt))
+    }
 
-                    let t = self.tokens.pop();
-                    let rt = lhs.return_type.clone();
+    fn parse_unary(&mut self) -> ParseResult {
+        if self.peek().is_none() {
+            return unexpected_end()
+        }
 
-                    match t.clone() {
-                        None => return ParseResult::Failed("Ran out of tokens..".to_string()),
+        let op = match self.match_operator(&[Token::Bang, Token::Subtract]) {
+            Some(op) => op,
+            None => return self.parse_postfix()
+        };
+
+        let rhs = match self.parse_unary() {
+            ParseResult::Success(rhs) => rhs,
+            _ => return ParseResult::Failed("Failed unary".to_string())
+        };
+
+        // '!' only applies to booleans, '-' to numbers and keeps their type
+        let rt = match (&op, &rhs.return_type) {
+            (&Token::Bang, &ReturnType::ReturnBool) => ReturnType::ReturnBool,
+            (&Token::Bang, operand) => {
+                return ParseResult::Failed(format!("'!' expects ReturnBool, got {:?}", operand))
+            },
+            (_, &ReturnType::ReturnInteger) => ReturnType::ReturnInteger,
+            (_, &ReturnType::ReturnFloat) => ReturnType::ReturnFloat,
+            (_, operand) => {
+                return ParseResult::Failed(format!("'-' expects a number, got {:?}", operand))
+            }
+        };
 
-                        Some(Token::GreaterThan) | Some(Token::LessThan) |
-                        Some(Token:: LessThanEqual) | Some(Token:: GreaterThanEqual) => {
-                            let rcmp = self.parse_addition();
 
-                            match rcmp.clone() {
+        return ParseResult::Success(Expression::new(self.ids.next_id(), ExpressionType::UnaryExpression(op, Box::new(rhs)), rt))
+    }
 
-                                ParseResult::Success(rhs) => {
-                                    if rt != rhs.return_type {
-                                        return ParseResult::Failed("Comparing different return types!".to_string())
-                                    } else {
-                                        self.node_count += 1;
+    // Arithmetic operands of the same type are left alone. An integer mixed with a
+    // float is wrapped in a promotion so the result is a float, anything else
+    // can't be combined.
+    fn promote_operands(&mut self, lhs: Expression, rhs: Expression) -> Option<(Expression, Expression, ReturnType)> {
+        match (lhs.return_type.clone(), rhs.return_type.clone()) {
+            (ref l, ref r) if l == r => return Some((lhs, rhs, l.clone())),
+            (ReturnType::ReturnInteger, ReturnType::ReturnFloat) => {
+                let lhs = self.promote(lhs);
+                return Some((lhs, rhs, ReturnType::ReturnFloat))
+            },
+            (ReturnType::ReturnFloat, ReturnType::ReturnInteger) => {
+                let rhs = self.promote(rhs);
+                return Some((lhs, rhs, ReturnType::ReturnFloat))
+            },
+            _ => return None
+        }
+    }
 
-                                        cmp = ParseResult::Success(Expression::new(
-                                                self.node_count,
-                                                ExpressionType::BinaryExpression(t.unwrap(), Box::new(lhs), Box::new(rhs)),
-                                                rt));
-                                    }
-                                },
-                                _ => return ParseResult::Failed("Failed comparison RHS".to_string())
-                            }
-                        },
+    fn promote(&mut self, expr: Expression) -> Expression {
 
-                        Some(_) => return cmp
-                    }
-                },
+        return Expression::new(
+            self.ids.next_id(),
+            ExpressionType::PromoteExpression(Box::new(expr)),
+            ReturnType::ReturnFloat
+        )
+    }
 
-                _ => {
-                    println!("Failed comparison");
-                    return cmp
-                }
-            }
+    // Consumes the next token if it is one of the operators
+    fn match_operator(&mut self, operators: &[Token]) -> Option<Token> {
+        match self.peek() {
+            Some(t) if operators.contains(t) => return self.advance(),
+            _ => return None
         }
     }
 
-    fn parse_equality(&mut self) -> ParseResult {
-        let mut cmp = self.parse_comparison();
+    // Precedence climbing over every binary operator. An operator is only taken while
+    // its left binding power is at least min_bp, its right operand is parsed with its
+    // right power, so tighter operators end up deeper in the tree.
+    fn parse_binary(&mut self, min_bp: u8) -> ParseResult {
+        let mut lhs = match self.parse_unary() {
+            ParseResult::Success(expr) => expr,
+            failed => return failed
+        };
 
         loop {
+            let right_bp = match self.peek().and_then(binding_power) {
+                Some((left_bp, right_bp)) if left_bp >= min_bp => right_bp,
+                _ => return ParseResult::Success(lhs)
+            };
 
-            match cmp.clone() {
-                ParseResult::Success(lr) => {
-
-                let lhs = lr.clone();
+            let op = match self.advance() {
+                Some(op) => op,
+                None => return unexpected_end()
+            };
 
-                    let t = self.tokens.pop();
-                    let rt = lhs.return_type.clone();
+            let rhs = match self.parse_binary(right_bp) {
+                ParseResult::Success(rhs) => rhs,
+                _ => return ParseResult::Failed(format!("Failed {} RHS", operator_class(&op)))
+            };
 
-                    match t.clone() {
+            lhs = match self.binary(op, lhs, rhs) {
+                ParseResult::Success(expr) => expr,
+                failed => return failed
+            };
+        }
+    }
 
-                        None => {
-                            return ParseResult::Failed("Ran out of tokens".to_string())
-                        },
+    // Checks the operand types and builds the binary expression with its result type
+    fn binary(&mut self, op: Token, lhs: Expression, rhs: Expression) -> ParseResult {
+        let (lhs, rhs, rt) = match op {
+            Token::Add | Token::Subtract | Token::Multiply | Token::Divide => {
+                match self.promote_operands(lhs, rhs) {
+                    Some(operands) => operands,
+                    None => return ParseResult::Failed("Comparing different return types!".to_string())
+                }
+            },
 
-                        Some(Token::NotEquality) | Some(Token::Equality) => {
-                            let rcmp = self.parse_comparison();
+            Token::LogicalAnd | Token::LogicalOr => {
+                if lhs.return_type != ReturnType::ReturnBool || rhs.return_type != ReturnType::ReturnBool {
+                    return ParseResult::Failed(format!("'{}' expects ReturnBool operands, got {:?} and {:?}", op, lhs.return_type, rhs.return_type))
+                }
 
-                            match rcmp.clone() {
+                (lhs, rhs, ReturnType::ReturnBool)
+            },
 
-                                ParseResult::Success(rhs) => {
-                                    if rt != rhs.return_type {
-                                        return ParseResult::Failed("Comparing different return types!".to_string())
-                                    } else {
-                                        self.node_count += 1;
+            Token::Modulo | Token::Or | Token::Xor | Token::And |
+            Token::ShiftLeft | Token::ShiftRight => {
+                if lhs.return_type != ReturnType::ReturnInteger || rhs.return_type != ReturnType::ReturnInteger {
+                    return ParseResult::Failed(format!("'{}' expects ReturnInteger operands, got {:?} and {:?}", op, lhs.return_type, rhs.return_type))
+                }
 
-                                        cmp = ParseResult::Success(Expression::new(
-                                                self.node_count,
-                                                ExpressionType::BinaryExpression(t.unwrap(), Box::new(lhs), Box::new(rhs)),
-                                                rt));
-                                    }
-                                },
-                                _ => return ParseResult::Failed("Failed equality comparison".to_string())
-                            }
-                        },
+                (lhs, rhs, ReturnType::ReturnInteger)
+            },
 
-                        Some(_) => return cmp
-                    }
-                },
+            // A nullable variable can be tested against null
+            Token::Equality | Token::NotEquality if self.null_check(&lhs, &rhs) || self.null_check(&rhs, &lhs) => {
+                (lhs, rhs, ReturnType::ReturnBool)
+            },
 
-                _ => {
-                    println!("Failed equality");
-                    return cmp
+            // Comparisons and equality
+            _ => {
+                if lhs.return_type != rhs.return_type {
+                    return ParseResult::Failed("Comparing different return types!".to_string())
                 }
+
+                (lhs, rhs, ReturnType::ReturnBool)
             }
+        };
+
+        return ParseResult::Success(Expression::new(self.ids.next_id(), ExpressionType::BinaryExpression(op, Box::new(lhs), Box::new(rhs)), rt))
+    }
+
+    // Whether value is a nullable variable and other the null literal
+    fn null_check(&self, value: &Expression, other: &Expression) -> bool {
+        if other.return_type != ReturnType::ReturnNull {
+            return false
+        }
+
+        match value.expression_type {
+            ExpressionType::Literal(Token::Identifier(ref name)) => return self.program.env.is_nullable(name) == Some(true),
+            _ => return false
         }
-        return cmp;
     }
 
     fn parse_assignment(&mut self) -> ParseResult {
-        let lh = self.parse_equality();
+        let expr_l = match self.parse_binary(0) {
+            ParseResult::Success(expr) => expr,
+            failed => {
+                println!("Failed assignment");
+                return failed
+            }
+        };
+
+        match self.peek() {
+            Some(&Token::Assign) => {
+                self.advance();
+                let mut rh = match self.parse_assignment() {
+                    ParseResult::Success(rh) => rh,
+                    _ => return ParseResult::Failed("Failed RHS of assignment".to_string())
+                };
+
+                if rh.return_type == ReturnType::ReturnNull {
+                    if let ExpressionType::Literal(Token::Identifier(ref name)) = expr_l.expression_type {
+                        if self.program.env.is_nullable(name) == Some(false) {
+                            return ParseResult::Failed(format!("Can't assign null to non-nullable '{}' of type {:?}", name, expr_l.return_type))
+                        }
+                    }
+                    rh.return_type = expr_l.return_type.clone();
+                }
 
-        match lh.clone() {
-            ParseResult::Success(expr_l) => {
+                if rh.return_type != expr_l.return_type {
+                    return ParseResult::Failed("Mismatched types".to_string())
+                }
 
-            let popped = self.tokens.clone().pop();
+                match expr_l.expression_type {
+                    ExpressionType::Literal(Token::Identifier(name)) => {
 
-                match popped {
-                    None => return ParseResult::Failed("Out of tokens".to_string()),
-
-                    Some(Token::Assign) => {
-                        self.tokens.pop();
-                        let res = self.parse_assignment();
-
-                        match res {
-                            ParseResult::Success(rh) => {
-                                if rh.return_type == expr_l.return_type {
-                                    match expr_l.clone().expression_type {
-                                        ExpressionType::LiteralExpression(name, v) => {
-                                            self.node_count += 1;
-
-                                            return self.program.env.define(
-                                                Variable::new(name.clone(),
-                                                    Expression::new(
-                                                        self.node_count,
-                                                        ExpressionType::AssignmentExpression(name, Box::new(rh)),
-                                                        expr_l.return_type
-                                                    )
-                                                )
-                                            )
-                                        },
-                                        _ => return ParseResult::Failed("Invalid assignment target".to_string())
-                                    }
-                                } else {
-                                    return ParseResult::Failed("Mismatched types".to_string())
-                                }
-                            },
-                            _ => return ParseResult::Failed("Failed RHS of assignment".to_string())
-                        }
+                        let assignment = Expression::new(
+                            self.ids.next_id(),
+                            ExpressionType::AssignmentExpression(name.clone(), Box::new(rh)),
+                            expr_l.return_type
+                        );
 
+                        match self.program.env.assign_value(&mut self.ids, Variable::new(name, assignment.clone())) {
+                            ParseResult::Success(_) => return ParseResult::Success(assignment),
+                            failed => return failed
+                        }
                     },
-                    Some(_) => return lh
+                    _ => return ParseResult::Failed("Invalid assignment target".to_string())
                 }
             },
-            _ => {
-                println!("Failed assignment");
-                return lh
-            }
+            _ => return ParseResult::Success(expr_l)
         }
     }
 
     fn parse_function_header_statement(&mut self) -> ParseResult {
-        let mut popped = self.tokens.pop();
-        let mut ident = String::new();
+        let mut popped = self.advance();
         match popped {
             Some(Token::Identifier(name)) => {
-                ident = name;
-                popped = self.tokens.pop();
+                let ident = name;
+                popped = self.advance();
                 match popped {
                     Some(Token::Colon) => {
-                        popped = self.tokens.pop();
-                        let ret_type = ReturnType::from(popped.unwrap());
+                        let ret_type = match self.advance() {
+                            Some(t) => ReturnType::from(t),
+                            None => return unexpected_end()
+                        };
                         match ret_type {
                             ReturnType::ReturnInvalid => return ParseResult::Failed("Expected return type after function definition".to_string()),
                             _ => {
-                                let popped = self.tokens.pop().unwrap();
-                                match popped {
-                                    Token::LeftParenthesis => {
+                                match self.advance() {
+                                    Some(Token::LeftParenthesis) => {
                                         let mut args = Vec::new();
                                         loop {
-                                            let rt = ReturnType::from(self.tokens.pop().unwrap());
+                                            let rt = match self.advance() {
+                                                Some(t) => ReturnType::from(t),
+                                                None => return unexpected_end()
+                                            };
                                             match rt {
                                                 ReturnType::ReturnVoid => {
-                                                    if args.len() > 0 {
+                                                    if !args.is_empty() {
                                                         return ParseResult::Failed("Unexpected void return type".to_string())
                                                     }
                                                     let f = FunctionHeader::new(ident, ret_type, args);
-                                                    let popped = self.tokens.pop().unwrap();
-                                                    match popped {
-                                                        Token::RightParenthesis => {
-                                                            self.node_count += 1;
+                                                    match self.advance() {
+                                                        Some(Token::RightParenthesis) => {
                                                             let e = ExpressionType::FunctionHeaderExpression(f);
 
-                                                            return ParseResult::Success(Expression::new(self.node_count, e, ReturnType::ReturnFunctionHeader))
+                                                            return ParseResult::Success(Expression::new(self.ids.next_id(), e, ReturnType::ReturnFunctionHeader))
                                                         },
+                                                        None => return unexpected_end(),
                                                         _ => return ParseResult::Failed("Expected ')' after arguments".to_string())
                                                     }
                                                 },
@@ -633,17 +1110,18 @@ impl Parser {
                                                 ReturnType::ReturnInteger | ReturnType::ReturnString |
                                                 ReturnType::ReturnBool | ReturnType::ReturnFloat |
                                                 ReturnType::ReturnStruct | ReturnType::ReturnCollection => {
-                                                    let popped = self.tokens.pop().unwrap();
-                                                    match popped {
-                                                        Token::Colon => {
-                                                            let popped = self.tokens.pop().unwrap();
-                                                            match popped {
-                                                                Token::Identifier(arg_name) => {
+                                                    match self.advance() {
+                                                        Some(Token::Colon) => {
+                                                            match self.advance() {
+                                                                Some(Token::Identifier(arg_name)) => {
                                                                     args.push(Argument::new(rt, arg_name));
                                                                 },
+                                                                Some(ref t) if t.is_keyword() => return reserved_keyword(t),
+                                                                None => return unexpected_end(),
                                                                 _ => return ParseResult::Failed("Expected argument name after ':'".to_string())
                                                             }
                                                         },
+                                                        None => return unexpected_end(),
                                                         _ => return ParseResult::Failed("Expected ')' after arguments".to_string())
                                                     }
                                                 },
@@ -653,12 +1131,11 @@ impl Parser {
                                                 ReturnType::ReturnEOF => return ParseResult::Failed("Unexpected end of file".to_string()),
 
                                                 ReturnType::ReturnArguments => {
-                                                    if args.len() > 0 {
-                                                        let f = FunctionHeader::new(ident, rt, args);
-                                                        self.node_count += 1;
+                                                    if !args.is_empty() {
+                                                        let f = FunctionHeader::new(ident, ret_type, args);
                                                         let e = ExpressionType::FunctionHeaderExpression(f);
 
-                                                        return ParseResult::Success(Expression::new(self.node_count, e, ReturnType::ReturnFunctionHeader))
+                                                        return ParseResult::Success(Expression::new(self.ids.next_id(), e, ReturnType::ReturnFunctionHeader))
                                                     } else {
                                                         return ParseResult::Failed("Expected argument list".to_string())
                                                     }
@@ -667,6 +1144,7 @@ impl Parser {
                                             }
                                         }
                                     },
+                                    None => return unexpected_end(),
                                     _ => return ParseResult::Failed("Expected '(' after return type".to_string())
                                 }
                             }
@@ -675,105 +1153,466 @@ impl Parser {
                     _ => return ParseResult::Failed("Expected ':' after identifier".to_string())
                 }
             },
+            Some(ref t) if t.is_keyword() => return reserved_keyword(t),
             _ => return ParseResult::Failed("Expected function identifier".to_string())
         }
     }
 
     fn parse_var_decl_statement(&mut self) -> ParseResult {
 
-        match self.tokens.pop() {
-            None => return ParseResult::Failed("Ran out of tokens".to_string()),
+        let name = match self.advance() {
+            None => return unexpected_end(),
+            Some(Token::Identifier(ident)) => ident,
+            Some(ref t) if t.is_keyword() => return reserved_keyword(t),
+            Some(_) => return ParseResult::Failed("Expected identifier".to_string())
+        };
 
-            Some(Token::Identifier(ident)) => {
-                let mut name = String::new();
-                name = ident;
+        // The type annotation is optional, without one the initialiser's type is used.
+        // A '?' after the type lets the variable hold null.
+        let mut declared = None;
+        let mut nullable = false;
 
-                match self.program.env.get_value(name) {
+        if self.peek() == Some(&Token::Colon) {
+            self.advance();
 
-                    ParseResult::Success(val) => {
-                        match self.tokens.pop() {
-                            None => return ParseResult::Failed("Ran out of tokens".to_string()),
-
-                            Some(Token::Assign) => {
-                                let expctd = val.return_type;
-                                let res = self.parse_expression();
-                                match res {
-                                    ParseResult::Success(expr) => {
-                                        if expr.return_type != expctd.clone() {
-                                            return ParseResult::Failed("Invalid return type".to_string());
-                                        }
-                                        match self.tokens.pop().unwrap() {
-                                            Token::Semicolon => {
-                                                self.node_count += 1;
-                                                return ParseResult::Success(Expression::new(self.node_count, ExpressionType::VarExpression(Box::new(expr)), expctd))
-                                            }
-                                            _ => return ParseResult::Failed("Expected ';'".to_string())
-                                        }
-                                    }
-                                    _ => return res
-                                }
-                            },
-                            Some(_) => return ParseResult::Failed("Expected '=' after return type".to_string())
-                        }
-                    },
-                    _ => return ParseResult::Failed("Variable not found".to_string())
-                }
-            },
-            Some(_) => return ParseResult::Failed("Expected identifier".to_string())
+            match self.advance() {
+                Some(t @ Token::IntegerDecl) | Some(t @ Token::FloatDecl) |
+                Some(t @ Token::StringDecl) | Some(t @ Token::BooleanDecl) |
+                Some(t @ Token::CollectionDecl) | Some(t @ Token::StructDecl) => {
+                    declared = Some(ReturnType::from(t));
+                },
+                _ => return ParseResult::Failed("Expected type after ':'".to_string())
+            }
+
+            if self.peek() == Some(&Token::Question) {
+                self.advance();
+                nullable = true;
+            }
+        }
+
+        match self.advance() {
+            None => return unexpected_end(),
+            Some(Token::Assign) => (),
+            Some(_) => return ParseResult::Failed("Expected '=' after variable name".to_string())
+        }
+
+        let mut expr = match self.parse_expression() {
+            ParseResult::Success(expr) => expr,
+            failed => return failed
+        };
+
+        // null takes on the declared type, which has to be a nullable one
+        if expr.return_type == ReturnType::ReturnNull {
+            match declared {
+                Some(ref expctd) if nullable => expr.return_type = expctd.clone(),
+                Some(ref expctd) => return ParseResult::Failed(format!("Can't assign null to non-nullable '{}' of type {:?}", name, expctd)),
+                None => return ParseResult::Failed(format!("Declare '{}' with a nullable type to initialise it with null", name))
+            }
+        }
+
+        if let Some(expctd) = declared {
+            if expr.return_type != expctd {
+                return ParseResult::Failed("Invalid return type".to_string());
+            }
+        }
+
+        match self.advance() {
+            Some(Token::Semicolon) => (),
+            _ => return ParseResult::Failed("Expected ';'".to_string())
+        }
+
+        match self.program.env.define(&mut self.ids, Variable::with_nullable(name, expr, nullable)) {
+            ParseResult::Success(var) => {
+                let rt = var.return_type.clone();
+
+                return ParseResult::Success(Expression::new(self.ids.next_id(), ExpressionType::VarExpression(Box::new(var)), rt))
+            },
+            failed => return failed
+        }
+    }
+
+    // Parses the block following a function header, with the arguments in scope
+    fn parse_function_body(&mut self, header: FunctionHeader) -> ParseResult {
+        let outer = mem::replace(&mut self.program.env, Environment::new());
+        self.program.env = Environment::new_sub(outer);
+
+        for arg in &header.args {
+            let value = Expression::new(self.ids.next_id(), ExpressionType::Literal(Token::Identifier(arg.ident.clone())), arg.return_type.clone());
+            self.program.env.vars.insert(arg.ident.clone(), VarEntry::new(value, false));
+        }
+
+        // Restored afterwards rather than cleared, functions declared inside
+        // another's body hand the context back to it
+        let enclosing = self.current_function.replace(header.clone());
+        // A function declared in a loop body can't break out of that loop
+        let loop_depth = mem::replace(&mut self.loop_depth, 0);
+        let body = self.parse_expression_statement();
+        self.loop_depth = loop_depth;
+        self.current_function = enclosing;
+
+        let inner = mem::replace(&mut self.program.env, Environment::new());
+        self.program.env = *inner.enclosing.expect("Function scope lost its enclosing environment");
+
+        let body = match body {
+            ParseResult::Success(body) => body,
+            failed => return failed
+        };
+
+        return ParseResult::Success(Expression::new(
+                self.ids.next_id(),
+                ExpressionType::FunctionExpression(Box::new(Function { header, body: Box::new(body) })),
+                ReturnType::ReturnFunction))
+    }
+
+    // for i in start..end [step n] { body }, or ..= to include the end
+    fn parse_for(&mut self) -> ParseResult {
+        let var = match self.advance() {
+            Some(Token::Identifier(ident)) => ident,
+            Some(ref t) if t.is_keyword() => return reserved_keyword(t),
+            _ => return ParseResult::Failed("Expected loop variable after 'for'".to_string())
+        };
+
+        if self.advance() != Some(Token::In) {
+            return ParseResult::Failed("Expected 'in' after loop variable".to_string())
+        }
+
+        let start = match self.parse_range_operand("start") {
+            ParseResult::Success(start) => start,
+            failed => return failed
+        };
+
+        let inclusive = match self.advance() {
+            Some(Token::DotDot) => false,
+            Some(Token::DotDotEqual) => true,
+            _ => return ParseResult::Failed("Expected '..' or '..=' in for range".to_string())
+        };
+
+        let end = match self.parse_range_operand("end") {
+            ParseResult::Success(end) => end,
+            failed => return failed
+        };
+
+        // Without a step the loop counts up by one
+        let step = if self.peek() == Some(&Token::Step) {
+            self.advance();
+
+            match self.parse_range_operand("step") {
+                ParseResult::Success(step) => step,
+                failed => return failed
+            }
+        } else {
+            Expression::new(self.ids.next_id(), ExpressionType::Literal(Token::IntegerLiteral(1)), ReturnType::ReturnInteger)
+        };
+
+        if self.peek() != Some(&Token::LeftBrace) {
+            return ParseResult::Failed("Expected '{' after for range".to_string())
+        }
+
+        // The loop variable is only in scope in the body
+        let outer = mem::replace(&mut self.program.env, Environment::new());
+        self.program.env = Environment::new_sub(outer);
+
+        let value = Expression::new(self.ids.next_id(), ExpressionType::Literal(Token::Identifier(var.clone())), ReturnType::ReturnInteger);
+        self.program.env.vars.insert(var.clone(), VarEntry::new(value, false));
+
+        let body = self.parse_loop_body();
+
+        let inner = mem::replace(&mut self.program.env, Environment::new());
+        self.program.env = *inner.enclosing.expect("Loop scope lost its enclosing environment");
+
+        let body = match body {
+            ParseResult::Success(body) => body,
+            failed => return failed
+        };
+
+        return ParseResult::Success(Expression::new(
+                self.ids.next_id(),
+                ExpressionType::ForExpression(Box::new(ForLoop {
+                    var,
+                    start: Box::new(start),
+                    end: Box::new(end),
+                    inclusive,
+                    step: Box::new(step),
+                    body: Box::new(body)
+                })),
+                ReturnType::ReturnBlock))
+    }
+
+    // if cond { body } [else if cond { body }]... [else { body }]
+    fn parse_if(&mut self) -> ParseResult {
+        let cond = match self.parse_expression() {
+            ParseResult::Success(cond) => cond,
+            failed => return failed
+        };
+
+        if cond.return_type != ReturnType::ReturnBool {
+            return ParseResult::Failed(format!("If condition must be ReturnBool, got {:?}", cond.return_type))
+        }
+
+        if self.peek() != Some(&Token::LeftBrace) {
+            return ParseResult::Failed("Expected '{' after if condition".to_string())
+        }
+
+        let body = match self.parse_expression_statement() {
+            ParseResult::Success(body) => body,
+            failed => return failed
+        };
+
+        let otherwise = if self.peek() == Some(&Token::Else) {
+            self.advance();
+
+            let branch = match self.peek() {
+                Some(&Token::If) => {
+                    self.advance();
+                    self.parse_if()
+                },
+                Some(&Token::LeftBrace) => self.parse_expression_statement(),
+                _ => return ParseResult::Failed("Expected '{' or 'if' after else".to_string())
+            };
+
+            match branch {
+                ParseResult::Success(branch) => Some(Box::new(branch)),
+                failed => return failed
+            }
+        } else {
+            None
+        };
+
+        return ParseResult::Success(Expression::new(
+                self.ids.next_id(),
+                ExpressionType::ConditionalExpression(Box::new(cond), Box::new(body), otherwise),
+                ReturnType::ReturnBlock))
+    }
+
+    // while cond { body }
+    fn parse_while(&mut self) -> ParseResult {
+        let cond = match self.parse_expression() {
+            ParseResult::Success(cond) => cond,
+            failed => return failed
+        };
+
+        if cond.return_type != ReturnType::ReturnBool {
+            return ParseResult::Failed(format!("While condition must be ReturnBool, got {:?}", cond.return_type))
+        }
+
+        if self.peek() != Some(&Token::LeftBrace) {
+            return ParseResult::Failed("Expected '{' after while condition".to_string())
+        }
+
+        let body = match self.parse_loop_body() {
+            ParseResult::Success(body) => body,
+            failed => return failed
+        };
+
+        return ParseResult::Success(Expression::new(
+                self.ids.next_id(),
+                ExpressionType::WhileExpression(Box::new(cond), Box::new(body)),
+                ReturnType::ReturnBlock))
+    }
+
+    // The block of a loop, inside which break and continue are allowed
+    fn parse_loop_body(&mut self) -> ParseResult {
+        self.loop_depth += 1;
+        let body = self.parse_expression_statement();
+        self.loop_depth -= 1;
+
+        return body
+    }
+
+    // break; or continue;
+    fn parse_loop_jump(&mut self, tok: Token) -> ParseResult {
+        if self.loop_depth == 0 {
+            return ParseResult::Failed(format!("{} outside loop", tok))
+        }
+
+        if self.advance() != Some(Token::Semicolon) {
+            return ParseResult::Failed(format!("Expected ';' after {}", tok))
+        }
+
+        let expression_type = match tok {
+            Token::Break => ExpressionType::BreakExpression,
+            _ => ExpressionType::ContinueExpression
+        };
+
+        return ParseResult::Success(Expression::new(self.ids.next_id(), expression_type, ReturnType::ReturnVoid))
+    }
+
+    // A bound or step of a for range, which has to be an integer
+    fn parse_range_operand(&mut self, what: &str) -> ParseResult {
+        match self.parse_expression() {
+            ParseResult::Success(expr) => {
+                if expr.return_type != ReturnType::ReturnInteger {
+                    return ParseResult::Failed(format!("For loop {} must be ReturnInteger, got {:?}", what, expr.return_type))
+                }
+                return ParseResult::Success(expr)
+            },
+            failed => return failed
+        }
+    }
+
+    // return [expr];
+    fn parse_return(&mut self) -> ParseResult {
+        let expected = match self.current_function {
+            Some(ref header) => header.return_type.clone(),
+            None => return ParseResult::Failed("return outside function".to_string())
+        };
+
+        let value = if self.peek() == Some(&Token::Semicolon) {
+            if expected != ReturnType::ReturnVoid {
+                return ParseResult::Failed(format!("Expected a return value of type {:?}", expected))
+            }
+            None
+        } else {
+            match self.parse_expression() {
+                ParseResult::Success(value) => {
+                    if value.return_type != expected {
+                        return ParseResult::Failed(format!("Return type mismatch: expected {:?}, got {:?}", expected, value.return_type))
+                    }
+                    Some(Box::new(value))
+                },
+                failed => return failed
+            }
+        };
+
+        if self.advance() != Some(Token::Semicolon) {
+            return ParseResult::Failed("Expected ';' after return".to_string())
+        }
+
+        return ParseResult::Success(Expression::new(
+                self.ids.next_id(),
+                ExpressionType::ReturnExpression(value),
+                expected))
+    }
+
+    // struct Name { type: field, type: field }
+    fn parse_struct_decl(&mut self) -> ParseResult {
+        let name = match self.advance() {
+            Some(Token::Identifier(ident)) => ident,
+            Some(ref t) if t.is_keyword() => return reserved_keyword(t),
+            _ => return ParseResult::Failed("Expected struct name".to_string())
+        };
+
+        if self.advance() != Some(Token::LeftBrace) {
+            return ParseResult::Failed("Expected '{' after struct name".to_string())
+        }
+
+        let mut fields: Vec<(String, ReturnType)> = vec![];
+
+        loop {
+            if self.peek() == Some(&Token::RightBrace) {
+                self.advance();
+                break;
+            }
+
+            let field_type = match self.advance() {
+                Some(t @ Token::IntegerDecl) | Some(t @ Token::FloatDecl) |
+                Some(t @ Token::StringDecl) | Some(t @ Token::BooleanDecl) |
+                Some(t @ Token::CollectionDecl) | Some(t @ Token::StructDecl) => ReturnType::from(t),
+                _ => return ParseResult::Failed("Expected field type".to_string())
+            };
+
+            if self.advance() != Some(Token::Colon) {
+                return ParseResult::Failed("Expected ':' after field type".to_string())
+            }
+
+            let field = match self.advance() {
+                Some(Token::Identifier(ident)) => ident,
+                Some(ref t) if t.is_keyword() => return reserved_keyword(t),
+                _ => return ParseResult::Failed("Expected field name after ':'".to_string())
+            };
+
+            if fields.iter().any(|f| f.0 == field) {
+                return ParseResult::Failed(format!("Duplicate field '{}' in struct '{}'", field, name))
+            }
+
+            fields.push((field, field_type));
+
+            match self.peek() {
+                Some(&Token::Comma) => {
+                    self.advance();
+                },
+                Some(&Token::RightBrace) => (),
+                _ => return ParseResult::Failed("Expected ',' or '}' after field".to_string())
+            }
         }
+
+        if let Err(e) = self.program.env.define_struct(name.clone(), fields.clone()) {
+            return ParseResult::Failed(e)
+        }
+
+        return ParseResult::Success(
+            Expression::new(
+                self.ids.next_id(),
+                ExpressionType::StructExpression(name, fields),
+                ReturnType::ReturnStruct
+            )
+        )
     }
 
     fn parse_print_expression(&mut self) -> ParseResult {
-        match self.tokens.pop().unwrap() {
-            Token::StringLiteral(str) => {
-                self.node_count += 1;
+        match self.advance() {
+            Some(Token::StringLiteral(str)) => {
+                if self.advance() != Some(Token::Semicolon) {
+                    return ParseResult::Failed("Expected ';' after print".to_string())
+                }
+
                 return ParseResult::Success(
                     Expression::new(
-                        self.node_count,
+                        self.ids.next_id(),
                         ExpressionType::PrintExpression(str),
                         ReturnType::ReturnString
                     )
                 )
             },
+            None => return unexpected_end(),
             _ => return ParseResult::Failed("Expected string after 'print'".to_string())
         }
     }
 
     fn parse_declaration(&mut self) -> ParseResult {
 
-        let cur_token = self.tokens.pop().unwrap();
-
-        match cur_token {
-            Token::VarDecl => {
+        match self.peek() {
+            Some(&Token::VarDecl) => {
+                self.advance();
                 let stm = self.parse_var_decl_statement();
 
-                match stm.clone() {
-                    ParseResult::Success(s) => {
-                        self.push_expression_statement(s);
-                        return stm.clone()
-                    },
-
-                    ParseResult::Failed(f) => {
-                        println!("Failed parsing var decl: {}", f);
-                        return stm.clone()
-                    }
+                if let ParseResult::Failed(ref f) = stm {
+                    println!("Failed parsing var decl: {}", f);
                 }
+                return stm
             },
 
-            Token::FunctionDecl => {
+            Some(&Token::StructDecl) => {
+                self.advance();
+                return self.parse_struct_decl()
+            },
+
+            Some(&Token::FunctionDecl) => {
+                self.advance();
                 let stm = self.parse_function_header_statement();
 
-                match stm.clone() {
-                    ParseResult::Success(s) => {
-                        self.push_expression_statement(s);
-                        return stm.clone()
+                let header = match stm {
+                    ParseResult::Success(ref expr) => {
+                        match expr.expression_type {
+                            ExpressionType::FunctionHeaderExpression(ref header) => header.clone(),
+                            _ => return stm
+                        }
                     },
-
-                    ParseResult::Failed(f) => {
+                    ParseResult::Failed(ref f) => {
                         println!("Failed parsing function decl: {}", f);
-                        return stm.clone()
+                        return stm
                     }
+                };
+
+                // Defined before the body is parsed so the function can call itself
+                if let Err(e) = self.program.env.define_function(header.clone()) {
+                    return ParseResult::Failed(e)
+                }
+
+                if self.peek() == Some(&Token::LeftBrace) {
+                    return self.parse_function_body(header)
                 }
+                return stm
             },
 
             _ => return self.parse_expression_statement()
@@ -782,38 +1621,56 @@ impl Parser {
 
     fn parse_expression_statement(&mut self) -> ParseResult {
 
-        let cur_token = self.tokens.pop().unwrap();
-
-        match cur_token {
-            Token::Print => return self.parse_print_expression(),
-            Token::LeftBrace => {
+        match self.peek() {
+            Some(&Token::Print) => {
+                self.advance();
+                return self.parse_print_expression()
+            },
+            Some(&Token::Return) => {
+                self.advance();
+                return self.parse_return()
+            },
+            Some(&Token::For) => {
+                self.advance();
+                return self.parse_for()
+            },
+            Some(&Token::If) => {
+                self.advance();
+                return self.parse_if()
+            },
+            Some(&Token::While) => {
+                self.advance();
+                return self.parse_while()
+            },
+            Some(&Token::Break) | Some(&Token::Continue) => {
+                let tok = self.advance().unwrap_or_default();
+                return self.parse_loop_jump(tok)
+            },
+            Some(&Token::LeftBrace) => {
+                self.advance();
                 let mut exs = vec!();
 
                 loop {
-                    let next = self.tokens.clone().pop();
-
-                    match next {
-                        None => return ParseResult::Failed("Ran out of tokens".to_string()),
+                    match self.peek() {
+                        None => return unexpected_end(),
 
-                        Some(Token::RightBrace) =>  {
-                            self.tokens.pop();
-                            self.node_count += 1;
+                        Some(&Token::RightBrace) =>  {
+                            self.advance();
                             return ParseResult::Success
                             (
                                 Expression::new
                                 (
-                                    self.node_count,
+                                    self.ids.next_id(),
                                     ExpressionType::BlockExpression(exs),
                                     ReturnType::ReturnBlock
                                 )
                             )
                         },
-                        Some(Token::EOF) => return ParseResult::Failed("Unexpected EOF".to_string()),
+                        Some(&Token::EOF) => return ParseResult::Failed("Unexpected EOF".to_string()),
                         Some(_) => {
                             let res = self.parse_declaration();
                             match res {
                                 ParseResult::Success(ex) => {
-                                    self.node_count += 1;
                                     exs.push(ex);
                                 },
                                 _ => return res
@@ -824,26 +1681,25 @@ impl Parser {
             },
             _ => return self.parse_statement()
         }
-
-        return ParseResult::Failed("lol".to_string())
     }
 
     pub fn parse_statement(&mut self) -> ParseResult {
         let res = self.parse_expression();
         match res.clone() {
-            ParseResult::Success(s) => {
-                if self.tokens.pop().unwrap() == Token::Semicolon {
-                    return res
+            ParseResult::Success(_) => {
+                match self.advance() {
+                    Some(Token::Semicolon) => return res,
+                    None => return unexpected_end(),
+                    Some(_) => return ParseResult::Failed("Expected ';' after expression".to_string())
                 }
-                return ParseResult::Failed("Expected ';' after expression".to_string())
             },
             _ => return res
         }
     }
 
     fn parse_expression(&mut self) -> ParseResult {
-        match self.tokens.clone().pop() {
-            Some(Token::EOF) | None => return ParseResult::Failed("Unexpected EOF".to_string()),
+        match self.peek() {
+            Some(&Token::EOF) | None => return ParseResult::Failed("Unexpected EOF".to_string()),
             _ => return self.parse_assignment()
         }
     }
@@ -857,51 +1713,793 @@ impl Parser {
         self.program.statements.push(stat);
     }
 
-    pub fn parse(&mut self) -> AstProgram {
+    // Parses the next top level statement, or None once the input is exhausted
+    pub fn parse_next(&mut self) -> Option<ParseResult> {
+        match self.peek() {
+            None | Some(&Token::EOF) => return None,
+            Some(_) => return Some(self.parse_declaration())
+        }
+    }
+
+    // Parses input consisting of a single expression with no terminating ';'
+    pub fn parse_bare_expression(&mut self) -> ParseResult {
+        let res = self.parse_expression();
+
+        match res {
+            ParseResult::Success(_) => {
+                match self.advance() {
+                    None | Some(Token::EOF) => return res,
+                    Some(_) => return ParseResult::Failed("Unexpected token after expression".to_string())
+                }
+            },
+            _ => return res
+        }
+    }
+
+    // Index of the last token consumed, which is where a failed parse gave up
+    pub fn last_token_index(&self) -> Option<usize> {
+        return self.pos.checked_sub(1)
+    }
+
+    pub fn environment(&self) -> &Environment {
+        &self.program.env
+    }
+
+    // Skips past the rest of a broken statement, stopping after a ';' or before
+    // a keyword that starts a new statement. At least one token is always skipped
+    // when the failed statement didn't consume any, so parsing can't get stuck.
+    fn synchronize(&mut self, start: usize) {
+        if self.pos == start {
+            self.advance();
+        }
 
         loop {
+            match self.peek() {
+                None | Some(&Token::EOF) => return,
 
-            let cur_token = self.tokens.pop();
+                Some(&Token::Semicolon) => {
+                    self.advance();
+                    return
+                },
 
-            match cur_token {
+                Some(&Token::VarDecl) | Some(&Token::ConstDecl) | Some(&Token::FunctionDecl) |
+                Some(&Token::Print) | Some(&Token::If) | Some(&Token::While) |
+                Some(&Token::For) | Some(&Token::Return) => return,
 
+                Some(_) => {
+                    self.advance();
+                }
+            }
+        }
+    }
+
+    // Parses every statement, recovering from failures so that all of them are
+    // reported rather than just the first
+    pub fn parse(&mut self) -> (AstProgram, Vec<String>) {
+
+        loop {
+            let start = self.pos;
+
+            match self.parse_next() {
                 None => break,
 
-                Some(Token::EOF) => {
-                    break
+                Some(ParseResult::Success(s)) => {
+                    self.push_expression_statement(s);
+                },
+
+                Some(ParseResult::Failed(f)) => {
+                    self.errors.push(f);
+                    self.synchronize(start);
                 }
+            }
+        }
 
-                Some(Token::Identifier(id)) => {
-                    match self.program.env.get_value(id) {
-                        ParseResult::Success(e) => {
-                            self.push_expression_statement(e);
-                        },
+        return (self.program.clone(), self.errors.clone())
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use compiler::Scanner;
+    use compiler::printer;
+
+    use vm::VM;
+
+    fn parse(source: &str) -> Parser {
+        return Parser::new(Scanner::new(source).tokenize())
+    }
+
+    fn parse_error(source: &str) -> String {
+        match parse(source).parse_next() {
+            Some(ParseResult::Failed(f)) => return f,
+            Some(ParseResult::Success(expr)) => panic!("Unexpectedly parsed {:?}", expr),
+            None => panic!("Nothing to parse")
+        }
+    }
+
+    #[test]
+    fn test_parse_reports_every_error() {
+        let (program, errors) = parse("var a = ; var b = 2; 1 + ; var c = b;").parse();
+
+        assert_eq!(errors, vec!["Expected primary expression", "Failed addition RHS"]);
+        assert_eq!(program.statements.len(), 2);
+        assert!(program.env.vars.contains_key("c"));
+    }
+
+    #[test]
+    fn test_unexpected_end_of_input() {
+        // No trailing EOF token, as when a caller builds the stream by hand
+        let tokens = vec![Token::IntegerLiteral(1), Token::Add, Token::IntegerLiteral(2)];
+
+        match Parser::new(tokens).parse_statement() {
+            ParseResult::Failed(f) => assert_eq!(f, "unexpected end of input"),
+            ParseResult::Success(expr) => panic!("Unexpectedly parsed {:?}", expr)
+        }
+
+        for tokens in [
+            vec![Token::Print],
+            vec![Token::FunctionDecl, Token::Identifier("f".to_string()), Token::Colon],
+            vec![Token::LeftBrace, Token::IntegerLiteral(1), Token::Semicolon]
+        ] {
+            match Parser::new(tokens).parse_next() {
+                Some(ParseResult::Failed(f)) => assert_eq!(f, "unexpected end of input"),
+                res => panic!("Expected a failure, got {:?}", res)
+            }
+        }
+    }
+
+    #[test]
+    fn test_tokens_in_source_order() {
+        let tokens = vec![
+            Token::IntegerLiteral(1), Token::Subtract, Token::IntegerLiteral(2), Token::Semicolon, Token::EOF
+        ];
+
+        match Parser::new(tokens).parse_next() {
+            Some(ParseResult::Success(expr)) => {
+                assert_eq!(printer::pretty_print(&expr), "\
+Binary Subtract : ReturnInteger
+  Literal IntegerLiteral(1) : ReturnInteger
+  Literal IntegerLiteral(2) : ReturnInteger
+");
+            },
+            res => panic!("Expected a subtraction, got {:?}", res)
+        }
+    }
+
+    #[test]
+    fn test_peek_does_not_advance() {
+        let mut parser = parse("x = 1;");
+
+        assert_eq!(parser.peek(), Some(&Token::Identifier("x".to_string())));
+        assert_eq!(parser.peek(), Some(&Token::Identifier("x".to_string())));
+
+        parser.advance();
+        assert_eq!(parser.peek(), Some(&Token::Assign));
+    }
+
+    fn parse_expr(source: &str) -> ParseResult {
+        return parse(source).parse_bare_expression()
+    }
+
+    #[test]
+    fn test_logical_not_typing() {
+        match parse_expr("!true") {
+            ParseResult::Success(expr) => assert_eq!(expr.return_type, ReturnType::ReturnBool),
+            ParseResult::Failed(f) => panic!("{}", f)
+        }
+
+        match parse_expr("!5") {
+            ParseResult::Failed(f) => assert_eq!(f, "'!' expects ReturnBool, got ReturnInteger"),
+            ParseResult::Success(expr) => panic!("Unexpectedly parsed {:?}", expr)
+        }
+
+        match parse_expr("-\"a\"") {
+            ParseResult::Failed(f) => assert_eq!(f, "'-' expects a number, got ReturnString"),
+            ParseResult::Success(expr) => panic!("Unexpectedly parsed {:?}", expr)
+        }
+    }
 
-                        ParseResult::Failed(f) => {
-                            println!("Failed parsing: {}", f);
-                            return self.program.clone()
+    #[test]
+    fn test_negation_typing() {
+        for (source, expected) in [
+            ("-5", ReturnType::ReturnInteger),
+            ("-3.0", ReturnType::ReturnFloat),
+            ("--5", ReturnType::ReturnInteger),
+            ("!!false", ReturnType::ReturnBool)
+        ] {
+            match parse_expr(source) {
+                ParseResult::Success(expr) => assert_eq!(expr.return_type, expected, "{}", source),
+                ParseResult::Failed(f) => panic!("{}: {}", source, f)
+            }
+        }
+
+        // The operand's type carries through to the enclosing expression
+        match parse_expr("2 * -5") {
+            ParseResult::Success(expr) => assert_eq!(expr.return_type, ReturnType::ReturnInteger),
+            ParseResult::Failed(f) => panic!("{}", f)
+        }
+    }
+
+    #[test]
+    fn test_comparison_typing() {
+        for source in ["1 < 2", "1 == 2", "1.5 >= 2.5", "\"a\" != \"b\"", "1 < 2 == true"] {
+            match parse_expr(source) {
+                ParseResult::Success(expr) => assert_eq!(expr.return_type, ReturnType::ReturnBool, "{}", source),
+                ParseResult::Failed(f) => panic!("{}: {}", source, f)
+            }
+        }
+
+        // Operands still have to agree with each other
+        match parse_expr("1 < 2.0") {
+            ParseResult::Failed(f) => assert_eq!(f, "Comparing different return types!"),
+            ParseResult::Success(expr) => panic!("Unexpectedly parsed {:?}", expr)
+        }
+    }
+
+    #[test]
+    fn test_mixed_arithmetic_promotion() {
+        match parse_expr("1 + 2.0") {
+            ParseResult::Success(expr) => {
+                assert_eq!(expr.return_type, ReturnType::ReturnFloat);
+                assert_eq!(printer::pretty_print(&expr), "\
+Binary Add : ReturnFloat
+  Promote : ReturnFloat
+    Literal IntegerLiteral(1) : ReturnInteger
+  Literal FloatLiteral(2.0) : ReturnFloat
+");
+            },
+            ParseResult::Failed(f) => panic!("{}", f)
+        }
+
+        match parse_expr("2.5 * 2") {
+            ParseResult::Success(expr) => assert_eq!(expr.return_type, ReturnType::ReturnFloat),
+            ParseResult::Failed(f) => panic!("{}", f)
+        }
+
+        match parse_expr("1 + \"a\"") {
+            ParseResult::Failed(f) => assert_eq!(f, "Comparing different return types!"),
+            ParseResult::Success(expr) => panic!("Unexpectedly parsed {:?}", expr)
+        }
+    }
+
+    #[test]
+    fn test_parse_long_expression() {
+        // Each operator used to clone everything parsed before it
+        let source = vec!["1"; 1000].join(" + ");
+
+        match parse_expr(&source) {
+            ParseResult::Success(expr) => {
+                let mut depth = 0;
+                let mut node = &expr;
+                while let ExpressionType::BinaryExpression(Token::Add, ref lhs, _) = node.expression_type {
+                    depth += 1;
+                    node = lhs;
+                }
+                assert_eq!(depth, 999);
+            },
+            ParseResult::Failed(f) => panic!("{}", f)
+        }
+    }
+
+    #[test]
+    fn test_struct_declaration() {
+        let mut parser = parse("struct Point { int: x, int: y }");
+
+        match parser.parse_next() {
+            Some(ParseResult::Success(expr)) => {
+                match expr.expression_type {
+                    ExpressionType::StructExpression(ref name, ref fields) => {
+                        assert_eq!(name, "Point");
+                        assert_eq!(fields, &vec![
+                            ("x".to_string(), ReturnType::ReturnInteger),
+                            ("y".to_string(), ReturnType::ReturnInteger)
+                        ]);
+                    },
+                    ref t => panic!("Expected a struct, got {:?}", t)
+                }
+                assert_eq!(expr.return_type, ReturnType::ReturnStruct);
+            },
+            res => panic!("Expected a struct, got {:?}", res)
+        }
+
+        assert!(parser.environment().get_struct("Point").is_some());
+    }
+
+    #[test]
+    fn test_struct_duplicate_field() {
+        assert_eq!(parse_error("struct Point { int: x, float: x }"), "Duplicate field 'x' in struct 'Point'");
+    }
+
+    #[test]
+    fn test_field_access() {
+        let mut parser = parse("struct Point { int: x, int: y } var p = Point { x: 1, y: 2 }; p.x");
+
+        while parser.peek() != Some(&Token::Identifier("p".to_string())) {
+            if let Some(ParseResult::Failed(f)) = parser.parse_next() {
+                panic!("{}", f)
+            }
+        }
+
+        match parser.parse_bare_expression() {
+            ParseResult::Success(expr) => {
+                match expr.expression_type {
+                    ExpressionType::FieldAccess(_, ref field) => assert_eq!(field, "x"),
+                    ref t => panic!("Expected a field access, got {:?}", t)
+                }
+                assert_eq!(expr.return_type, ReturnType::ReturnInteger);
+            },
+            ParseResult::Failed(f) => panic!("{}", f)
+        }
+    }
+
+    #[test]
+    fn test_unknown_field_access() {
+        let (_, errors) = parse("struct Point { int: x, int: y } var p = Point { x: 1, y: 2 }; var z = p.z;").parse();
+
+        assert_eq!(errors, vec!["Struct 'Point' has no field 'z'"]);
+    }
+
+    fn parse_after_header(source: &str) -> ParseResult {
+        let mut parser = parse(source);
+
+        match parser.parse_next() {
+            Some(ParseResult::Success(_)) => (),
+            res => panic!("Expected a function header, got {:?}", res)
+        }
+
+        return parser.parse_bare_expression()
+    }
+
+    #[test]
+    fn test_function_call() {
+        match parse_after_header("fn add : int (int: a, int: b) add(1, 2 * 3)") {
+            ParseResult::Success(expr) => {
+                match expr.expression_type {
+                    ExpressionType::CallExpression(ref name, ref args) => {
+                        assert_eq!(name, "add");
+                        assert_eq!(args.len(), 2);
+                    },
+                    ref t => panic!("Expected a call, got {:?}", t)
+                }
+                assert_eq!(expr.return_type, ReturnType::ReturnInteger);
+            },
+            ParseResult::Failed(f) => panic!("{}", f)
+        }
+    }
+
+    #[test]
+    fn test_function_call_wrong_argument() {
+        match parse_after_header("fn add : int (int: a, int: b) add(1, \"two\")") {
+            ParseResult::Failed(f) => assert_eq!(f, "Argument 2 of 'add' should be ReturnInteger, got ReturnString"),
+            ParseResult::Success(expr) => panic!("Unexpectedly parsed {:?}", expr)
+        }
+
+        match parse_after_header("fn add : int (int: a, int: b) add(1)") {
+            ParseResult::Failed(f) => assert_eq!(f, "Function 'add' expects 2 arguments, got 1"),
+            ParseResult::Success(expr) => panic!("Unexpectedly parsed {:?}", expr)
+        }
+    }
+
+    #[test]
+    fn test_collection_literal() {
+        match parse("[1, 2, 3]").parse_bare_expression() {
+            ParseResult::Success(expr) => {
+                match expr.expression_type {
+                    ExpressionType::CollectionExpression(ref elements) => assert_eq!(elements.len(), 3),
+                    ref t => panic!("Expected a collection, got {:?}", t)
+                }
+                assert_eq!(expr.return_type, ReturnType::ReturnCollection);
+            },
+            ParseResult::Failed(f) => panic!("{}", f)
+        }
+    }
+
+    #[test]
+    fn test_mixed_collection_literal() {
+        match parse("[1, \"a\"]").parse_bare_expression() {
+            ParseResult::Failed(f) => assert_eq!(f, "Mixed element types in collection: ReturnInteger and ReturnString"),
+            ParseResult::Success(expr) => panic!("Unexpectedly parsed {:?}", expr)
+        }
+    }
+
+    #[test]
+    fn test_index_expression() {
+        let mut parser = parse("var arr = [4, 5, 6]; arr[0]");
+
+        if let Some(ParseResult::Failed(f)) = parser.parse_next() {
+            panic!("{}", f)
+        }
+
+        match parser.parse_bare_expression() {
+            ParseResult::Success(expr) => {
+                match expr.expression_type {
+                    ExpressionType::IndexExpression(..) => (),
+                    ref t => panic!("Expected an index, got {:?}", t)
+                }
+                assert_eq!(expr.return_type, ReturnType::ReturnInteger);
+            },
+            ParseResult::Failed(f) => panic!("{}", f)
+        }
+    }
+
+    #[test]
+    fn test_string_index() {
+        let (_, errors) = parse("var arr = [4, 5, 6]; var x = arr[\"a\"];").parse();
+        assert_eq!(errors, vec!["Collection index must be ReturnInteger, got ReturnString"]);
+
+        let (_, errors) = parse("var s = \"abc\"; var x = s[0];").parse();
+        assert_eq!(errors, vec!["Only collections can be indexed, got ReturnString"]);
+    }
+
+    #[test]
+    fn test_return_in_function() {
+        match parse("fn five : int (void) { return 5; }").parse_next() {
+            Some(ParseResult::Success(expr)) => {
+                assert_eq!(expr.return_type, ReturnType::ReturnFunction);
+
+                match expr.expression_type {
+                    ExpressionType::FunctionExpression(ref f) => assert_eq!(f.header.name, "five"),
+                    ref t => panic!("Expected a function, got {:?}", t)
+                }
+            },
+            res => panic!("Expected a function, got {:?}", res)
+        }
+
+        // Arguments are in scope in the body
+        let (_, errors) = parse("fn add : int (int: a, int: b) { return a + b; }").parse();
+        assert!(errors.is_empty(), "{:?}", errors);
+    }
+
+    #[test]
+    fn test_return_type_mismatch() {
+        assert_eq!(parse_error("fn five : int (void) { return \"five\"; }"),
+                   "Return type mismatch: expected ReturnInteger, got ReturnString");
+    }
+
+    #[test]
+    fn test_return_outside_function() {
+        assert_eq!(parse_error("return 5;"), "return outside function");
+
+        let (_, errors) = parse("fn five : int (void) { return 5; } return 5;").parse();
+        assert_eq!(errors, vec!["return outside function"]);
+    }
+
+    #[test]
+    fn test_return_in_nested_function() {
+        let (_, errors) = parse("fn outer : int (void) { fn inner : bool (void) { return true; } return 1; }").parse();
+
+        assert!(errors.is_empty(), "{:?}", errors);
+    }
+
+    #[test]
+    fn test_keyword_as_variable_name() {
+        assert_eq!(parse_error("var for = 5;"), "'for' is a reserved keyword");
+    }
+
+    #[test]
+    fn test_keyword_as_function_name() {
+        assert_eq!(parse_error("fn while : int (void)"), "'while' is a reserved keyword");
+    }
+
+    #[test]
+    fn test_to_bytecode() {
+        let (program, errors) = parse("1 + 2;").parse();
+        assert!(errors.is_empty());
+
+        let mut vm = VM::new();
+        vm.load_bytes(program.to_bytecode().unwrap());
+        vm.run().unwrap();
+
+        // 1 and 2 are loaded into $0 and $1, their sum into $2
+        assert_eq!(vm.registers[2], 3);
+    }
+
+    #[test]
+    fn test_logical_operators() {
+        let expr = match parse_expr("1 < 2 || false && 2 == 3") {
+            ParseResult::Success(expr) => expr,
+            ParseResult::Failed(f) => panic!("{}", f)
+        };
+
+        assert_eq!(expr.return_type, ReturnType::ReturnBool);
+        // && binds tighter than ||
+        match expr.expression_type {
+            ExpressionType::BinaryExpression(Token::LogicalOr, _, ref rhs) => {
+                match rhs.expression_type {
+                    ExpressionType::BinaryExpression(Token::LogicalAnd, _, _) => (),
+                    ref other => panic!("Expected &&, got {:?}", other)
+                }
+            },
+            ref other => panic!("Expected ||, got {:?}", other)
+        }
+
+        match parse("1 && true;").parse_next() {
+            Some(ParseResult::Failed(f)) => assert_eq!(f, "'&&' expects ReturnBool operands, got ReturnInteger and ReturnBool"),
+            _ => panic!("Expected a type error")
+        }
+    }
+
+    fn parse_for_loop(source: &str) -> ForLoop {
+        match parse(source).parse_next() {
+            Some(ParseResult::Success(expr)) => {
+                assert_eq!(expr.return_type, ReturnType::ReturnBlock);
+
+                match expr.expression_type {
+                    ExpressionType::ForExpression(f) => return *f,
+                    t => panic!("Expected a for loop, got {:?}", t)
+                }
+            },
+            res => panic!("Expected a for loop, got {:?}", res)
+        }
+    }
+
+    #[test]
+    fn test_for_step() {
+        let f = parse_for_loop("for i in 0..10 step 2 { i + 1; }");
+        assert_eq!(f.var, "i");
+        assert!(!f.inclusive);
+
+        match f.step.expression_type {
+            ExpressionType::Literal(Token::IntegerLiteral(2)) => (),
+            ref t => panic!("Expected a step of 2, got {:?}", t)
+        }
+
+        // The step defaults to 1
+        let f = parse_for_loop("for i in 0..=10 { }");
+        assert!(f.inclusive);
+
+        match f.step.expression_type {
+            ExpressionType::Literal(Token::IntegerLiteral(1)) => (),
+            ref t => panic!("Expected a step of 1, got {:?}", t)
+        }
+
+        // The loop variable goes out of scope with the body
+        let (_, errors) = parse("for i in 0..10 { } i;").parse();
+        assert_eq!(errors, vec!["Undefined variable 'i'"]);
+    }
+
+    #[test]
+    fn test_for_step_wrong_type() {
+        assert_eq!(parse_error("for i in 0..10 step 1.5 { }"), "For loop step must be ReturnInteger, got ReturnFloat");
+        assert_eq!(parse_error("for i in 0..\"ten\" { }"), "For loop end must be ReturnInteger, got ReturnString");
+    }
+
+    #[test]
+    fn test_break_in_while() {
+        let (program, errors) = parse("var n = 0; while n < 10 { n = n + 1; break; }").parse();
+        assert!(errors.is_empty(), "{:?}", errors);
+
+        match program.statements[1].expr.expression_type {
+            ExpressionType::WhileExpression(ref cond, ref body) => {
+                assert_eq!(cond.return_type, ReturnType::ReturnBool);
+
+                match body.expression_type {
+                    ExpressionType::BlockExpression(ref exprs) => {
+                        match exprs[1].expression_type {
+                            ExpressionType::BreakExpression => (),
+                            ref t => panic!("Expected a break, got {:?}", t)
                         }
-                    }
-                },
+                    },
+                    ref t => panic!("Expected a block, got {:?}", t)
+                }
+            },
+            ref t => panic!("Expected a while loop, got {:?}", t)
+        }
 
-                Some(_) => {
-                    let stm = self.parse_expression_statement();
+        assert_eq!(parse_error("while 1 { }"), "While condition must be ReturnBool, got ReturnInteger");
+    }
 
-                    match stm {
-                        ParseResult::Success(s) => {
-                            self.push_expression_statement(s);
-                        },
+    #[test]
+    fn test_break_outside_loop() {
+        assert_eq!(parse_error("break;"), "break outside loop");
+        assert_eq!(parse_error("continue;"), "continue outside loop");
+
+        // The loop is over once its body closes
+        let (_, errors) = parse("while true { continue; } break;").parse();
+        assert_eq!(errors, vec!["break outside loop"]);
+    }
+
+    #[test]
+    fn test_break_in_nested_loops() {
+        let (_, errors) = parse("while true { for i in 0..3 { while false { continue; } break; } break; }").parse();
+        assert!(errors.is_empty(), "{:?}", errors);
+
+        // Loops don't reach into the bodies of functions declared inside them
+        assert_eq!(parse_error("while true { fn f : void (void) { break; } break; }"), "break outside loop");
+    }
+
+    #[test]
+    fn test_else_if_chain() {
+        let (program, errors) = parse("var a = true; var b = false; if (a) {} else if (b) {} else {}").parse();
+        assert!(errors.is_empty(), "{:?}", errors);
+
+        let expr = &program.statements[2].expr;
+        assert_eq!(expr.return_type, ReturnType::ReturnBlock);
 
-                        ParseResult::Failed(f) => {
-                            println!("Failed parsing: {}", f);
-                            return self.program.clone()
+        match expr.expression_type {
+            ExpressionType::ConditionalExpression(ref cond, _, Some(ref otherwise)) => {
+                assert_eq!(cond.return_type, ReturnType::ReturnBool);
+
+                match otherwise.expression_type {
+                    ExpressionType::ConditionalExpression(ref cond, _, Some(ref otherwise)) => {
+                        assert_eq!(cond.return_type, ReturnType::ReturnBool);
+
+                        match otherwise.expression_type {
+                            ExpressionType::BlockExpression(ref exprs) => assert!(exprs.is_empty()),
+                            ref t => panic!("Expected the else block, got {:?}", t)
                         }
-                    }
+                    },
+                    ref t => panic!("Expected the else if, got {:?}", t)
                 }
-            };
+            },
+            ref t => panic!("Expected a conditional with an else branch, got {:?}", t)
+        }
+
+        assert_eq!(parse_error("if 1 {} else {}"), "If condition must be ReturnBool, got ReturnInteger");
+        assert_eq!(parse_error("if true {} else 5;"), "Expected '{' or 'if' after else");
+    }
+
+    #[test]
+    fn test_nullable_accepts_null() {
+        let (program, errors) = parse("var x : int? = null; x = 5; x = null;").parse();
+        assert!(errors.is_empty(), "{:?}", errors);
+
+        assert_eq!(program.env.is_nullable("x"), Some(true));
+        assert_eq!(program.statements[0].expr.return_type, ReturnType::ReturnInteger);
+    }
+
+    #[test]
+    fn test_nullable_compares_with_null() {
+        let (program, errors) = parse("var x : int? = null; var a = x == null; var b = null != x;").parse();
+        assert!(errors.is_empty(), "{:?}", errors);
+
+        assert_eq!(program.statements[1].expr.return_type, ReturnType::ReturnBool);
+        assert_eq!(program.statements[2].expr.return_type, ReturnType::ReturnBool);
+
+        // Only nullable variables can be null
+        let (_, errors) = parse("var y = 1; var c = y == null;").parse();
+        assert_eq!(errors, vec!["Comparing different return types!"]);
+
+        let (_, errors) = parse("var x : int? = null; var c = x < null;").parse();
+        assert_eq!(errors, vec!["Comparing different return types!"]);
+    }
+
+    #[test]
+    fn test_non_nullable_rejects_null() {
+        assert_eq!(parse_error("var x : int = null;"), "Can't assign null to non-nullable 'x' of type ReturnInteger");
+        assert_eq!(parse_error("var x = null;"), "Declare 'x' with a nullable type to initialise it with null");
+
+        let (program, errors) = parse("var x : int = 1; x = null;").parse();
+        assert_eq!(errors, vec!["Can't assign null to non-nullable 'x' of type ReturnInteger"]);
+        assert_eq!(program.env.is_nullable("x"), Some(false));
+    }
+
+    #[test]
+    fn test_environment_variables() {
+        let (program, errors) = parse("var a = 1; var b = true;").parse();
+        assert!(errors.is_empty(), "{:?}", errors);
+
+        let mut inner = Environment::new_sub(program.env);
+        let value = Expression::new(0, ExpressionType::Literal(Token::StringLiteral("s".to_string())), ReturnType::ReturnString);
+        inner.vars.insert("b".to_string(), VarEntry::new(value, false));
+
+        // The inner b shadows the outer one
+        let types: Vec<(&str, ReturnType)> = inner.variables().into_iter()
+            .map(|(name, entry)| (name.as_str(), entry.value.return_type.clone()))
+            .collect();
+        assert_eq!(types, vec![("a", ReturnType::ReturnInteger), ("b", ReturnType::ReturnString)]);
+    }
+
+    #[test]
+    fn test_get_value_through_shared_reference() {
+        let (program, errors) = parse("var a = 1.5;").parse();
+        assert!(errors.is_empty(), "{:?}", errors);
+
+        let inner = Environment::new_sub(Environment::new_sub(program.env));
+        let env: &Environment = &inner;
+
+        match env.get_value("a".to_string()) {
+            ParseResult::Success(value) => assert_eq!(value.return_type, ReturnType::ReturnFloat),
+            ParseResult::Failed(f) => panic!("{}", f)
+        }
+
+        match env.get_value("b".to_string()) {
+            ParseResult::Failed(f) => assert_eq!(f, "Variable doesn't exist"),
+            ParseResult::Success(value) => panic!("Unexpectedly found {:?}", value)
+        }
+    }
+
+    // Ids of every node in the tree, read back out of its JSON rendering
+    fn ids_of(expr: &Expression) -> Vec<u32> {
+        return json::expression_to_json(expr).split("\"id\":").skip(1)
+            .map(|rest| rest.split(',').next().unwrap().parse().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_expression_ids_increase() {
+        let source = "
+            var a = 1;
+            { var b = a; { var c = b + a; while c > 0 { c = c - 1; { a = a + c; } } } }
+            fn f : int (int: x) { { return x * 2; } }
+            a = a + 1;
+        ";
+        let (program, errors) = parse(source).parse();
+        assert!(errors.is_empty(), "{:?}", errors);
+
+        let mut seen: Vec<u32> = vec![];
+
+        for statement in &program.statements {
+            let ids = ids_of(&statement.expr);
+
+            // Children are built before their parent, and statements in order
+            assert_eq!(ids.iter().max(), Some(&statement.expr.id()));
+            assert!(ids.iter().all(|id| seen.iter().all(|s| id > s)), "{:?} after {:?}", ids, seen);
+
+            seen.extend(ids);
         }
 
-        return self.program.clone()
+        let count = seen.len();
+        seen.sort();
+        seen.dedup();
+        assert_eq!(seen.len(), count);
+    }
+
+    #[test]
+    fn test_expression_ids_distinct() {
+        let (program, errors) = parse("var a = 1; var b : float = a + 2.5; a = a * 2; while a < 10 { a = a + 1; }").parse();
+        assert!(errors.is_empty(), "{:?}", errors);
+
+        // Every node shows up once in the JSON rendering, along with its id
+        let json = program.to_json();
+        let mut ids: Vec<&str> = json.split("\"id\":").skip(1)
+            .map(|rest| rest.split(',').next().unwrap())
+            .collect();
+        let count = ids.len();
+
+        ids.sort();
+        ids.dedup();
+        assert_eq!(ids.len(), count, "{}", json);
+    }
+
+    // Fully parenthesised rendering of an expression, to check how it grouped
+    fn grouping(expr: &Expression) -> String {
+        match expr.expression_type {
+            ExpressionType::BinaryExpression(ref op, ref lhs, ref rhs) => return format!("({} {} {})", grouping(lhs), op, grouping(rhs)),
+            ExpressionType::UnaryExpression(ref op, ref rhs) => return format!("({}{})", op, grouping(rhs)),
+            ExpressionType::PromoteExpression(ref value) => return grouping(value),
+            ExpressionType::Literal(ref t) => return t.to_string(),
+            ref other => panic!("Unexpected {:?}", other)
+        }
     }
 
+    #[test]
+    fn test_operator_precedence() {
+        let cases = [
+            ("1 + 2 * 3 - 4", "((1 + (2 * 3)) - 4)"),
+            ("-2 * 3 % 4", "(((-2) * 3) % 4)"),
+            ("1 << 2 + 3", "(1 << (2 + 3))"),
+            ("1 | 2 ^ 3 & 4 >> 1", "(1 | (2 ^ (3 & (4 >> 1))))"),
+            ("5 & 1 == 1", "((5 & 1) == 1)"),
+            ("1 < 2 == 3 >= 4", "((1 < 2) == (3 >= 4))"),
+            ("1 < 2 == 3 > 4 && true || false", "((((1 < 2) == (3 > 4)) && true) || false)"),
+            ("false || true && 1 + 1.5 > 2.0", "(false || (true && ((1 + 1.5) > 2.0)))"),
+            ("(1 + 2) * 3", "((1 + 2) * 3)"),
+        ];
+
+        for &(source, expected) in cases.iter() {
+            match parse_expr(source) {
+                ParseResult::Success(expr) => assert_eq!(grouping(&expr), expected, "{}", source),
+                ParseResult::Failed(f) => panic!("{}: {}", source, f)
+            }
+        }
+
+        match parse_expr("1 % 2.0") {
+            ParseResult::Failed(f) => assert_eq!(f, "'%' expects ReturnInteger operands, got ReturnInteger and ReturnFloat"),
+            ParseResult::Success(expr) => panic!("Unexpectedly parsed {:?}", expr)
+        }
+    }
 }
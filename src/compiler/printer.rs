@@ -0,0 +1,182 @@
+use compiler::token::Token;
+use compiler::parser::Expression;
+use compiler::parser::ForLoop;
+use compiler::parser::Function;
+use compiler::parser::FunctionHeader;
+use compiler::parser::ReturnType;
+use compiler::visitor::ExpressionVisitor;
+
+// Width of one level of indentation in the printed tree
+const INDENT: usize = 2;
+
+// Renders an expression tree with one node per line, children indented below their parent
+pub fn pretty_print(expr: &Expression) -> String {
+    let mut printer = Printer {
+        depth: 0,
+        out: String::new()
+    };
+    printer.visit(expr);
+
+    return printer.out
+}
+
+fn print_line(label: String, depth: usize, out: &mut String) {
+    for _ in 0..depth * INDENT {
+        out.push(' ');
+    }
+    out.push_str(&label);
+    out.push('\n');
+}
+
+struct Printer {
+    depth: usize,
+    out: String
+}
+
+impl Printer {
+    // Prints the node's line, then its children one level further in
+    fn node(&mut self, label: String, expr: &Expression) {
+        print_line(label, self.depth, &mut self.out);
+
+        self.depth += 1;
+        self.walk(expr);
+        self.depth -= 1;
+    }
+}
+
+impl ExpressionVisitor for Printer {
+    fn visit_literal(&mut self, expr: &Expression, tok: &Token) {
+        self.node(format!("Literal {:?} : {:?}", tok, expr.return_type), expr)
+    }
+
+    fn visit_named(&mut self, expr: &Expression, name: &str, _value: &Expression) {
+        self.node(format!("Named '{}' : {:?}", name, expr.return_type), expr)
+    }
+
+    fn visit_assignment(&mut self, expr: &Expression, name: &str, _value: &Expression) {
+        self.node(format!("Assign '{}' : {:?}", name, expr.return_type), expr)
+    }
+
+    fn visit_print(&mut self, expr: &Expression, s: &str) {
+        self.node(format!("Print {:?}", s), expr)
+    }
+
+    fn visit_block(&mut self, expr: &Expression, _exprs: &[Expression]) {
+        self.node("Block".to_string(), expr)
+    }
+
+    fn visit_var(&mut self, expr: &Expression, _var: &Expression) {
+        self.node(format!("Var : {:?}", expr.return_type), expr)
+    }
+
+    fn visit_const(&mut self, expr: &Expression, _var: &Expression) {
+        self.node(format!("Const : {:?}", expr.return_type), expr)
+    }
+
+    fn visit_unary(&mut self, expr: &Expression, op: &Token, _rhs: &Expression) {
+        self.node(format!("Unary {:?} : {:?}", op, expr.return_type), expr)
+    }
+
+    fn visit_binary(&mut self, expr: &Expression, op: &Token, _lhs: &Expression, _rhs: &Expression) {
+        self.node(format!("Binary {:?} : {:?}", op, expr.return_type), expr)
+    }
+
+    fn visit_conditional(&mut self, expr: &Expression, _cond: &Expression, _body: &Expression, _otherwise: Option<&Expression>) {
+        self.node("Conditional".to_string(), expr)
+    }
+
+    fn visit_loop(&mut self, expr: &Expression, _body: &Expression) {
+        self.node("Loop".to_string(), expr)
+    }
+
+    fn visit_for(&mut self, expr: &Expression, f: &ForLoop) {
+        let range = if f.inclusive { "..=" } else { ".." };
+        self.node(format!("For '{}' {}", f.var, range), expr)
+    }
+
+    fn visit_while(&mut self, expr: &Expression, _cond: &Expression, _body: &Expression) {
+        self.node("While".to_string(), expr)
+    }
+
+    fn visit_break(&mut self, expr: &Expression) {
+        self.node("Break".to_string(), expr)
+    }
+
+    fn visit_continue(&mut self, expr: &Expression) {
+        self.node("Continue".to_string(), expr)
+    }
+
+    fn visit_function(&mut self, expr: &Expression, f: &Function) {
+        self.node(format!("Function '{}' : {:?}", f.header.name, f.header.return_type), expr)
+    }
+
+    fn visit_function_header(&mut self, expr: &Expression, header: &FunctionHeader) {
+        self.node(format!("FunctionHeader '{}' : {:?}", header.name, header.return_type), expr)
+    }
+
+    fn visit_struct(&mut self, expr: &Expression, name: &str, fields: &[(String, ReturnType)]) {
+        self.node(format!("Struct '{}'", name), expr);
+        for (field, rt) in fields {
+            print_line(format!("Field '{}' : {:?}", field, rt), self.depth + 1, &mut self.out);
+        }
+    }
+
+    fn visit_collection(&mut self, expr: &Expression, _elements: &[Expression]) {
+        self.node(format!("Collection : {:?}", expr.return_type), expr)
+    }
+
+    fn visit_call(&mut self, expr: &Expression, name: &str, _args: &[Expression]) {
+        self.node(format!("Call '{}' : {:?}", name, expr.return_type), expr)
+    }
+
+    fn visit_struct_literal(&mut self, expr: &Expression, name: &str, _values: &[(String, Expression)]) {
+        self.node(format!("StructLiteral '{}'", name), expr)
+    }
+
+    fn visit_field_access(&mut self, expr: &Expression, _receiver: &Expression, field: &str) {
+        self.node(format!("FieldAccess '{}' : {:?}", field, expr.return_type), expr)
+    }
+
+    fn visit_index(&mut self, expr: &Expression, _receiver: &Expression, _index: &Expression) {
+        self.node(format!("Index : {:?}", expr.return_type), expr)
+    }
+
+    fn visit_return(&mut self, expr: &Expression, _value: Option<&Expression>) {
+        self.node(format!("Return : {:?}", expr.return_type), expr)
+    }
+
+    fn visit_promote(&mut self, expr: &Expression, _value: &Expression) {
+        self.node(format!("Promote : {:?}", expr.return_type), expr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use compiler::Scanner;
+    use compiler::parser::Parser;
+    use compiler::parser::ParseResult;
+
+    fn parse_expression(source: &str) -> Expression {
+        let tokens = Scanner::new(source).tokenize();
+
+        match Parser::new(tokens).parse_bare_expression() {
+            ParseResult::Success(expr) => return expr,
+            ParseResult::Failed(f) => panic!("{}", f)
+        }
+    }
+
+    #[test]
+    fn test_pretty_print_nested() {
+        let expr = parse_expression("1 + 2 * 3");
+
+        assert_eq!(pretty_print(&expr), "\
+Binary Add : ReturnInteger
+  Literal IntegerLiteral(1) : ReturnInteger
+  Binary Multiply : ReturnInteger
+    Literal IntegerLiteral(2) : ReturnInteger
+    Literal IntegerLiteral(3) : ReturnInteger
+");
+    }
+}
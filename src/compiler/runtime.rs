@@ -0,0 +1,185 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use compiler::token::Token;
+use compiler::parser::ReturnType;
+
+// A value produced while evaluating a program
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Int(i32),
+    Float(f64),
+    Bool(bool),
+    Str(String),
+    Void
+}
+
+impl Value {
+    // The value a literal token stands for, if it is one
+    pub fn from_literal(tok: &Token) -> Option<Value> {
+        match *tok {
+            Token::IntegerLiteral(i) => return Some(Value::Int(i)),
+            Token::FloatLiteral(f) => return Some(Value::Float(f)),
+            Token::BooleanLiteral(b) => return Some(Value::Bool(b)),
+            Token::StringLiteral(ref s) => return Some(Value::Str(s.clone())),
+            Token::Null => return Some(Value::Void),
+            _ => return None
+        }
+    }
+
+    // The literal token that would produce this value
+    pub fn to_literal(&self) -> Token {
+        match *self {
+            Value::Int(i) => return Token::IntegerLiteral(i),
+            Value::Float(f) => return Token::FloatLiteral(f),
+            Value::Bool(b) => return Token::BooleanLiteral(b),
+            Value::Str(ref s) => return Token::StringLiteral(s.clone()),
+            Value::Void => return Token::Null
+        }
+    }
+
+    pub fn return_type(&self) -> ReturnType {
+        match *self {
+            Value::Int(_) => return ReturnType::ReturnInteger,
+            Value::Float(_) => return ReturnType::ReturnFloat,
+            Value::Bool(_) => return ReturnType::ReturnBool,
+            Value::Str(_) => return ReturnType::ReturnString,
+            Value::Void => return ReturnType::ReturnVoid
+        }
+    }
+}
+
+// Renders the value the way it would be written in source
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Value::Int(i) => write!(f, "{}", i),
+            Value::Float(x) => write!(f, "{}", x),
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::Str(ref s) => write!(f, "{}", s),
+            Value::Void => write!(f, "null")
+        }
+    }
+}
+
+// Variable values while a program runs. The parser's Environment only tracks
+// what has been declared and its type, this holds what the variables contain.
+#[derive(Debug, Clone)]
+pub struct RuntimeEnvironment {
+    pub enclosing: Option<Box<RuntimeEnvironment>>,
+    values: HashMap<String, Value>
+}
+
+impl RuntimeEnvironment {
+    pub fn new() -> RuntimeEnvironment {
+        RuntimeEnvironment {
+            enclosing: None,
+            values: HashMap::new()
+        }
+    }
+
+    pub fn new_sub(env: RuntimeEnvironment) -> RuntimeEnvironment {
+        RuntimeEnvironment {
+            enclosing: Some(Box::new(env)),
+            values: HashMap::new()
+        }
+    }
+
+    // Declares a variable in this scope, replacing any earlier one of the same name
+    pub fn define(&mut self, name: String, value: Value) {
+        self.values.insert(name, value);
+    }
+
+    // Updates the nearest variable of that name, which has to keep its type. Null
+    // carries no type of its own, the parser has already checked it's allowed.
+    pub fn assign(&mut self, name: &str, value: Value) -> Result<(), String> {
+        match self.values.get_mut(name) {
+            Some(current) => {
+                let null = *current == Value::Void || value == Value::Void;
+
+                if !null && current.return_type() != value.return_type() {
+                    return Err(format!("Can't assign {:?} to '{}' of type {:?}", value.return_type(), name, current.return_type()))
+                }
+
+                *current = value;
+                return Ok(())
+            },
+            None => {
+                match self.enclosing {
+                    Some(ref mut env) => return env.assign(name, value),
+                    None => return Err(format!("Undefined variable '{}'", name))
+                }
+            }
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Value> {
+        match self.values.get(name) {
+            Some(value) => return Some(value),
+            None => {
+                match self.enclosing {
+                    Some(ref env) => return env.get(name),
+                    None => return None
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_define_and_assign() {
+        let mut env = RuntimeEnvironment::new();
+
+        env.define("x".to_string(), Value::Int(1));
+        env.assign("x", Value::Int(5)).unwrap();
+
+        assert_eq!(env.get("x"), Some(&Value::Int(5)));
+        assert_eq!(env.get("x").map(|v| v.return_type()), Some(ReturnType::ReturnInteger));
+
+        assert_eq!(env.assign("x", Value::Str("five".to_string())),
+                   Err("Can't assign ReturnString to 'x' of type ReturnInteger".to_string()));
+        assert_eq!(env.get("x"), Some(&Value::Int(5)));
+    }
+
+    #[test]
+    fn test_assign_null() {
+        let mut env = RuntimeEnvironment::new();
+
+        env.define("x".to_string(), Value::Void);
+        env.assign("x", Value::Int(5)).unwrap();
+        env.assign("x", Value::Void).unwrap();
+
+        assert_eq!(env.get("x"), Some(&Value::Void));
+    }
+
+    #[test]
+    fn test_enclosing_scope() {
+        let mut outer = RuntimeEnvironment::new();
+        outer.define("f".to_string(), Value::Float(1.5));
+
+        let mut inner = RuntimeEnvironment::new_sub(outer);
+        inner.define("b".to_string(), Value::Bool(true));
+        inner.assign("f", Value::Float(2.5)).unwrap();
+
+        assert_eq!(inner.get("b"), Some(&Value::Bool(true)));
+        assert!(inner.assign("missing", Value::Void).is_err());
+
+        let outer = *inner.enclosing.unwrap();
+        assert_eq!(outer.get("f"), Some(&Value::Float(2.5)));
+        assert_eq!(outer.get("b"), None);
+    }
+
+    #[test]
+    fn test_literal_round_trip() {
+        for tok in [Token::IntegerLiteral(3), Token::FloatLiteral(0.5), Token::BooleanLiteral(false),
+                    Token::StringLiteral("s".to_string()), Token::Null] {
+            assert_eq!(Value::from_literal(&tok).unwrap().to_literal(), tok);
+        }
+
+        assert_eq!(Value::from_literal(&Token::Identifier("x".to_string())), None);
+    }
+}
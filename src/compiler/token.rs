@@ -1,3 +1,5 @@
+use std::fmt;
+
 #[derive(Debug, PartialEq, Clone)]
 pub enum Token {
 
@@ -48,10 +50,15 @@ pub enum Token {
     LeftBrace,
     RightBrace,
 
+    LeftBracket,
+    RightBracket,
+
     Semicolon,
 
     Colon,
 
+    Question,
+
     Quote,
 
     // Control flow
@@ -65,6 +72,12 @@ pub enum Token {
     For,
     While,
 
+    In,
+    Step,
+
+    Break,
+    Continue,
+
     Comment,
 
     // Object
@@ -81,6 +94,7 @@ pub enum Token {
     Identifier(String),
 
     DotDot,
+    DotDotEqual,
 
     RangeLiteral,
 
@@ -118,31 +132,120 @@ impl Default for Token {
     }
 }
 
+// Reserved words and the tokens they scan to
+const KEYWORDS: &[(&str, Token)] = &[
+    ("fn", Token::FunctionDecl),
+    ("const", Token::ConstDecl),
+    ("var", Token::VarDecl),
+    ("match", Token::Match),
+    ("return", Token::Return),
+    ("super", Token::Super),
+    ("if", Token::If),
+    ("else", Token::Else),
+    ("null", Token::Null),
+    ("for", Token::For),
+    ("while", Token::While),
+    ("in", Token::In),
+    ("step", Token::Step),
+    ("break", Token::Break),
+    ("continue", Token::Continue),
+    ("true", Token::BooleanLiteral(true)),
+    ("false", Token::BooleanLiteral(false)),
+    ("void", Token::VoidDecl),
+    ("collection", Token::CollectionDecl),
+    ("int", Token::IntegerDecl),
+    ("float", Token::FloatDecl),
+    ("string", Token::StringDecl),
+    ("bool", Token::BooleanDecl),
+    ("struct", Token::StructDecl),
+    ("print", Token::Print),
+];
+
+impl Token {
+    // The reserved word this token was scanned from, if any
+    pub fn keyword(&self) -> Option<&'static str> {
+        return spelling(self)
+    }
+
+    pub fn is_keyword(&self) -> bool {
+        self.keyword().is_some()
+    }
+}
+
+// Renders the token as it would appear in source
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(word) = spelling(self) {
+            return write!(f, "{}", word)
+        }
+
+        let text = match *self {
+            Token::Identifier(ref name) => return write!(f, "{}", name),
+            Token::StringLiteral(ref s) => return write!(f, "{:?}", s),
+            Token::IntegerLiteral(i) => return write!(f, "{}", i),
+            Token::FloatLiteral(x) => return write!(f, "{:?}", x),
+            Token::Error(ref e) => return write!(f, "<error: {}>", e),
+
+            Token::Illegal => "<illegal>",
+            Token::EOF => "<eof>",
+
+            Token::Assign => "=",
+            Token::Add => "+",
+            Token::Subtract => "-",
+            Token::Multiply => "*",
+            Token::Divide => "/",
+            Token::Or => "|",
+            Token::And => "&",
+            Token::LogicalOr => "||",
+            Token::LogicalAnd => "&&",
+            Token::ShiftLeft => "<<",
+            Token::ShiftRight => ">>",
+            Token::Xor => "^",
+            Token::Modulo => "%",
+
+            Token::LessThan => "<",
+            Token::GreaterThan => ">",
+            Token::LessThanEqual => "<=",
+            Token::GreaterThanEqual => ">=",
+            Token::Bang => "!",
+            Token::Equality => "==",
+            Token::NotEquality => "!=",
+
+            Token::Dot => ".",
+            Token::Comma => ",",
+            Token::LeftParenthesis => "(",
+            Token::RightParenthesis => ")",
+            Token::LeftBrace => "{",
+            Token::RightBrace => "}",
+            Token::LeftBracket => "[",
+            Token::RightBracket => "]",
+            Token::Semicolon => ";",
+            Token::Colon => ":",
+            Token::Question => "?",
+            Token::Quote => "\"",
+            Token::Comment => "//",
+            Token::DotDot => "..",
+            Token::DotDotEqual => "..=",
+
+            // Tokens that never come from a single piece of source text
+            ref t => return write!(f, "{:?}", t)
+        };
+
+        return write!(f, "{}", text)
+    }
+}
+
+// Inverse of lookup, the source spelling of a keyword token
+pub fn spelling(tok: &Token) -> Option<&'static str> {
+    KEYWORDS.iter()
+        .find(|&(_, t)| t == tok)
+        .map(|&(word, _)| word)
+}
+
 pub fn lookup(ident: &str) -> Token {
-    match ident {
-        "fn" => Token::FunctionDecl,
-        "const" => Token::ConstDecl,
-        "var" => Token::VarDecl,
-        "match" => Token::Match,
-        "return" => Token::Return,
-        "super" => Token::Super,
-        "if" => Token::If,
-        "else" => Token::Else,
-        "null" => Token::Null,
-        "for" => Token::For,
-        "while" => Token::While,
-        "true" => Token::BooleanLiteral(true),
-        "false" => Token::BooleanLiteral(false),
-        "void" => Token::VoidDecl,
-        "collection" => Token::CollectionDecl,
-        "int" => Token::IntegerDecl,
-        "float" => Token::FloatDecl,
-        "string" => Token::StringDecl,
-        "bool" => Token::BooleanDecl,
-        "struct" => Token::StructDecl,
-        "print" => Token::Print,
-
-        _ => Token::Identifier(ident.to_string()),
+    match KEYWORDS.iter().find(|&&(word, _)| word == ident) {
+        Some((_, t)) => t.clone(),
+        None => Token::Identifier(ident.to_string()),
     }
 }
 
@@ -150,3 +253,34 @@ pub fn lookup(ident: &str) -> Token {
 fn test_lookup() {
     assert_eq!(lookup("fn"), Token::FunctionDecl);
 }
+
+#[test]
+fn test_spelling_round_trip() {
+    for &(word, _) in KEYWORDS {
+        assert_eq!(spelling(&lookup(word)), Some(word));
+    }
+
+    assert_eq!(spelling(&Token::While), Some("while"));
+    assert_eq!(spelling(&Token::Identifier("x".to_string())), None);
+    assert_eq!(spelling(&Token::Add), None);
+}
+
+#[test]
+fn test_is_keyword() {
+    assert_eq!(Token::For.keyword(), Some("for"));
+    assert!(Token::BooleanLiteral(true).is_keyword());
+    assert!(!Token::Identifier("x".to_string()).is_keyword());
+}
+
+#[test]
+fn test_display() {
+    assert_eq!(Token::Add.to_string(), "+");
+    assert_eq!(Token::LeftBrace.to_string(), "{");
+    assert_eq!(Token::GreaterThanEqual.to_string(), ">=");
+    assert_eq!(Token::Identifier("count".to_string()).to_string(), "count");
+    assert_eq!(Token::IntegerLiteral(42).to_string(), "42");
+    assert_eq!(Token::FloatLiteral(1.0).to_string(), "1.0");
+    assert_eq!(Token::StringLiteral("hi".to_string()).to_string(), "\"hi\"");
+    assert_eq!(Token::BooleanLiteral(true).to_string(), "true");
+    assert_eq!(Token::FunctionDecl.to_string(), "fn");
+}
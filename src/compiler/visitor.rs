@@ -0,0 +1,234 @@
+use compiler::token::Token;
+use compiler::parser::Expression;
+use compiler::parser::ExpressionType;
+use compiler::parser::ForLoop;
+use compiler::parser::Function;
+use compiler::parser::FunctionHeader;
+use compiler::parser::ReturnType;
+
+// The direct children of a node, in source order
+pub fn children(expr: &Expression) -> Vec<&Expression> {
+    match expr.expression_type {
+        ExpressionType::Literal(_) |
+        ExpressionType::PrintExpression(_) |
+        ExpressionType::BreakExpression |
+        ExpressionType::ContinueExpression |
+        ExpressionType::FunctionHeaderExpression(_) |
+        ExpressionType::StructExpression(..) => vec![],
+
+        ExpressionType::LiteralExpression(_, ref value) |
+        ExpressionType::AssignmentExpression(_, ref value) |
+        ExpressionType::VarExpression(ref value) |
+        ExpressionType::ConstExpression(ref value) |
+        ExpressionType::UnaryExpression(_, ref value) |
+        ExpressionType::LoopExpression(ref value) |
+        ExpressionType::FieldAccess(ref value, _) |
+        ExpressionType::PromoteExpression(ref value) => vec![value],
+
+        ExpressionType::BinaryExpression(_, ref lhs, ref rhs) |
+        ExpressionType::WhileExpression(ref lhs, ref rhs) |
+        ExpressionType::IndexExpression(ref lhs, ref rhs) => vec![lhs, rhs],
+
+        ExpressionType::ConditionalExpression(ref cond, ref body, ref otherwise) => {
+            let mut children = vec![&**cond, &**body];
+            children.extend(otherwise.iter().map(|e| &**e));
+            children
+        },
+
+        ExpressionType::ForExpression(ref f) => vec![&f.start, &f.end, &f.step, &f.body],
+
+        ExpressionType::FunctionExpression(ref f) => vec![&f.body],
+
+        ExpressionType::BlockExpression(ref exprs) |
+        ExpressionType::CollectionExpression(ref exprs) |
+        ExpressionType::CallExpression(_, ref exprs) => exprs.iter().collect(),
+
+        ExpressionType::StructLiteral(_, ref values) => values.iter().map(|v| &v.1).collect(),
+
+        ExpressionType::ReturnExpression(ref value) => value.iter().map(|v| &**v).collect()
+    }
+}
+
+// Walks an expression tree. `visit` hands each node to the method for its kind,
+// which by default just carries on into the node's children. Implementors
+// override the kinds they care about and call `walk` to keep descending.
+pub trait ExpressionVisitor {
+    fn visit(&mut self, expr: &Expression) {
+        match expr.expression_type {
+            ExpressionType::Literal(ref t) => self.visit_literal(expr, t),
+            ExpressionType::LiteralExpression(ref name, ref value) => self.visit_named(expr, name, value),
+            ExpressionType::AssignmentExpression(ref name, ref value) => self.visit_assignment(expr, name, value),
+            ExpressionType::PrintExpression(ref s) => self.visit_print(expr, s),
+            ExpressionType::BlockExpression(ref exprs) => self.visit_block(expr, exprs),
+            ExpressionType::VarExpression(ref var) => self.visit_var(expr, var),
+            ExpressionType::ConstExpression(ref var) => self.visit_const(expr, var),
+            ExpressionType::UnaryExpression(ref op, ref rhs) => self.visit_unary(expr, op, rhs),
+            ExpressionType::BinaryExpression(ref op, ref lhs, ref rhs) => self.visit_binary(expr, op, lhs, rhs),
+            ExpressionType::ConditionalExpression(ref cond, ref body, ref otherwise) => {
+                self.visit_conditional(expr, cond, body, otherwise.as_deref())
+            },
+            ExpressionType::LoopExpression(ref body) => self.visit_loop(expr, body),
+            ExpressionType::ForExpression(ref f) => self.visit_for(expr, f),
+            ExpressionType::WhileExpression(ref cond, ref body) => self.visit_while(expr, cond, body),
+            ExpressionType::BreakExpression => self.visit_break(expr),
+            ExpressionType::ContinueExpression => self.visit_continue(expr),
+            ExpressionType::FunctionExpression(ref f) => self.visit_function(expr, f),
+            ExpressionType::FunctionHeaderExpression(ref header) => self.visit_function_header(expr, header),
+            ExpressionType::StructExpression(ref name, ref fields) => self.visit_struct(expr, name, fields),
+            ExpressionType::CollectionExpression(ref elements) => self.visit_collection(expr, elements),
+            ExpressionType::CallExpression(ref name, ref args) => self.visit_call(expr, name, args),
+            ExpressionType::StructLiteral(ref name, ref values) => self.visit_struct_literal(expr, name, values),
+            ExpressionType::FieldAccess(ref receiver, ref field) => self.visit_field_access(expr, receiver, field),
+            ExpressionType::IndexExpression(ref receiver, ref index) => self.visit_index(expr, receiver, index),
+            ExpressionType::ReturnExpression(ref value) => self.visit_return(expr, value.as_deref()),
+            ExpressionType::PromoteExpression(ref value) => self.visit_promote(expr, value)
+        }
+    }
+
+    // Visits each direct child of the node
+    fn walk(&mut self, expr: &Expression) {
+        for child in children(expr) {
+            self.visit(child);
+        }
+    }
+
+    fn visit_literal(&mut self, expr: &Expression, _tok: &Token) {
+        self.walk(expr)
+    }
+
+    fn visit_named(&mut self, expr: &Expression, _name: &str, _value: &Expression) {
+        self.walk(expr)
+    }
+
+    fn visit_assignment(&mut self, expr: &Expression, _name: &str, _value: &Expression) {
+        self.walk(expr)
+    }
+
+    fn visit_print(&mut self, expr: &Expression, _s: &str) {
+        self.walk(expr)
+    }
+
+    fn visit_block(&mut self, expr: &Expression, _exprs: &[Expression]) {
+        self.walk(expr)
+    }
+
+    fn visit_var(&mut self, expr: &Expression, _var: &Expression) {
+        self.walk(expr)
+    }
+
+    fn visit_const(&mut self, expr: &Expression, _var: &Expression) {
+        self.walk(expr)
+    }
+
+    fn visit_unary(&mut self, expr: &Expression, _op: &Token, _rhs: &Expression) {
+        self.walk(expr)
+    }
+
+    fn visit_binary(&mut self, expr: &Expression, _op: &Token, _lhs: &Expression, _rhs: &Expression) {
+        self.walk(expr)
+    }
+
+    fn visit_conditional(&mut self, expr: &Expression, _cond: &Expression, _body: &Expression, _otherwise: Option<&Expression>) {
+        self.walk(expr)
+    }
+
+    fn visit_loop(&mut self, expr: &Expression, _body: &Expression) {
+        self.walk(expr)
+    }
+
+    fn visit_for(&mut self, expr: &Expression, _f: &ForLoop) {
+        self.walk(expr)
+    }
+
+    fn visit_while(&mut self, expr: &Expression, _cond: &Expression, _body: &Expression) {
+        self.walk(expr)
+    }
+
+    fn visit_break(&mut self, expr: &Expression) {
+        self.walk(expr)
+    }
+
+    fn visit_continue(&mut self, expr: &Expression) {
+        self.walk(expr)
+    }
+
+    fn visit_function(&mut self, expr: &Expression, _f: &Function) {
+        self.walk(expr)
+    }
+
+    fn visit_function_header(&mut self, expr: &Expression, _header: &FunctionHeader) {
+        self.walk(expr)
+    }
+
+    fn visit_struct(&mut self, expr: &Expression, _name: &str, _fields: &[(String, ReturnType)]) {
+        self.walk(expr)
+    }
+
+    fn visit_collection(&mut self, expr: &Expression, _elements: &[Expression]) {
+        self.walk(expr)
+    }
+
+    fn visit_call(&mut self, expr: &Expression, _name: &str, _args: &[Expression]) {
+        self.walk(expr)
+    }
+
+    fn visit_struct_literal(&mut self, expr: &Expression, _name: &str, _values: &[(String, Expression)]) {
+        self.walk(expr)
+    }
+
+    fn visit_field_access(&mut self, expr: &Expression, _receiver: &Expression, _field: &str) {
+        self.walk(expr)
+    }
+
+    fn visit_index(&mut self, expr: &Expression, _receiver: &Expression, _index: &Expression) {
+        self.walk(expr)
+    }
+
+    fn visit_return(&mut self, expr: &Expression, _value: Option<&Expression>) {
+        self.walk(expr)
+    }
+
+    fn visit_promote(&mut self, expr: &Expression, _value: &Expression) {
+        self.walk(expr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use compiler::Scanner;
+    use compiler::parser::Parser;
+
+    // Tallies binary expressions, and how many of them are additions
+    struct BinaryCounter {
+        binaries: usize,
+        additions: usize
+    }
+
+    impl ExpressionVisitor for BinaryCounter {
+        fn visit_binary(&mut self, expr: &Expression, op: &Token, _lhs: &Expression, _rhs: &Expression) {
+            self.binaries += 1;
+            if *op == Token::Add {
+                self.additions += 1;
+            }
+
+            self.walk(expr)
+        }
+    }
+
+    #[test]
+    fn test_count_binary_expressions() {
+        let source = "var a = 1 + 2 * 3; { var b = (a - 1) + a; } while a < 10 { a = a + 1; }";
+        let (program, errors) = Parser::new(Scanner::new(source).tokenize()).parse();
+        assert!(errors.is_empty(), "{:?}", errors);
+
+        let mut counter = BinaryCounter { binaries: 0, additions: 0 };
+        for statement in &program.statements {
+            counter.visit(&statement.expr);
+        }
+
+        assert_eq!(counter.binaries, 6);
+        assert_eq!(counter.additions, 3);
+    }
+}
@@ -1,4 +1,5 @@
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+#[allow(non_camel_case_types)]
 pub enum Opcode {
     HLT,
     LT,
@@ -21,16 +22,172 @@ pub enum Opcode {
     NOP,
     ALOC,
     LBL,
+    NEG,
+    NOT,
+    FADD,
+    FSUB,
+    FMUL,
+    FDIV,
+    FLOAD,
+    ITOF,
+    FTOI,
+    JMPI,
+    JEQI,
+    MOV,
+    CMPI,
+    LOADA,
+    SW,
+    LW,
+    DUMP,
+    LOADS,
+    CMOV,
+    STORE_IDX,
+    LOAD_IDX,
+    ASSERT,
+    LOOP,
+    SYS,
+    PRTR,
+    WADD,
+    WSUB,
+    WMUL,
+    SADD,
+    SSUB,
+    SMUL,
+    FREE,
+    ALOC_ABS,
 }
 
-#[derive(Debug, PartialEq)]
+// How an operand byte (or pair of bytes) following the opcode is interpreted
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum OperandKind {
+    Register,
+    Immediate8,
+    Immediate16,
+    Unused,
+}
+
+impl OperandKind {
+    pub fn width(&self) -> usize {
+        match *self {
+            OperandKind::Immediate16 => 2,
+            _ => 1,
+        }
+    }
+}
+
+use self::OperandKind::*;
+
+impl Opcode {
+    // Strict decoding that rejects bytes which aren't assigned to any opcode,
+    // returning the offending byte. The reserved IGL byte decodes successfully.
+    // This is inherent rather than a TryFrom impl, which would clash with the
+    // blanket impl that From<u8> already provides.
+    pub fn try_from(v: u8) -> Result<Opcode, u8> {
+        let opcode = Opcode::from(v);
+
+        if opcode == Opcode::IGL && v != u8::from(Opcode::IGL) {
+            return Err(v)
+        }
+
+        return Ok(opcode)
+    }
+
+    // Layout of the bytes following the opcode, the single source of truth for
+    // how far each instruction advances the program counter
+    pub fn operand_kinds(&self) -> &'static [OperandKind] {
+        match *self {
+            Opcode::IGL => &[],
+
+            Opcode::LOAD | Opcode::FLOAD |
+            Opcode::CMPI | Opcode::LOADA |
+            Opcode::LOADS => &[Register, Immediate16],
+
+            Opcode::ADD | Opcode::SUB | Opcode::MUL | Opcode::DIV |
+            Opcode::FADD | Opcode::FSUB | Opcode::FMUL | Opcode::FDIV |
+            Opcode::WADD | Opcode::WSUB | Opcode::WMUL |
+            Opcode::SADD | Opcode::SSUB | Opcode::SMUL |
+            Opcode::STORE_IDX | Opcode::LOAD_IDX => &[Register, Register, Register],
+
+            Opcode::EQ | Opcode::NEQ | Opcode::GT | Opcode::LT |
+            Opcode::GTE | Opcode::LTE |
+            Opcode::ITOF | Opcode::FTOI |
+            Opcode::MOV | Opcode::CMOV |
+            Opcode::SW | Opcode::LW | Opcode::DUMP |
+            Opcode::ASSERT | Opcode::LOOP => &[Register, Register, Unused],
+
+            Opcode::JMP | Opcode::JMPF | Opcode::JMPB |
+            Opcode::JEQ | Opcode::JNE => &[Register, Unused, Unused],
+
+            Opcode::JMPI | Opcode::JEQI => &[Immediate16, Unused],
+
+            Opcode::SYS => &[Immediate8, Unused, Unused],
+
+            Opcode::HLT |
+            Opcode::ALOC | Opcode::NEG | Opcode::NOT |
+            Opcode::ALOC_ABS |
+            Opcode::FREE |
+            Opcode::PRTR => &[Register, Unused, Unused],
+
+            Opcode::NOP | Opcode::LBL => &[Unused, Unused, Unused],
+        }
+    }
+
+    // Total size of the encoded instruction in bytes, including the opcode
+    pub fn width(&self) -> usize {
+        1 + self.operand_kinds().iter().map(|k| k.width()).sum::<usize>()
+    }
+
+    // Number of padding bytes at the end of the instruction
+    pub fn unused_width(&self) -> usize {
+        self.operand_kinds().iter().rev()
+            .take_while(|k| **k == Unused)
+            .count()
+    }
+}
+
+// A single fixed width instruction: an opcode byte followed by three operand bytes
+#[derive(Debug, PartialEq, Clone)]
 pub struct Instruction {
-    opcode: Opcode
+    opcode: Opcode,
+    operands: [u8; 3]
 }
 
 impl From<u8> for Opcode {
     fn from(v: u8) -> Self {
         match v {
+            52 => return Opcode::ALOC_ABS,
+            51 => return Opcode::FREE,
+            50 => return Opcode::SMUL,
+            49 => return Opcode::SSUB,
+            48 => return Opcode::SADD,
+            47 => return Opcode::WMUL,
+            46 => return Opcode::WSUB,
+            45 => return Opcode::WADD,
+            44 => return Opcode::PRTR,
+            43 => return Opcode::SYS,
+            42 => return Opcode::LOOP,
+            41 => return Opcode::ASSERT,
+            40 => return Opcode::LOAD_IDX,
+            39 => return Opcode::STORE_IDX,
+            38 => return Opcode::CMOV,
+            37 => return Opcode::LOADS,
+            36 => return Opcode::DUMP,
+            35 => return Opcode::LW,
+            34 => return Opcode::SW,
+            33 => return Opcode::LOADA,
+            32 => return Opcode::CMPI,
+            31 => return Opcode::MOV,
+            30 => return Opcode::JEQI,
+            29 => return Opcode::JMPI,
+            28 => return Opcode::FTOI,
+            27 => return Opcode::ITOF,
+            26 => return Opcode::FLOAD,
+            25 => return Opcode::FDIV,
+            24 => return Opcode::FMUL,
+            23 => return Opcode::FSUB,
+            22 => return Opcode::FADD,
+            21 => return Opcode::NOT,
+            20 => return Opcode::NEG,
             19 => return Opcode::LBL,
             18 => return Opcode::ALOC,
             17 => return Opcode::NOP,
@@ -59,6 +216,40 @@ impl From<u8> for Opcode {
 impl<'a> From<&'a str> for Opcode {
     fn from(str: &'a str) -> Self {
         match str.to_lowercase().as_ref() {
+            "aloc_abs" => return Opcode::ALOC_ABS,
+            "free" => return Opcode::FREE,
+            "smul" => return Opcode::SMUL,
+            "ssub" => return Opcode::SSUB,
+            "sadd" => return Opcode::SADD,
+            "wmul" => return Opcode::WMUL,
+            "wsub" => return Opcode::WSUB,
+            "wadd" => return Opcode::WADD,
+            "prtr" => return Opcode::PRTR,
+            "sys" => return Opcode::SYS,
+            "loop" => return Opcode::LOOP,
+            "assert" => return Opcode::ASSERT,
+            "load_idx" => return Opcode::LOAD_IDX,
+            "store_idx" => return Opcode::STORE_IDX,
+            "cmov" => return Opcode::CMOV,
+            "loads" => return Opcode::LOADS,
+            "dump" => return Opcode::DUMP,
+            "lw" => return Opcode::LW,
+            "sw" => return Opcode::SW,
+            "loada" => return Opcode::LOADA,
+            "cmpi" => return Opcode::CMPI,
+            "mov" => return Opcode::MOV,
+            "jeqi" => return Opcode::JEQI,
+            "jmpi" => return Opcode::JMPI,
+            "ftoi" => return Opcode::FTOI,
+            "itof" => return Opcode::ITOF,
+            "fload" => return Opcode::FLOAD,
+            "fdiv" => return Opcode::FDIV,
+            "fmul" => return Opcode::FMUL,
+            "fsub" => return Opcode::FSUB,
+            "fadd" => return Opcode::FADD,
+            "not" => return Opcode::NOT,
+            "neg" => return Opcode::NEG,
+            "lbl" => return Opcode::LBL,
             "aloc" => return Opcode::ALOC,
             "nop" => return Opcode::NOP,
             "lt" => return Opcode::LT,
@@ -83,12 +274,106 @@ impl<'a> From<&'a str> for Opcode {
     }
 }
 
+// The exact inverse of From<u8>, IGL takes the reserved byte 255
+impl From<Opcode> for u8 {
+    fn from(opcode: Opcode) -> Self {
+        match opcode {
+            Opcode::LOAD => 0,
+            Opcode::ADD => 1,
+            Opcode::SUB => 2,
+            Opcode::MUL => 3,
+            Opcode::DIV => 4,
+            Opcode::HLT => 5,
+            Opcode::JMP => 6,
+            Opcode::JMPF => 7,
+            Opcode::JMPB => 8,
+            Opcode::EQ => 9,
+            Opcode::JEQ => 10,
+            Opcode::JNE => 11,
+            Opcode::NEQ => 12,
+            Opcode::GTE => 13,
+            Opcode::LTE => 14,
+            Opcode::GT => 15,
+            Opcode::LT => 16,
+            Opcode::NOP => 17,
+            Opcode::ALOC => 18,
+            Opcode::LBL => 19,
+            Opcode::NEG => 20,
+            Opcode::NOT => 21,
+            Opcode::FADD => 22,
+            Opcode::FSUB => 23,
+            Opcode::FMUL => 24,
+            Opcode::FDIV => 25,
+            Opcode::FLOAD => 26,
+            Opcode::ITOF => 27,
+            Opcode::FTOI => 28,
+            Opcode::JMPI => 29,
+            Opcode::JEQI => 30,
+            Opcode::MOV => 31,
+            Opcode::CMPI => 32,
+            Opcode::LOADA => 33,
+            Opcode::SW => 34,
+            Opcode::LW => 35,
+            Opcode::DUMP => 36,
+            Opcode::LOADS => 37,
+            Opcode::CMOV => 38,
+            Opcode::STORE_IDX => 39,
+            Opcode::LOAD_IDX => 40,
+            Opcode::ASSERT => 41,
+            Opcode::LOOP => 42,
+            Opcode::SYS => 43,
+            Opcode::PRTR => 44,
+            Opcode::WADD => 45,
+            Opcode::WSUB => 46,
+            Opcode::WMUL => 47,
+            Opcode::SADD => 48,
+            Opcode::SSUB => 49,
+            Opcode::SMUL => 50,
+            Opcode::FREE => 51,
+            Opcode::ALOC_ABS => 52,
+            Opcode::IGL => 255,
+        }
+    }
+}
+
 impl Instruction {
     pub fn new(opcode: Opcode) -> Instruction {
+        Instruction::with_operands(opcode, [0; 3])
+    }
+
+    pub fn with_operands(opcode: Opcode, operands: [u8; 3]) -> Instruction {
         Instruction {
-            opcode: opcode
+            opcode,
+            operands
         }
     }
+
+    pub fn opcode(&self) -> &Opcode {
+        &self.opcode
+    }
+
+    pub fn operands(&self) -> &[u8; 3] {
+        &self.operands
+    }
+
+    pub fn encode(&self) -> [u8; 4] {
+        [u8::from(self.opcode), self.operands[0], self.operands[1], self.operands[2]]
+    }
+
+    // Decodes the instruction at the start of `bytes`, missing operand bytes read as zero
+    pub fn decode(bytes: &[u8]) -> Instruction {
+        let opcode = match bytes.first() {
+            Some(&b) => Opcode::from(b),
+            None => Opcode::IGL,
+        };
+
+        let mut operands = [0; 3];
+        for (operand, &b) in operands.iter_mut().zip(bytes.iter().skip(1)) {
+            *operand = b;
+        }
+
+        Instruction::with_operands(opcode, operands)
+    }
 }
 
 #[cfg(test)]
@@ -107,6 +392,106 @@ mod tests {
         assert_eq!(instruction.opcode, Opcode::HLT);
     }
 
+    const ALL_OPCODES: [Opcode; 54] = [
+        Opcode::HLT, Opcode::LT, Opcode::GT, Opcode::LTE, Opcode::GTE,
+        Opcode::NEQ, Opcode::EQ, Opcode::JEQ, Opcode::JNE, Opcode::JMP,
+        Opcode::JMPF, Opcode::JMPB, Opcode::IGL, Opcode::LOAD, Opcode::ADD,
+        Opcode::SUB, Opcode::MUL, Opcode::DIV, Opcode::NOP, Opcode::ALOC,
+        Opcode::LBL, Opcode::NEG, Opcode::NOT, Opcode::FADD, Opcode::FSUB,
+        Opcode::FMUL, Opcode::FDIV, Opcode::FLOAD, Opcode::ITOF, Opcode::FTOI,
+        Opcode::JMPI, Opcode::JEQI, Opcode::MOV, Opcode::CMPI,
+        Opcode::LOADA, Opcode::SW, Opcode::LW, Opcode::DUMP,
+        Opcode::LOADS, Opcode::CMOV, Opcode::STORE_IDX, Opcode::LOAD_IDX,
+        Opcode::ASSERT, Opcode::LOOP, Opcode::SYS,
+        Opcode::PRTR,
+        Opcode::WADD, Opcode::WSUB, Opcode::WMUL,
+        Opcode::SADD, Opcode::SSUB, Opcode::SMUL,
+        Opcode::FREE,
+        Opcode::ALOC_ABS,
+    ];
+
+    #[test]
+    fn test_opcode_byte_round_trip() {
+        for &op in ALL_OPCODES.iter() {
+            assert_eq!(Opcode::from(u8::from(op)), op);
+        }
+
+        assert_eq!(u8::from(Opcode::IGL), 255);
+    }
+
+    #[test]
+    fn test_byte_opcode_round_trip() {
+        for b in 0..=255u8 {
+            let op = Opcode::from(b);
+
+            if op != Opcode::IGL {
+                assert_eq!(u8::from(op), b);
+            }
+        }
+    }
+
+    #[test]
+    fn test_try_from_byte() {
+        assert_eq!(Opcode::try_from(200), Err(200));
+        assert_eq!(Opcode::try_from(5), Ok(Opcode::HLT));
+        assert_eq!(Opcode::try_from(255), Ok(Opcode::IGL));
+    }
+
+    #[test]
+    fn test_operand_kinds() {
+        assert_eq!(Opcode::LOAD.operand_kinds(), &[OperandKind::Register, OperandKind::Immediate16]);
+        assert_eq!(Opcode::EQ.operand_kinds(), &[OperandKind::Register, OperandKind::Register, OperandKind::Unused]);
+        assert_eq!(Opcode::IGL.operand_kinds(), &[]);
+    }
+
+    #[test]
+    fn test_opcode_width() {
+        assert_eq!(Opcode::LOAD.width(), 4);
+        assert_eq!(Opcode::NOP.width(), 4);
+        assert_eq!(Opcode::NOP.unused_width(), 3);
+        assert_eq!(Opcode::JMP.width(), 4);
+        assert_eq!(Opcode::HLT.width(), 4);
+        assert_eq!(Opcode::IGL.width(), 1);
+    }
+
+    #[test]
+    fn test_encode_decode_registers() {
+        let instruction = Instruction::with_operands(Opcode::ADD, [0, 1, 2]);
+
+        assert_eq!(instruction.encode(), [1, 0, 1, 2]);
+        assert_eq!(Instruction::decode(&instruction.encode()), instruction);
+    }
+
+    #[test]
+    fn test_encode_decode_immediate() {
+        let instruction = Instruction::with_operands(Opcode::LOAD, [3, 1, 244]);
+
+        assert_eq!(instruction.encode(), [0, 3, 1, 244]);
+        assert_eq!(Instruction::decode(&instruction.encode()), instruction);
+    }
+
+    #[test]
+    fn test_encode_decode_single_register() {
+        let instruction = Instruction::with_operands(Opcode::JMP, [4, 0, 0]);
+
+        assert_eq!(instruction.encode(), [6, 4, 0, 0]);
+        assert_eq!(Instruction::decode(&instruction.encode()), instruction);
+    }
+
+    #[test]
+    fn test_encode_decode_no_operands() {
+        let instruction = Instruction::new(Opcode::HLT);
+
+        assert_eq!(instruction.encode(), [5, 0, 0, 0]);
+        assert_eq!(Instruction::decode(&instruction.encode()), instruction);
+    }
+
+    #[test]
+    fn test_decode_short_input() {
+        assert_eq!(Instruction::decode(&[5]), Instruction::new(Opcode::HLT));
+        assert_eq!(Instruction::decode(&[]), Instruction::new(Opcode::IGL));
+    }
+
     #[test]
     fn test_instruction_from_string() {
         let instruction = Instruction::new(Opcode::from("HLT"));
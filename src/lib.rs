@@ -1,4 +1,7 @@
+#![allow(clippy::needless_return, clippy::needless_bool_assign, clippy::new_without_default, clippy::inherent_to_string, clippy::bool_assert_comparison)]
+
 pub mod compiler;
 pub mod repl;
 pub mod vm;
 pub mod instruction;
+pub mod assembler;
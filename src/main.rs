@@ -1,12 +1,126 @@
+#![allow(clippy::needless_return, clippy::needless_bool_assign, clippy::new_without_default, clippy::inherent_to_string, clippy::bool_assert_comparison)]
+
 pub mod vm;
 pub mod instruction;
+pub mod assembler;
 pub mod repl;
 pub mod compiler;
 
+use std::env;
+use std::fs::File;
+use std::io;
+use std::io::IsTerminal;
+use std::io::Read;
+use std::process;
+
+use compiler::Scanner;
+
+const USAGE: &str = "Usage: iv [repl | run <file> | tokens <file>]
+
+`run` compiles the whole file to bytecode before running it. Only integer and
+boolean variables, arithmetic, comparisons, blocks and while loops compile so far,
+anything else (print, if, for, functions, floats, strings) stops with an error.
+Piped input is run the same way.";
+
+// What the binary was asked to do on the command line
+#[derive(Debug, PartialEq)]
+enum Action {
+    Repl,
+    Run(String),
+    Tokens(String),
+}
+
+// Maps the arguments after the program name to an action
+fn parse_args(args: &[String]) -> Result<Action, String> {
+    let args: Vec<&str> = args.iter().map(|a| a.as_str()).collect();
+
+    match args.as_slice() {
+        [] | ["repl"] => return Ok(Action::Repl),
+        ["run", file] => return Ok(Action::Run(file.to_string())),
+        ["tokens", file] => return Ok(Action::Tokens(file.to_string())),
+        _ => return Err(USAGE.to_string()),
+    }
+}
+
+fn read_file(path: &str) -> String {
+    let mut contents = String::new();
+
+    let result = File::open(path).and_then(|mut f| f.read_to_string(&mut contents));
+    if let Err(e) = result {
+        eprintln!("Unable to read {}: {}", path, e);
+        process::exit(1);
+    }
+
+    return contents
+}
+
+fn run_batch(repl: &mut repl::REPL, input: &mut dyn Read) {
+    if let Err(e) = repl.run_batch(input) {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+}
+
 fn main() {
-    println!("Initialising....");
+    let args: Vec<String> = env::args().skip(1).collect();
+
+    let action = match parse_args(&args) {
+        Ok(action) => action,
+        Err(usage) => {
+            eprintln!("{}", usage);
+            process::exit(2);
+        }
+    };
+
+    match action {
+        Action::Run(path) => {
+            let source = read_file(&path);
+            run_batch(&mut repl::REPL::with_history_file(None), &mut source.as_bytes());
+        },
+
+        Action::Tokens(path) => {
+            let source = read_file(&path);
+
+            for token in Scanner::new(&source).tokenize() {
+                println!("{:?}", token);
+            }
+        },
+
+        Action::Repl => {
+            let mut repl = repl::REPL::new();
+
+            // Piped input is run as a whole program rather than line by line
+            if !io::stdin().is_terminal() {
+                run_batch(&mut repl, &mut io::stdin());
+                return;
+            }
+
+            println!("Initialising....");
+
+            repl.run();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(list: &[&str]) -> Vec<String> {
+        list.iter().map(|a| a.to_string()).collect()
+    }
 
-    let mut repl = repl::REPL::new();
+    #[test]
+    fn test_parse_args() {
+        assert_eq!(parse_args(&args(&["run", "x.iv"])), Ok(Action::Run("x.iv".to_string())));
+        assert_eq!(parse_args(&args(&["tokens", "x.iv"])), Ok(Action::Tokens("x.iv".to_string())));
+        assert_eq!(parse_args(&args(&["repl"])), Ok(Action::Repl));
+        assert_eq!(parse_args(&args(&[])), Ok(Action::Repl));
+    }
 
-    repl.run();
+    #[test]
+    fn test_parse_args_invalid() {
+        assert!(parse_args(&args(&["run"])).is_err());
+        assert!(parse_args(&args(&["compile", "x.iv"])).is_err());
+    }
 }
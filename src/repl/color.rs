@@ -0,0 +1,87 @@
+use std::env;
+
+use compiler::token::Token;
+use compiler::token;
+
+const KEYWORD: &str = "\x1b[35m";
+const LITERAL: &str = "\x1b[32m";
+const ERROR: &str = "\x1b[31m";
+const RESET: &str = "\x1b[0m";
+
+// Wraps REPL output in ANSI colors when enabled, otherwise passes it through untouched
+pub struct Colorizer {
+    enabled: bool
+}
+
+impl Colorizer {
+    pub fn new(enabled: bool) -> Colorizer {
+        Colorizer {
+            enabled
+        }
+    }
+
+    // Colored unless the user has opted out through NO_COLOR
+    pub fn from_env() -> Colorizer {
+        Colorizer::new(env::var_os("NO_COLOR").is_none())
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+    }
+
+    pub fn token(&self, tok: &Token) -> String {
+        let color = match *tok {
+            Token::StringLiteral(_) | Token::IntegerLiteral(_) |
+            Token::FloatLiteral(_) | Token::BooleanLiteral(_) | Token::Null => Some(LITERAL),
+            Token::Error(_) => Some(ERROR),
+            ref t if token::spelling(t).is_some() => Some(KEYWORD),
+            _ => None
+        };
+
+        match color {
+            Some(color) => return self.paint(color, &tok.to_string()),
+            None => return tok.to_string()
+        }
+    }
+
+    pub fn error(&self, message: &str) -> String {
+        return self.paint(ERROR, message)
+    }
+
+    fn paint(&self, color: &str, text: &str) -> String {
+        if !self.enabled {
+            return text.to_string()
+        }
+
+        return format!("{}{}{}", color, text, RESET)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_colors() {
+        let colorizer = Colorizer::new(true);
+
+        assert_eq!(colorizer.token(&Token::FunctionDecl), "\x1b[35mfn\x1b[0m");
+        assert_eq!(colorizer.token(&Token::IntegerLiteral(3)), "\x1b[32m3\x1b[0m");
+        assert_eq!(colorizer.token(&Token::Add), "+");
+        assert_eq!(colorizer.error("oops"), "\x1b[31moops\x1b[0m");
+    }
+
+    #[test]
+    fn test_disabled_is_plain() {
+        let colorizer = Colorizer::new(false);
+
+        for tok in [Token::FunctionDecl, Token::IntegerLiteral(3), Token::Error("bad".to_string())] {
+            assert!(!colorizer.token(&tok).contains('\x1b'));
+        }
+        assert_eq!(colorizer.error("oops"), "oops");
+    }
+}
@@ -0,0 +1,282 @@
+use std::io;
+use std::io::IsTerminal;
+use std::io::Read;
+use std::io::Write;
+
+use std::process::Command;
+use std::process::Stdio;
+
+// Source of input lines for the REPL, swappable so tests can script input
+pub trait LineReader {
+    // Returns None once there's no more input to read
+    fn read_line(&mut self, prompt: &str, history: &[String]) -> Option<String>;
+}
+
+// Picks the line editor when attached to a terminal, plain stdin otherwise
+pub fn stdin_reader() -> Box<dyn LineReader> {
+    if io::stdin().is_terminal() && io::stdout().is_terminal() {
+        Box::new(TerminalReader::new())
+    } else {
+        Box::new(PlainReader::new())
+    }
+}
+
+pub struct PlainReader;
+
+impl PlainReader {
+    pub fn new() -> PlainReader {
+        PlainReader
+    }
+}
+
+impl LineReader for PlainReader {
+    fn read_line(&mut self, prompt: &str, _history: &[String]) -> Option<String> {
+        print!("{}", prompt);
+        io::stdout().flush()
+            .expect("Unable to flush stdout");
+
+        let mut buffer = String::new();
+
+        match io::stdin().read_line(&mut buffer) {
+            Ok(0) | Err(_) => return None,
+            Ok(_) => return Some(buffer.trim_end_matches(&['\r', '\n'][..]).to_string()),
+        }
+    }
+}
+
+const CTRL_D: u8 = 4;
+const BACKSPACE: u8 = 8;
+const ESCAPE: u8 = 27;
+const DELETE: u8 = 127;
+
+pub struct TerminalReader;
+
+impl TerminalReader {
+    pub fn new() -> TerminalReader {
+        TerminalReader
+    }
+}
+
+// Switches the terminal between raw and cooked input using stty
+fn set_raw_mode(raw: bool) -> bool {
+    let args: &[&str] = if raw {
+        &["-icanon", "-echo", "min", "1"]
+    } else {
+        &["icanon", "echo"]
+    };
+
+    match Command::new("stty").args(args).stdin(Stdio::inherit()).status() {
+        Ok(status) => return status.success(),
+        Err(_) => return false,
+    }
+}
+
+impl LineReader for TerminalReader {
+    fn read_line(&mut self, prompt: &str, history: &[String]) -> Option<String> {
+        if !set_raw_mode(true) {
+            return PlainReader::new().read_line(prompt, history);
+        }
+
+        let stdin = io::stdin();
+        let stdout = io::stdout();
+
+        let line = edit_line(&mut stdin.lock(), &mut stdout.lock(), prompt, history);
+
+        set_raw_mode(false);
+
+        return line
+    }
+}
+
+struct LineEditor<'a> {
+    prompt: &'a str,
+    history: &'a [String],
+    history_index: usize,
+    pending: Vec<char>,
+    line: Vec<char>,
+    cursor: usize,
+}
+
+impl<'a> LineEditor<'a> {
+    fn new(prompt: &'a str, history: &'a [String]) -> LineEditor<'a> {
+        LineEditor {
+            prompt,
+            history,
+            history_index: history.len(),
+            pending: vec![],
+            line: vec![],
+            cursor: 0,
+        }
+    }
+
+    fn redraw<W: Write>(&self, output: &mut W) -> io::Result<()> {
+        let text: String = self.line.iter().collect();
+
+        write!(output, "\r{}{}\x1b[K", self.prompt, text)?;
+
+        let back = self.line.len() - self.cursor;
+        if back > 0 {
+            write!(output, "\x1b[{}D", back)?;
+        }
+
+        output.flush()
+    }
+
+    fn insert(&mut self, c: char) {
+        self.line.insert(self.cursor, c);
+        self.cursor += 1;
+    }
+
+    fn backspace(&mut self) {
+        if self.cursor > 0 {
+            self.cursor -= 1;
+            self.line.remove(self.cursor);
+        }
+    }
+
+    fn recall(&mut self, index: usize) {
+        // Stash whatever was being typed before moving into history
+        if self.history_index == self.history.len() {
+            self.pending = self.line.clone();
+        }
+
+        self.history_index = index;
+
+        self.line = if index == self.history.len() {
+            self.pending.clone()
+        } else {
+            self.history[index].chars().collect()
+        };
+        self.cursor = self.line.len();
+    }
+
+    fn history_up(&mut self) {
+        if self.history_index > 0 {
+            let index = self.history_index - 1;
+            self.recall(index);
+        }
+    }
+
+    fn history_down(&mut self) {
+        if self.history_index < self.history.len() {
+            let index = self.history_index + 1;
+            self.recall(index);
+        }
+    }
+
+    fn handle_escape<R: Read>(&mut self, input: &mut R) {
+        if read_byte(input) != Some(b'[') {
+            return;
+        }
+
+        match read_byte(input) {
+            Some(b'A') => self.history_up(),
+            Some(b'B') => self.history_down(),
+            Some(b'C') if self.cursor < self.line.len() => self.cursor += 1,
+            Some(b'D') if self.cursor > 0 => self.cursor -= 1,
+            Some(b'H') => self.cursor = 0,
+            Some(b'F') => self.cursor = self.line.len(),
+            _ => (),
+        }
+    }
+}
+
+fn read_byte<R: Read>(input: &mut R) -> Option<u8> {
+    let mut byte = [0; 1];
+
+    match input.read(&mut byte) {
+        Ok(1) => return Some(byte[0]),
+        _ => return None,
+    }
+}
+
+// Reads a UTF-8 character whose first byte has already been consumed
+fn read_utf8<R: Read>(input: &mut R, first: u8) -> Option<char> {
+    let len = match first {
+        0x00..=0x7f => 1,
+        0xc0..=0xdf => 2,
+        0xe0..=0xef => 3,
+        _ => 4,
+    };
+
+    let mut bytes = vec![first];
+    for _ in 1..len {
+        bytes.push(read_byte(input)?);
+    }
+
+    return String::from_utf8(bytes).ok().and_then(|s| s.chars().next())
+}
+
+// Runs the line editor over raw terminal input until enter is pressed
+fn edit_line<R: Read, W: Write>(input: &mut R, output: &mut W, prompt: &str, history: &[String]) -> Option<String> {
+    let mut editor = LineEditor::new(prompt, history);
+
+    editor.redraw(output).ok()?;
+
+    loop {
+        match read_byte(input) {
+            None => return None,
+
+            Some(b'\n') | Some(b'\r') => {
+                writeln!(output).ok()?;
+                return Some(editor.line.iter().collect())
+            },
+
+            Some(CTRL_D) => {
+                if editor.line.is_empty() {
+                    writeln!(output).ok()?;
+                    return None
+                }
+            },
+
+            Some(BACKSPACE) | Some(DELETE) => editor.backspace(),
+
+            Some(ESCAPE) => editor.handle_escape(input),
+
+            Some(byte) => {
+                if let Some(c) = read_utf8(input, byte) {
+                    if !c.is_control() {
+                        editor.insert(c);
+                    }
+                }
+            }
+        }
+
+        editor.redraw(output).ok()?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn edit(keys: &[u8], history: &[String]) -> Option<String> {
+        let mut input = keys;
+        let mut output = vec![];
+
+        return edit_line(&mut input, &mut output, ">>> ", history)
+    }
+
+    #[test]
+    fn test_edit_line_cursor_movement() {
+        // Type "ac", step left, insert "b"
+        let line = edit(b"ac\x1b[Db\n", &[]);
+
+        assert_eq!(line, Some("abc".to_string()));
+    }
+
+    #[test]
+    fn test_edit_line_history_recall() {
+        let history = vec![".history".to_string(), ".program".to_string()];
+
+        assert_eq!(edit(b"\x1b[A\n", &history), Some(".program".to_string()));
+        assert_eq!(edit(b"\x1b[A\x1b[A\n", &history), Some(".history".to_string()));
+        assert_eq!(edit(b"x\x1b[A\x1b[B\n", &history), Some("x".to_string()));
+    }
+
+    #[test]
+    fn test_edit_line_eof() {
+        assert_eq!(edit(b"\x04", &[]), None);
+        assert_eq!(edit(b"ab\x7f\n", &[]), Some("a".to_string()));
+    }
+}
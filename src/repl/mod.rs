@@ -1,177 +1,1246 @@
-use std;
 use std::io;
 use std::io::Write;
 use std::io::Read;
 
+use std::env;
 use std::fs::File;
 use std::path::Path;
+use std::path::PathBuf;
 
 use vm::VM;
 
 use compiler::Scanner;
+use compiler::Span;
 use compiler::token::Token;
 use compiler::parser::Parser;
 use compiler::parser::ParseResult;
+use compiler::parser::Environment;
+use compiler::parser::Expression;
+use compiler::evaluator::Evaluator;
+use compiler::printer;
+use compiler::json;
+use compiler::codegen::Codegen;
+use compiler::optimize;
+
+pub mod line_reader;
+pub mod color;
+
+use self::line_reader::LineReader;
+use self::color::Colorizer;
+
+// Writes a line to the REPL's output sink
+macro_rules! out {
+    ($repl:expr, $($arg:tt)*) => {
+        writeln!($repl.output, $($arg)*).expect("Unable to write output")
+    };
+}
+
+// Input ending in ';' or '}' is a run of statements, anything else a bare expression
+fn is_statement_input(tokens: &[Token]) -> bool {
+    tokens.iter().rev()
+        .find(|t| **t != Token::EOF && **t != Token::Comment)
+        .is_none_or(|t| *t == Token::Semicolon || *t == Token::RightBrace)
+}
+
+// Bytes written as hex pairs like `00 01 f4`
+fn parse_hex_bytes(words: &[&str]) -> Result<Vec<u8>, String> {
+    return words.iter()
+        .map(|word| u8::from_str_radix(word, 16).map_err(|_| format!("Invalid hex byte: {}", word)))
+        .collect()
+}
+
+// Lines opening with a declaration keyword are statements even without a trailing ';'
+fn is_declaration_input(tokens: &[Token]) -> bool {
+    matches!(tokens.first(),
+        Some(&Token::VarDecl) | Some(&Token::ConstDecl) |
+        Some(&Token::FunctionDecl) | Some(&Token::Print))
+}
+
+// Shows the error message above the source line it refers to, with carets
+// under the span, like
+//
+//     Parse error: Undefined variable 'y'
+//     x = y + 1;
+//         ^
+pub fn render_error(source: &str, span: Span, message: &str) -> String {
+    let line = source.lines().nth(span.line).unwrap_or("");
+
+    // Tabs are copied so the carets line up however wide the terminal draws them
+    let indent: String = line.chars()
+        .take(span.col)
+        .map(|c| if c == '\t' { '\t' } else { ' ' })
+        .collect();
+
+    return format!("{}\n{}\n{}{}", message, line, indent, "^".repeat(span.len.max(1)))
+}
+
+// File in the user's home directory that history is persisted to
+const HISTORY_FILE: &str = ".iv_history";
+
+// Maximum number of commands kept in the history file
+const DEFAULT_HISTORY_LIMIT: usize = 1000;
 
 pub struct REPL {
 
     command_buffer: Vec<String>,
 
+    history_path: Option<PathBuf>,
+    history_limit: usize,
+
     vm: VM,
+    // Lowers each line onto the end of the VM's program, keeping variables in their registers
+    codegen: Codegen,
+
+    // Definitions and values carried over between lines
+    env: Environment,
+    evaluator: Evaluator,
+
+    // Line ends terminate statements, toggled with `.semicolons off`
+    implicit_semicolons: bool,
+
+    // Each line's bytecode extends the program rather than replacing it, toggled with `.append off`
+    append_program: bool,
+
+    color: Colorizer,
+
+    output: Box<dyn Write>,
 }
 
 impl REPL {
     pub fn new() -> REPL {
-        REPL {
+        let history_path = env::var_os("HOME")
+            .map(|home| Path::new(&home).join(HISTORY_FILE));
+
+        let mut repl = REPL::with_history_file(history_path);
+        repl.color = Colorizer::from_env();
+
+        return repl
+    }
+
+    // Passing None disables history persistence entirely
+    pub fn with_history_file(history_path: Option<PathBuf>) -> REPL {
+        let mut repl = REPL {
             vm: VM::new(),
-            command_buffer: vec![]
+            codegen: Codegen::new(),
+            env: Environment::new(),
+            evaluator: Evaluator::new(),
+            implicit_semicolons: false,
+            append_program: true,
+            color: Colorizer::new(false),
+            output: Box::new(io::stdout()),
+            command_buffer: vec![],
+            history_path,
+            history_limit: DEFAULT_HISTORY_LIMIT,
+        };
+
+        repl.load_history();
+
+        return repl
+    }
+
+    pub fn set_output(&mut self, output: Box<dyn Write>) {
+        self.output = output;
+    }
+
+    pub fn set_history_limit(&mut self, limit: usize) {
+        self.history_limit = limit;
+    }
+
+    fn load_history(&mut self) {
+        let path = match self.history_path {
+            Some(ref path) => path,
+            None => return,
+        };
+
+        // A missing history file just means this is the first session
+        let mut contents = String::new();
+        match File::open(path) {
+            Ok(mut f) => {
+                if f.read_to_string(&mut contents).is_err() {
+                    return;
+                }
+            },
+            Err(_) => return,
+        }
+
+        let lines: Vec<&str> = contents.lines().filter(|l| !l.is_empty()).collect();
+        let start = lines.len().saturating_sub(self.history_limit);
+
+        for line in &lines[start..] {
+            self.command_buffer.push(line.to_string());
         }
     }
 
-    pub fn run(&mut self) {
-        println!("Welcome to the i_v REPL loop");
+    // Only the file is capped, the session keeps every line for .history and .save_source
+    fn save_history(&mut self) {
+        let path = match self.history_path {
+            Some(ref path) => path,
+            None => return,
+        };
+
+        let start = self.command_buffer.len().saturating_sub(self.history_limit);
+
+        let mut contents = String::new();
+        for command in &self.command_buffer[start..] {
+            contents.push_str(command);
+            contents.push('\n');
+        }
+
+        let result = File::create(path)
+            .and_then(|mut f| f.write_all(contents.as_bytes()).and_then(|_| f.flush()));
+
+        if result.is_err() {
+            out!(self, "Unable to save history to {}", path.display());
+        }
+    }
+
+    // Writes the source entered so far, without REPL commands, so it can be `.load`ed later
+    fn save_source(&self, path: &Path) -> io::Result<usize> {
+        let lines: Vec<&String> = self.command_buffer.iter()
+            .filter(|c| !c.starts_with('.'))
+            .collect();
+
+        let mut contents = String::new();
+        for line in &lines {
+            contents.push_str(line);
+            contents.push('\n');
+        }
 
-        let stdin = io::stdin();
+        File::create(path)
+            .and_then(|mut f| f.write_all(contents.as_bytes()).and_then(|_| f.flush()))?;
 
-        loop {
+        return Ok(lines.len())
+    }
 
-            let mut buffer = String::new();
+    pub fn run(&mut self) {
+        let mut reader = line_reader::stdin_reader();
 
-            print!(">>> ");
-            io::stdout().flush()
-                .expect("Unable to flush stdout");
+        self.run_with(&mut *reader);
+    }
 
-            stdin.read_line(&mut buffer)
-                .expect("Unable to read input");
+    // Runs the loop until the reader runs dry or `.quit` is entered
+    pub fn run_with(&mut self, reader: &mut dyn LineReader) {
+        out!(self, "Welcome to the i_v REPL loop");
 
+        while let Some(buffer) = reader.read_line(">>> ", &self.command_buffer) {
             let buffer = buffer.trim();
 
+            // Blank lines just prompt again and aren't worth keeping in the history
+            if buffer.is_empty() {
+                continue;
+            }
+
             self.command_buffer.push(buffer.to_string());
+            self.save_history();
 
-            match buffer {
-                ".quit" => {
-                    println!("Exiting...");
-                    std::process::exit(0);
+            if !self.execute(buffer, reader) {
+                break;
+            }
+        }
+    }
+
+    // Compiles the whole input to bytecode and runs it once, without prompting.
+    // Statements codegen can't lower yet, like print, fail the whole batch.
+    pub fn run_batch(&mut self, input: &mut dyn Read) -> Result<(), String> {
+        let mut source = String::new();
+        if let Err(e) = input.read_to_string(&mut source) {
+            return Err(format!("Unable to read input: {}", e))
+        }
+
+        let tokens = self.tokens(&source);
+
+        let mut parser = Parser::with_environment(tokens, self.env.clone());
+        let mut codegen = Codegen::new();
+
+        while let Some(res) = parser.parse_next() {
+            match res {
+                ParseResult::Success(expr) => {
+                    codegen.generate(&optimize::optimize(expr))?;
                 },
+                ParseResult::Failed(f) => return Err(format!("Parse error: {}", f)),
+            }
+        }
+
+        self.env = parser.environment().clone();
+
+        self.vm.load_bytes(codegen.into_bytes());
+
+        match self.vm.run() {
+            Ok(_) => return Ok(()),
+            Err(e) => return Err(format!("Runtime error: {}", e))
+        }
+    }
+
+    // Executes a single line of input, returning false when the REPL should exit
+    fn execute(&mut self, buffer: &str, reader: &mut dyn LineReader) -> bool {
+        let mut words = buffer.split_whitespace();
+        let command = words.next().unwrap_or("");
+        let args: Vec<&str> = words.collect();
+
+        match command {
+            // Nothing to scan or parse
+            "" => (),
+
+            ".quit" => {
+                out!(self, "Exiting...");
+                return false
+            },
+            ".history" => {
+                for command in &self.command_buffer {
+                    out!(self, "{}", command);
+                }
+            },
+
+            ".clear_registers" => {
+
+                out!(self, "Clearing registers...");
+
+                for i in 0..self.vm.registers.len() {
+                    self.vm.registers[i] = 0;
+                }
+            },
+
+            ".set_register" => {
+                match self.set_register(&args) {
+                    Ok((index, value)) => out!(self, "Register {} set to {}", index, value),
+                    Err(e) => out!(self, "{}", e),
+                }
+            },
+
+            ".tokens" => {
+                let source = buffer[command.len()..].trim();
 
-                ".history" => {
-                    for command in &self.command_buffer {
-                        println!("{}", command);
+                for tok in self.tokens(source) {
+                    out!(self, "{}", self.color.token(&tok));
+                }
+            },
+
+            ".ast" => {
+                let source = buffer[command.len()..].trim();
+
+                self.print_ast(source);
+            },
+
+            ".ast-json" => {
+                let source = buffer[command.len()..].trim();
+
+                self.print_ast_json(source);
+            },
+
+            ".color" => {
+                match args.first() {
+                    Some(&"on") => self.color.set_enabled(true),
+                    Some(&"off") => self.color.set_enabled(false),
+                    _ => {
+                        out!(self, "Usage: .color <on|off>");
+                        return true
                     }
-                },
+                }
+
+                out!(self, "Color {}", if self.color.is_enabled() { "on" } else { "off" });
+            },
+
+            ".semicolons" => {
+                match args.first() {
+                    Some(&"on") => self.implicit_semicolons = false,
+                    Some(&"off") => self.implicit_semicolons = true,
+                    _ => {
+                        out!(self, "Usage: .semicolons <on|off>");
+                        return true
+                    }
+                }
+
+                out!(self, "Semicolons {}", if self.implicit_semicolons { "optional" } else { "required" });
+            },
+
+            ".append" => {
+                match args.first() {
+                    Some(&"on") => self.append_program = true,
+                    Some(&"off") => self.append_program = false,
+                    _ => {
+                        out!(self, "Usage: .append <on|off>");
+                        return true
+                    }
+                }
+
+                out!(self, "Append {}", if self.append_program { "on" } else { "off" });
+            },
+
+            ".bytes" => {
+                match parse_hex_bytes(&args) {
+                    Ok(ref bytes) if bytes.is_empty() => out!(self, "Usage: .bytes <hex byte>..."),
+                    Ok(bytes) => {
+                        out!(self, "Loaded {} bytes", bytes.len());
+                        self.load_and_run(bytes);
+                    },
+                    Err(e) => self.error(e),
+                }
+            },
+
+            ".list_registers" => {
+
+                out!(self, "Listing registers...");
+
+                out!(self, "{:#?}", self.vm.registers);
+            },
+
+            ".cleanup" => {
 
-                ".clear_registers" => {
+                out!(self, "Clearing program...");
 
-                    println!("Clearing registers...");
+                self.vm.load_bytes(vec![]);
+                self.codegen = Codegen::new();
 
-                    for i in 0..self.vm.registers.len() {
-                        self.vm.registers[i] = 0;
+                for i in 0..self.vm.registers.len() {
+                    self.vm.registers[i] = 0;
+                }
+            },
+
+            ".reset" => {
+
+                out!(self, "Resetting virtual machine...");
+
+                self.vm = VM::new();
+                self.codegen = Codegen::new();
+            },
+
+            ".list_vars" => {
+
+                out!(self, "Listing variables...");
+
+                let mut names: Vec<&String> = self.env.vars.keys().collect();
+                names.sort();
+
+                for name in names {
+                    match self.evaluator.get(name) {
+                        Some(value) => out!(self, "{} = {}", name, value),
+                        None => out!(self, "{}", name),
                     }
-                },
+                }
+            },
 
-                ".list_registers" => {
+            ".env" => {
 
-                    println!("Listing registers...");
+                out!(self, "Listing environment...");
 
-                    println!("{:#?}", self.vm.registers);
-                },
+                let vars = self.env.variables();
+                let width = vars.iter().map(|v| v.0.len()).max().unwrap_or(0);
 
-                ".cleanup" => {
+                for (name, entry) in vars {
+                    let nullable = if entry.nullable { "?" } else { "" };
+                    out!(self, "{:<width$}  {:?}{}", name, entry.value.return_type, nullable, width = width);
+                }
+            },
+
+            ".profile" => {
+
+                out!(self, "Listing opcode execution counts...");
+
+                for &(opcode, count) in self.vm.profile() {
+                    out!(self, "{:?}: {}", opcode, count);
+                }
+            },
+
+            ".program" => {
 
-                    println!("Clearing program...");
+                out!(self, "Listing current instructions in program...");
 
-                    self.vm.program.truncate(0);
+                for instruction in &self.vm.program {
+                    out!(self, "{}", instruction);
+                }
+            },
+
+            ".help" => {
+                out!(self, "Current commands: ");
+                out!(self, "> .help");
+                out!(self, "> .history");
+                out!(self, "> .cleanup");
+                out!(self, "> .reset");
+                out!(self, "> .clear_registers");
+                out!(self, "> .list_registers");
+                out!(self, "> .set_register <index> <value>");
+                out!(self, "> .program");
+                out!(self, "> .profile");
+                out!(self, "> .list_vars");
+                out!(self, "> .env");
+                out!(self, "> .tokens <source>");
+                out!(self, "> .ast <source>");
+                out!(self, "> .ast-json <source>");
+                out!(self, "> .semicolons <on|off>");
+                out!(self, "> .color <on|off>");
+                out!(self, "> .append <on|off>");
+                out!(self, "> .bytes <hex byte>...");
+                out!(self, "> .edit (finish with .end)");
+                out!(self, "> .save_source <path>");
+                out!(self, "> .quit");
+            },
 
-                    for i in 0..self.vm.registers.len() {
-                        self.vm.registers[i] = 0;
+            ".save_source" => {
+                let path = match args.first() {
+                    Some(path) => Path::new(path),
+                    None => {
+                        out!(self, "Usage: .save_source <path>");
+                        return true
                     }
-                },
+                };
 
-                ".program" => {
+                match self.save_source(path) {
+                    Ok(count) => out!(self, "Saved {} lines to {}", count, path.display()),
+                    Err(e) => out!(self, "Unable to save source to {}: {}", path.display(), e),
+                }
+            },
+
+            ".edit" => {
+                let mut source = String::new();
 
-                    println!("Listing current instructions in program...");
+                // Lines are collected as they are so definitions can span several
+                loop {
+                    let line = match reader.read_line("... ", &self.command_buffer) {
+                        Some(line) => line,
+                        None => return false,
+                    };
 
-                    for instruction in &self.vm.program {
-                        println!("{}", instruction);
+                    if line.trim() == ".end" {
+                        break;
                     }
-                },
 
-                ".help" => {
-                    println!("Current commands: ");
-                    println!("> .help");
-                    println!("> .history");
-                    println!("> .cleanup");
-                    println!("> .clear_registers");
-                    println!("> .list_registers");
-                    println!("> .program");
-                    println!("> .quit");
-                },
+                    if !line.trim().is_empty() {
+                        self.command_buffer.push(line.trim_end().to_string());
+                    }
+                    source.push_str(&line);
+                    source.push('\n');
+                }
 
-                ".load" => {
-                    println!("Please enter the file you wish to load");
-                    print!("> ");
-                    io::stdout().flush().expect("Unable to flush output");
+                self.save_history();
+                self.eval_source(&source);
+            },
 
-                    let mut tmp = String::new();
+            ".load" => {
+                out!(self, "Please enter the file you wish to load");
 
-                    stdin.read_line(&mut tmp).expect("Unable to read input");
-                    let tmp = tmp.trim();
+                let tmp = match reader.read_line("> ", &[]) {
+                    Some(line) => line,
+                    None => return false,
+                };
+                let tmp = tmp.trim();
 
-                    let file_name = Path::new(&tmp);
-                    let mut f = File::open(file_name).expect("Unable to open file");
+                let file_name = Path::new(&tmp);
+                let mut f = File::open(file_name).expect("Unable to open file");
 
-                    let mut contents = String::new();
-                    f.read_to_string(&mut contents).expect("Unable to read file");
+                let mut contents = String::new();
+                f.read_to_string(&mut contents).expect("Unable to read file");
 
-                    let mut scanner = Scanner::new(&contents);
+                let tokens = self.tokens(&contents);
 
-                    let mut tokens = vec!();
+                for tok in &tokens {
+                    out!(self, "{:?}", tok);
+                }
 
-                    loop {
-                        let tok = scanner.next_token();
-                        tokens.push(tok.clone());
+                let mut parser = Parser::with_environment(tokens, self.env.clone());
+                let (program, errors) = parser.parse();
 
-                        println!("{:?}", tok);
+                for e in &errors {
+                    self.error(format!("Parse error: {}", e));
+                }
 
-                        if tok == Token::EOF {
-                            break;
-                        }
+                for stm in &program.statements {
+                    out!(self, "statement... {:?}", stm);
+
+                    if let Err(e) = self.evaluator.execute(&stm.expr) {
+                        self.error(format!("Runtime error: {}", e));
+                        break;
                     }
+                }
+
+                self.env = program.env;
+            },
+
+            _ => self.eval_source(buffer)
+        }
+
+        return true
+    }
+
+    fn error(&mut self, message: String) {
+        out!(self, "{}", self.color.error(&message));
+    }
+
+    // Scans source without parsing it
+    fn tokens(&self, source: &str) -> Vec<Token> {
+        return self.scan(source, self.implicit_semicolons).0
+    }
+
+    fn scan(&self, source: &str, implicit_semicolons: bool) -> (Vec<Token>, Vec<Span>) {
+        let mut scanner = Scanner::new(source);
+        scanner.set_implicit_semicolons(implicit_semicolons);
+
+        let tokens = scanner.tokenize();
+
+        return (tokens, scanner.spans().to_vec())
+    }
+
+    // Scans a line of input and decides whether it's statements or a bare expression.
+    // Implicit semicolons are only inserted into statements, so an expression still
+    // prints its value.
+    fn scan_input(&self, source: &str) -> (Vec<Token>, Vec<Span>, bool) {
+        let (tokens, spans) = self.scan(source, false);
 
-                    let mut parser = Parser::new(tokens);
-                    let program = parser.parse();
+        let is_statement = is_statement_input(&tokens) ||
+            (self.implicit_semicolons && is_declaration_input(&tokens));
 
-                    for expr in program.statements {
-                        println!("statement... {:?}", expr);
+        if is_statement {
+            let (tokens, spans) = self.scan(source, self.implicit_semicolons);
+            return (tokens, spans, true)
+        }
+
+        return (tokens, spans, false)
+    }
+
+    // Parses source against the current environment without executing it
+    fn print_ast(&mut self, source: &str) {
+        let (tokens, _, is_statement) = self.scan_input(source);
+
+        let mut parser = Parser::with_environment(tokens, self.env.clone());
+
+        if is_statement {
+            while let Some(res) = parser.parse_next() {
+                match res {
+                    ParseResult::Success(expr) => out!(self, "{}", printer::pretty_print(&expr).trim_end()),
+                    ParseResult::Failed(f) => {
+                        self.error(format!("Parse error: {}", f));
+                        break;
                     }
-                },
+                }
+            }
+        } else {
+            match parser.parse_bare_expression() {
+                ParseResult::Success(expr) => out!(self, "{}", printer::pretty_print(&expr).trim_end()),
+                ParseResult::Failed(f) => self.error(format!("Parse error: {}", f)),
+            }
+        }
+    }
+
+    // Like print_ast, but as JSON for tooling. Statements are rendered as a whole
+    // program, a bare expression as a single node.
+    fn print_ast_json(&mut self, source: &str) {
+        let (tokens, _, is_statement) = self.scan_input(source);
+
+        let mut parser = Parser::with_environment(tokens, self.env.clone());
 
-                _ => {
-                    let mut scanner = Scanner::new(&buffer);
+        if is_statement {
+            let (program, errors) = parser.parse();
 
-                    let mut tokens = vec!();
+            match errors.first() {
+                Some(f) => self.error(format!("Parse error: {}", f)),
+                None => out!(self, "{}", program.to_json()),
+            }
+        } else {
+            match parser.parse_bare_expression() {
+                ParseResult::Success(expr) => out!(self, "{}", json::expression_to_json(&expr)),
+                ParseResult::Failed(f) => self.error(format!("Parse error: {}", f)),
+            }
+        }
+    }
 
-                    loop {
-                        let tok = scanner.next_token();
-                        tokens.push(tok.clone());
+    // Evaluates and prints a bare expression, or executes statements
+    fn eval_source(&mut self, source: &str) {
+        let (tokens, spans, is_statement) = self.scan_input(source);
 
-                        println!("{:?}", tok);
+        let mut parser = Parser::with_environment(tokens, self.env.clone());
 
-                        if tok == Token::EOF {
+        if is_statement {
+            while let Some(res) = parser.parse_next() {
+                match res {
+                    ParseResult::Success(expr) => {
+                        if let Err(e) = self.evaluator.execute(&expr) {
+                            self.error(format!("Runtime error: {}", e));
                             break;
                         }
+
+                        self.compile(&expr);
+                    },
+                    ParseResult::Failed(f) => {
+                        self.parse_error(source, &spans, parser.last_token_index(), f);
+                        break;
+                    }
+                }
+            }
+        } else {
+            match parser.parse_bare_expression() {
+                ParseResult::Success(expr) => {
+                    match self.evaluator.evaluate(&expr) {
+                        Ok(value) => {
+                            self.compile(&expr);
+                            out!(self, "{}", value);
+                        },
+                        Err(e) => self.error(format!("Runtime error: {}", e)),
                     }
+                },
+                ParseResult::Failed(f) => self.parse_error(source, &spans, parser.last_token_index(), f),
+            }
+        }
 
-                    tokens.reverse();
+        self.env = parser.environment().clone();
+    }
 
-                    let mut parser = Parser::new(tokens);
-                    let program = parser.parse();
+    // Reports a parse error beneath the input it came from, pointing at the token the parser gave up on
+    fn parse_error(&mut self, source: &str, spans: &[Span], index: Option<usize>, message: String) {
+        let message = self.color.error(&format!("Parse error: {}", message));
 
-                    for expr in program.statements {
-                        println!("statement.. {:?}", expr);
-                    }
-                }
+        match index.and_then(|i| spans.get(i)) {
+            Some(&span) => out!(self, "{}", render_error(source, span, &message)),
+            None => out!(self, "{}", message),
+        }
+    }
+
+    // Adds the bytecode for a line the evaluator has already run successfully, so
+    // faults like division by zero are caught there first, then runs it from where the
+    // last line stopped. Codegen doesn't cover the whole language yet, lines it can't
+    // lower only run in the evaluator.
+    fn compile(&mut self, expr: &Expression) {
+        if self.codegen.generate(expr).is_err() {
+            self.codegen.take_bytes();
+            return;
+        }
+
+        let bytes = self.codegen.take_bytes();
+        if bytes.is_empty() {
+            return;
+        }
+
+        out!(self, "Emitted {} bytes", bytes.len());
+        self.load_and_run(bytes);
+    }
+
+    // Adds the bytes to the program, or replaces it with them when appending is
+    // off, and runs from wherever the VM stopped
+    fn load_and_run(&mut self, bytes: Vec<u8>) {
+        if self.append_program {
+            self.vm.program.extend(bytes);
+        } else {
+            self.vm.load_bytes(bytes);
+        }
+
+        if let Err(e) = self.vm.run() {
+            self.error(format!("Runtime error: {}", e));
+            // Don't resume part way through the failed line
+            self.vm.pc = self.vm.program.len();
+        }
+    }
+
+    fn set_register(&mut self, args: &[&str]) -> Result<(usize, i32), String> {
+        if args.len() != 2 {
+            return Err("Usage: .set_register <index> <value>".to_string())
+        }
+
+        let index: usize = match args[0].parse() {
+            Ok(index) => index,
+            Err(_) => return Err(format!("Invalid register index: {}", args[0])),
+        };
+
+        if index >= self.vm.registers.len() {
+            return Err(format!("Register index out of range: {} (max {})", index, self.vm.registers.len() - 1))
+        }
+
+        let value: i32 = match args[1].parse() {
+            Ok(value) => value,
+            Err(_) => return Err(format!("Invalid register value: {}", args[1])),
+        };
+
+        self.vm.registers[index] = value;
+
+        return Ok((index, value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+    use std::rc::Rc;
+    use std::cell::RefCell;
+
+    #[derive(Clone)]
+    struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+    impl SharedBuffer {
+        fn new() -> SharedBuffer {
+            SharedBuffer(Rc::new(RefCell::new(vec![])))
+        }
+
+        fn contents(&self) -> String {
+            String::from_utf8(self.0.borrow().clone()).unwrap()
+        }
+    }
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn get_temp_path(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("iv_test_{}_{}", std::process::id(), name))
+    }
+
+    struct MockReader {
+        lines: Vec<String>,
+        history_lens: Vec<usize>,
+        prompts: Vec<String>,
+    }
+
+    impl MockReader {
+        fn new(lines: &[&str]) -> MockReader {
+            let mut lines: Vec<String> = lines.iter().map(|l| l.to_string()).collect();
+            lines.reverse();
+
+            MockReader {
+                lines,
+                history_lens: vec![],
+                prompts: vec![],
             }
         }
     }
+
+    impl LineReader for MockReader {
+        fn read_line(&mut self, prompt: &str, history: &[String]) -> Option<String> {
+            self.history_lens.push(history.len());
+            self.prompts.push(prompt.to_string());
+            self.lines.pop()
+        }
+    }
+
+    fn get_test_repl() -> REPL {
+        let mut repl = REPL::with_history_file(None);
+        repl.set_output(Box::new(io::sink()));
+
+        return repl
+    }
+
+    fn get_captured_repl() -> (REPL, SharedBuffer) {
+        let buffer = SharedBuffer::new();
+
+        let mut repl = REPL::with_history_file(None);
+        repl.set_output(Box::new(buffer.clone()));
+
+        return (repl, buffer)
+    }
+
+    #[test]
+    fn test_run_with_scripted_reader() {
+        let mut repl = get_test_repl();
+        let mut reader = MockReader::new(&[".clear_registers", ".history"]);
+
+        repl.run_with(&mut reader);
+
+        assert_eq!(repl.command_buffer, vec![".clear_registers", ".history"]);
+        // Each prompt sees the history accumulated so far
+        assert_eq!(reader.history_lens, vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn test_quit_stops_reading() {
+        let mut repl = get_test_repl();
+        let mut reader = MockReader::new(&[".quit", ".history"]);
+
+        repl.run_with(&mut reader);
+
+        assert_eq!(repl.command_buffer, vec![".quit"]);
+    }
+
+    #[test]
+    fn test_empty_input_reprompts() {
+        let (mut repl, buffer) = get_captured_repl();
+        let mut reader = MockReader::new(&["", "   ", "\t"]);
+
+        repl.run_with(&mut reader);
+
+        assert!(repl.command_buffer.is_empty());
+        assert_eq!(reader.history_lens, vec![0, 0, 0, 0]);
+        assert_eq!(buffer.contents(), "Welcome to the i_v REPL loop\n");
+
+        let (mut repl, buffer) = get_captured_repl();
+        assert!(execute(&mut repl, ""));
+        assert_eq!(buffer.contents(), "");
+    }
+
+    #[test]
+    fn test_edit_multiline_input() {
+        let (mut repl, buffer) = get_captured_repl();
+        let mut reader = MockReader::new(&[
+            ".edit",
+            "fn double : int (int: n) {",
+            "    return n * 2;",
+            "}",
+            ".end",
+        ]);
+
+        repl.run_with(&mut reader);
+
+        assert_eq!(reader.prompts, vec![">>> ", "... ", "... ", "... ", "... ", ">>> "]);
+        assert!(repl.env.get_function("double").is_some());
+        assert_eq!(buffer.contents(), "Welcome to the i_v REPL loop\n");
+    }
+
+    fn execute(repl: &mut REPL, line: &str) -> bool {
+        repl.execute(line, &mut MockReader::new(&[]))
+    }
+
+    #[test]
+    fn test_reset_reinitialises_vm() {
+        let mut repl = get_test_repl();
+
+        // LOAD $0 #16, ALOC $0, NOP
+        repl.vm.program = vec![0, 0, 0, 16, 18, 0, 0, 0, 17, 0, 0, 0];
+        repl.vm.run().unwrap();
+
+        assert_eq!(repl.vm.pc, 12);
+        assert_eq!(repl.vm.heap().len(), 16);
+
+        execute(&mut repl, ".reset");
+
+        assert_eq!(repl.vm.pc, 0);
+        assert!(repl.vm.heap().is_empty());
+        assert!(repl.vm.program.is_empty());
+        assert_eq!(repl.vm.registers[0], 0);
+    }
+
+    #[test]
+    fn test_run_batch() {
+        let mut repl = get_test_repl();
+        let mut input = "var x = 4;\nvar y = x * 3;\n\nx = y - x;\n".as_bytes();
+
+        repl.run_batch(&mut input).unwrap();
+
+        assert_eq!(repl.vm.registers[0], 8);
+        assert_eq!(repl.vm.registers[1], 12);
+    }
+
+    #[test]
+    fn test_semicolons_off() {
+        let (mut repl, buffer) = get_captured_repl();
+        let mut reader = MockReader::new(&[".semicolons off", "var x = 5", "x = x + 1", "x * 2"]);
+
+        repl.run_with(&mut reader);
+
+        let output = buffer.contents();
+
+        assert_eq!(output.lines().last(), Some("12"));
+        assert!(!output.contains("error"));
+    }
+
+    #[test]
+    fn test_run_batch_without_semicolons() {
+        let mut repl = get_test_repl();
+        let mut input = "var x = 4\nvar y = x * 3\n".as_bytes();
+
+        execute(&mut repl, ".semicolons off");
+        repl.run_batch(&mut input).unwrap();
+
+        assert_eq!(repl.vm.registers[1], 12);
+    }
+
+    #[test]
+    fn test_run_batch_parse_error() {
+        let mut repl = get_test_repl();
+        let mut input = "var x = ;".as_bytes();
+
+        assert!(repl.run_batch(&mut input).unwrap_err().starts_with("Parse error"));
+    }
+
+    #[test]
+    fn test_run_batch_division_by_zero() {
+        let mut repl = get_test_repl();
+        let mut input = "var x = 0; var y = 1 / x;".as_bytes();
+
+        assert_eq!(repl.run_batch(&mut input), Err("Runtime error: Division by zero at address 12".to_string()));
+    }
+
+    #[test]
+    fn test_run_batch_unsupported_statement() {
+        let mut repl = get_test_repl();
+        let mut input = "var x = 2; print \"hi\"; x = x + 1;".as_bytes();
+
+        assert_eq!(repl.run_batch(&mut input), Err("Codegen doesn't support PrintExpression(\"hi\") yet".to_string()));
+
+        // Nothing ran, not even the statements before the one that failed
+        assert_eq!(repl.vm.registers[0], 0);
+    }
+
+    #[test]
+    fn test_statement_then_expression() {
+        let (mut repl, buffer) = get_captured_repl();
+        let mut reader = MockReader::new(&["var x = 5;", "x + 1"]);
+
+        repl.run_with(&mut reader);
+
+        let output = buffer.contents();
+        let lines: Vec<&str> = output.lines().collect();
+
+        assert_eq!(lines.last(), Some(&"6"));
+        assert!(!output.contains("error"));
+    }
+
+    #[test]
+    fn test_variables_persist_across_lines() {
+        let (mut repl, buffer) = get_captured_repl();
+
+        execute(&mut repl, "var a : int = 3;");
+        execute(&mut repl, "var b = a * 2;");
+        execute(&mut repl, ".list_vars");
+
+        assert_eq!(buffer.contents(), "Emitted 8 bytes\nEmitted 12 bytes\nListing variables...\na = 3\nb = 6\n");
+    }
+
+    #[test]
+    fn test_env_lists_types() {
+        let (mut repl, buffer) = get_captured_repl();
+
+        execute(&mut repl, "var count : int? = 3;");
+        execute(&mut repl, "var s = \"hi\";");
+        execute(&mut repl, ".env");
+
+        assert!(buffer.contents().ends_with("Listing environment...\ncount  ReturnInteger?\ns      ReturnString\n"), "{}", buffer.contents());
+    }
+
+    #[test]
+    fn test_lines_compile_onto_program() {
+        let (mut repl, buffer) = get_captured_repl();
+
+        execute(&mut repl, "1 + 2");
+
+        assert_eq!(buffer.contents(), "Emitted 12 bytes\n3\n");
+        assert_eq!(repl.vm.program.len(), 12);
+        assert_eq!(repl.vm.registers[2], 3);
+
+        execute(&mut repl, "var x = 4;");
+        execute(&mut repl, "x * 2");
+
+        // x lives in $0, so the product lands in $2
+        assert_eq!(repl.vm.program.len(), 28);
+        assert_eq!(repl.vm.registers[0], 4);
+        assert_eq!(repl.vm.registers[2], 8);
+    }
+
+    #[test]
+    fn test_append_toggle() {
+        let (mut repl, buffer) = get_captured_repl();
+
+        execute(&mut repl, "var x = 4;");
+        execute(&mut repl, "x + 1");
+        assert_eq!(repl.vm.program.len(), 16);
+
+        execute(&mut repl, ".append off");
+        execute(&mut repl, "x + 2");
+        assert_eq!(repl.vm.program.len(), 8);
+        assert_eq!(repl.vm.registers[2], 6);
+
+        execute(&mut repl, ".append on");
+        execute(&mut repl, "x + 3");
+        assert_eq!(repl.vm.program.len(), 16);
+        assert_eq!(repl.vm.registers[2], 7);
+
+        assert!(buffer.contents().contains("Append off\n"));
+        assert!(buffer.contents().contains("Append on\n"));
+    }
+
+    #[test]
+    fn test_bytes_command() {
+        let (mut repl, buffer) = get_captured_repl();
+
+        execute(&mut repl, ".bytes 00 00 01 f4");
+
+        assert_eq!(repl.vm.program, vec![0, 0, 1, 244]);
+        assert_eq!(repl.vm.registers[0], 500);
+        assert_eq!(buffer.contents(), "Loaded 4 bytes\n");
+
+        execute(&mut repl, ".bytes 00 01 zz");
+        assert!(buffer.contents().ends_with("Invalid hex byte: zz\n"));
+        assert_eq!(repl.vm.program.len(), 4);
+
+        execute(&mut repl, ".append off");
+        execute(&mut repl, ".bytes 00 01 00 07");
+        assert_eq!(repl.vm.program, vec![0, 1, 0, 7]);
+        assert_eq!(repl.vm.registers[1], 7);
+    }
+
+    #[test]
+    fn test_parse_error_reported() {
+        let (mut repl, buffer) = get_captured_repl();
+
+        execute(&mut repl, "y + 1");
+
+        assert_eq!(buffer.contents(), "Parse error: Undefined variable 'y'\ny + 1\n^\n");
+    }
+
+    #[test]
+    fn test_parse_error_underlined() {
+        let (mut repl, buffer) = get_captured_repl();
+
+        execute(&mut repl, "var count = 1 + ;");
+        execute(&mut repl, "var total = missing;");
+
+        assert_eq!(buffer.contents(), "\
+Parse error: Failed addition RHS
+var count = 1 + ;
+                ^
+Parse error: Undefined variable 'missing'
+var total = missing;
+            ^^^^^^^
+");
+    }
+
+    #[test]
+    fn test_render_error() {
+        let span = Span { line: 1, col: 2, len: 3 };
+
+        assert_eq!(render_error("first\n\tx foo;", span, "oops"), "oops\n\tx foo;\n\t ^^^");
+    }
+
+    #[test]
+    fn test_tokens_command() {
+        let (mut repl, buffer) = get_captured_repl();
+
+        execute(&mut repl, ".tokens 1 + 2");
+
+        assert_eq!(buffer.contents(), "1\n+\n2\n<eof>\n");
+    }
+
+    #[test]
+    fn test_ast_command() {
+        let (mut repl, buffer) = get_captured_repl();
+
+        execute(&mut repl, ".ast 1 + 2 * 3");
+
+        assert_eq!(buffer.contents(), "\
+Binary Add : ReturnInteger
+  Literal IntegerLiteral(1) : ReturnInteger
+  Binary Multiply : ReturnInteger
+    Literal IntegerLiteral(2) : ReturnInteger
+    Literal IntegerLiteral(3) : ReturnInteger
+");
+    }
+
+    #[test]
+    fn test_ast_command_parse_failure() {
+        let (mut repl, buffer) = get_captured_repl();
+
+        execute(&mut repl, ".ast missing * 3");
+
+        assert_eq!(buffer.contents(), "Parse error: Undefined variable 'missing'\n");
+    }
+
+    #[test]
+    fn test_profile_command() {
+        let (mut repl, buffer) = get_captured_repl();
+
+        repl.vm.program = vec![1, 0, 1, 2, 1, 0, 1, 2, 17, 0, 0, 0];
+        repl.vm.run().unwrap();
+
+        execute(&mut repl, ".profile");
+
+        assert_eq!(buffer.contents(), "Listing opcode execution counts...\nADD: 2\nNOP: 1\n");
+    }
+
+    #[test]
+    fn test_set_register() {
+        let mut repl = get_test_repl();
+
+        execute(&mut repl, ".set_register 3 -42");
+
+        assert_eq!(repl.vm.registers[3], -42);
+    }
+
+    #[test]
+    fn test_set_register_invalid_input() {
+        let mut repl = get_test_repl();
+
+        assert!(repl.set_register(&["32", "1"]).is_err());
+        assert!(repl.set_register(&["x", "1"]).is_err());
+        assert!(repl.set_register(&["0", "one"]).is_err());
+        assert!(repl.set_register(&["0"]).is_err());
+        assert_eq!(repl.set_register(&["31", "7"]), Ok((31, 7)));
+    }
+
+    #[test]
+    fn test_history_loaded_from_file() {
+        let path = get_temp_path("history_load");
+        fs::write(&path, "var a : int = 1;\n.history\n").unwrap();
+
+        let repl = REPL::with_history_file(Some(path.clone()));
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(repl.command_buffer, vec!["var a : int = 1;", ".history"]);
+    }
+
+    #[test]
+    fn test_history_capped_to_limit() {
+        let path = get_temp_path("history_limit");
+
+        let mut repl = REPL::with_history_file(Some(path.clone()));
+        repl.set_history_limit(2);
+
+        for command in &["a", "b", "c"] {
+            repl.command_buffer.push(command.to_string());
+            repl.save_history();
+        }
+
+        let contents = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(contents, "b\nc\n");
+        assert_eq!(repl.command_buffer, vec!["a", "b", "c"]);
+    }
+
+    #[test]
+    fn test_ast_json_command() {
+        let (mut repl, buffer) = get_captured_repl();
+
+        execute(&mut repl, ".ast-json -2");
+
+        assert_eq!(buffer.contents(), concat!(
+            r#"{"id":2,"kind":"Unary","return_type":"ReturnInteger","operator":"-","children":["#,
+            r#"{"id":1,"kind":"Literal","return_type":"ReturnInteger","value":2,"children":[]}]}"#,
+            "\n"
+        ));
+    }
+
+    #[test]
+    fn test_color_toggle() {
+        let (mut repl, buffer) = get_captured_repl();
+
+        execute(&mut repl, ".tokens var x = 5;");
+        execute(&mut repl, "y + 1");
+        assert!(!buffer.contents().contains('\x1b'));
+
+        execute(&mut repl, ".color on");
+        execute(&mut repl, ".tokens var x = 5;");
+        execute(&mut repl, "y + 1");
+        assert!(buffer.contents().contains("\x1b[35mvar\x1b[0m"));
+        assert!(buffer.contents().contains("\x1b[31mParse error: Undefined variable 'y'\x1b[0m"));
+    }
+
+    #[test]
+    fn test_save_source() {
+        let path = get_temp_path("save_source");
+        let mut repl = get_test_repl();
+        let mut reader = MockReader::new(&[
+            "var a = 1;",
+            ".list_vars",
+            "a + 2",
+            &format!(".save_source {}", path.display()),
+        ]);
+
+        repl.run_with(&mut reader);
+
+        let contents = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(contents, "var a = 1;\na + 2\n");
+    }
 }
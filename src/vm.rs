@@ -1,37 +1,196 @@
+use std::fmt;
+use std::io;
+use std::ops::Range;
+use std::io::Write;
+
 use instruction::Opcode;
+use instruction::OperandKind;
+
+// Faults that halt execution
+#[derive(Debug, PartialEq, Clone)]
+pub enum VmError {
+    // Jump to an address outside the program or not on an instruction boundary
+    BadJump(usize),
+    // Program length isn't a whole number of instructions
+    BadLength(usize),
+    // Byte at the given address isn't a known opcode
+    UnknownOpcode(usize, u8),
+    // Register operand at the given address is out of range
+    BadRegister(usize, u8),
+    // Heap access of the given length at the given offset runs outside the heap
+    OutOfBounds(i32, i32),
+    // ASSERT at address pc found its registers differ
+    AssertionFailed { expected: i32, actual: i32, pc: usize },
+    // SYS at the given address named a syscall that was never registered
+    UnknownSyscall(usize, u8),
+    // ALOC asked for a heap of the given size, more than the limit that follows
+    HeapLimitExceeded(i64, usize),
+    // DIV at the given address had a zero divisor
+    DivisionByZero(usize),
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            VmError::BadJump(target) => write!(f, "Bad jump to address {}", target),
+            VmError::BadLength(len) => write!(f, "Program length {} is not a multiple of {}", len, INSTRUCTION_WIDTH),
+            VmError::UnknownOpcode(at, byte) => write!(f, "Unknown opcode {} at address {}", byte, at),
+            VmError::BadRegister(at, register) => write!(f, "Bad register ${} at address {}", register, at),
+            VmError::OutOfBounds(offset, len) => write!(f, "Heap access of {} bytes at offset {} is out of bounds", len, offset),
+            VmError::UnknownSyscall(at, index) => write!(f, "Unknown syscall {} at address {}", index, at),
+            VmError::HeapLimitExceeded(size, limit) => write!(f, "Heap of {} bytes exceeds the limit of {} bytes", size, limit),
+            VmError::DivisionByZero(at) => write!(f, "Division by zero at address {}", at),
+            VmError::AssertionFailed { expected, actual, pc } => write!(f, "Assertion failed at address {}: expected {}, got {}", pc, expected, actual),
+        }
+    }
+}
+
+// Host function invoked by the SYS opcode
+pub type Syscall = Box<dyn FnMut(&mut VM)>;
+
+// Width in bytes of every instruction
+const INSTRUCTION_WIDTH: usize = 4;
+
+// Largest heap ALOC will grow to unless the limit is changed, 64 MiB
+const DEFAULT_HEAP_LIMIT: usize = 64 * 1024 * 1024;
 
-#[derive(Debug)]
 pub struct VM {
     pub registers: [i32; 32],
+    pub float_registers: [f64; 32],
     pub pc: usize,
+    // Status passed to HLT by the program
+    pub exit_code: i32,
     pub program: Vec<u8>,
     heap: Vec<u8>,
+    // Size in bytes ALOC refuses to grow the heap beyond
+    heap_limit: usize,
     remainder: u32,
     equal_flag: bool,
+    // Execution count per opcode, in the order each opcode was first run
+    profile: Vec<(Opcode, u64)>,
+    trace: bool,
+    trace_out: Box<dyn Write>,
+    // Where opcodes that print write to
+    out: Box<dyn Write>,
+    // Host functions the program can call with SYS, by index
+    syscalls: Vec<Option<Syscall>>,
+}
+
+impl fmt::Debug for VM {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("VM")
+            .field("registers", &self.registers)
+            .field("float_registers", &self.float_registers)
+            .field("pc", &self.pc)
+            .field("exit_code", &self.exit_code)
+            .field("program", &self.program)
+            .field("heap", &self.heap)
+            .field("remainder", &self.remainder)
+            .field("equal_flag", &self.equal_flag)
+            .field("trace", &self.trace)
+            .finish()
+    }
 }
 
 impl VM {
     pub fn new() -> VM {
         VM {
             registers: [0; 32],
+            float_registers: [0.0; 32],
             program: vec![],
             heap: vec![],
+            heap_limit: DEFAULT_HEAP_LIMIT,
             pc: 0,
+            exit_code: 0,
             remainder: 0,
             equal_flag: false,
+            profile: vec![],
+            trace: false,
+            trace_out: Box::new(io::stdout()),
+            out: Box::new(io::stdout()),
+            syscalls: vec![],
         }
     }
 
-    fn skip_8_bits(&mut self) {
-        self.pc += 1;
+    // Replaces the program and rewinds to its first instruction
+    pub fn load_bytes(&mut self, bytes: Vec<u8>) {
+        self.program = bytes;
+        self.reset_pc();
     }
 
-    fn skip_16_bits(&mut self) {
-        self.pc += 2;
+    pub fn reset_pc(&mut self) {
+        self.pc = 0;
+    }
+
+    // Replaces the heap, e.g. with the data section of an assembled program
+    pub fn load_data(&mut self, bytes: Vec<u8>) {
+        self.heap = bytes;
+    }
+
+    pub fn heap(&self) -> &[u8] {
+        &self.heap
+    }
+
+    pub fn set_heap_limit(&mut self, limit: usize) {
+        self.heap_limit = limit;
+    }
+
+    // Prints every instruction and the registers it changed as it executes
+    pub fn set_trace(&mut self, trace: bool) {
+        self.trace = trace;
+    }
+
+    pub fn set_trace_output(&mut self, out: Box<dyn Write>) {
+        self.trace_out = out;
+    }
+
+    pub fn set_output(&mut self, out: Box<dyn Write>) {
+        self.out = out;
+    }
+
+    fn write_trace(&mut self, start: usize, before: &[i32; 32]) {
+        let opcode = Opcode::from(self.program[start]);
+        let end = std::cmp::min(start + opcode.width(), self.program.len());
+
+        let mut line = format!("{:04} {:?}", start, opcode);
+        for byte in &self.program[start + 1..end] {
+            line.push_str(&format!(" {}", byte));
+        }
+
+        for (i, (old, new)) in before.iter().zip(self.registers.iter()).enumerate() {
+            if old != new {
+                line.push_str(&format!(" | ${}: {} -> {}", i, old, new));
+            }
+        }
+
+        writeln!(self.trace_out, "{}", line).expect("Unable to write trace");
+    }
+
+    // Makes the closure callable from bytecode as `SYS index`, replacing any already there
+    pub fn register_syscall<F>(&mut self, index: u8, syscall: F) where F: FnMut(&mut VM) + 'static {
+        let index = index as usize;
+
+        if self.syscalls.len() <= index {
+            self.syscalls.resize_with(index + 1, || None);
+        }
+
+        self.syscalls[index] = Some(Box::new(syscall));
+    }
+
+    pub fn profile(&self) -> &[(Opcode, u64)] {
+        &self.profile
+    }
+
+    fn record_execution(&mut self, opcode: Opcode) {
+        match self.profile.iter_mut().find(|entry| entry.0 == opcode) {
+            Some(entry) => entry.1 += 1,
+            None => self.profile.push((opcode, 1)),
+        }
     }
 
-    fn skip_24_bits(&mut self) {
-        self.pc += 3;
+    // Steps over the padding bytes at the end of the current instruction
+    fn skip_unused(&mut self, opcode: Opcode) {
+        self.pc += opcode.unused_width();
     }
 
     fn next_8_bits(&mut self) -> u8 {
@@ -56,31 +215,127 @@ impl VM {
         return opcode;
     }
 
-    // Run until we run out of instructions to execute
-    pub fn run(&mut self) {
+    // Statically checks the program before it runs, reporting the first problem found
+    pub fn verify(&self) -> Result<(), VmError> {
+        if !self.program.len().is_multiple_of(INSTRUCTION_WIDTH) {
+            return Err(VmError::BadLength(self.program.len()));
+        }
+
+        for (index, instruction) in self.program.chunks(INSTRUCTION_WIDTH).enumerate() {
+            let start = index * INSTRUCTION_WIDTH;
+
+            let opcode = match Opcode::try_from(instruction[0]) {
+                Ok(opcode) => opcode,
+                Err(byte) => return Err(VmError::UnknownOpcode(start, byte)),
+            };
+
+            let mut offset = 1;
+            for kind in opcode.operand_kinds() {
+                if *kind == OperandKind::Register && instruction[offset] as usize >= self.registers.len() {
+                    return Err(VmError::BadRegister(start + offset, instruction[offset]));
+                }
+
+                offset += kind.width();
+            }
+        }
+
+        return Ok(())
+    }
+
+    // Run until we run out of instructions to execute, returning how many were executed
+    pub fn run(&mut self) -> Result<u64, VmError> {
+        self.verify()?;
+
+        let mut executed = 0;
         let mut is_done = false;
 
-        while !is_done {
-            is_done = self.execute_instruction();
+        while !is_done && self.pc < self.program.len() {
+            is_done = self.execute_instruction()?;
+            executed += 1;
         }
+
+        return Ok(executed)
     }
 
     // Execute only a single instruction
-    pub fn run_once(&mut self) {
-        self.execute_instruction();
+    pub fn run_once(&mut self) -> Result<(), VmError> {
+        self.execute_instruction()?;
+
+        return Ok(())
     }
 
-    pub fn execute_instruction(&mut self) -> bool {
+    pub fn execute_instruction(&mut self) -> Result<bool, VmError> {
         // Check whether we've exceeded the max size of the program
         if self.pc >= self.program.len() {
-            return true;
+            return Ok(true);
+        }
+
+        if !self.trace {
+            return self.step();
+        }
+
+        let start = self.pc;
+        let before = self.registers;
+
+        let done = self.step();
+        self.write_trace(start, &before);
+
+        return done
+    }
+
+    // The heap bytes covered by an access, if they all exist
+    fn heap_range(&self, offset: i32, len: i32) -> Result<Range<usize>, VmError> {
+        if offset < 0 || len < 0 || offset as usize + len as usize > self.heap.len() {
+            return Err(VmError::OutOfBounds(offset, len));
+        }
+
+        return Ok(offset as usize..offset as usize + len as usize)
+    }
+
+    // Offset of a word in an array of them. Overflow saturates to an offset no heap reaches.
+    fn element_offset(base: i32, index: i32) -> i32 {
+        return base.saturating_add(index.saturating_mul(4))
+    }
+
+    // Jumps may land on any instruction boundary, or exactly at the end of the program
+    fn jump_to(&mut self, target: usize) -> Result<(), VmError> {
+        if target > self.program.len() || !target.is_multiple_of(INSTRUCTION_WIDTH) {
+            return Err(VmError::BadJump(target));
         }
-        match self.decode_opcode() {
+
+        self.pc = target;
+
+        return Ok(())
+    }
+
+    // Jumps relative to pc. A target before the start of the program has no
+    // address, so the offset is reported instead.
+    fn jump_by(&mut self, offset: isize) -> Result<(), VmError> {
+        match self.pc.checked_add_signed(offset) {
+            Some(target) => return self.jump_to(target),
+            None => return Err(VmError::BadJump(offset.unsigned_abs()))
+        }
+    }
+
+    // Instructions are a fixed 4 bytes wide, laid out per Opcode::operand_kinds.
+    // Each arm must leave pc on the next 4-byte boundary unless it jumps, whichever
+    // way a conditional goes. JMPF/JMPB offsets count from the byte after their register.
+    fn step(&mut self) -> Result<bool, VmError> {
+        let opcode = self.decode_opcode();
+        self.record_execution(opcode);
+
+        match opcode {
 
             Opcode::HLT => {
-                println!("HLT encountered.. Exiting program");
+                self.exit_code = self.registers[self.next_8_bits() as usize];
+                self.skip_unused(opcode);
 
-                return true;
+                if self.trace {
+                    writeln!(self.trace_out, "HLT encountered.. Exiting program with status {}", self.exit_code)
+                        .expect("Unable to write trace");
+                }
+
+                return Ok(true);
             },
 
             Opcode::ADD => {
@@ -106,34 +361,205 @@ impl VM {
             },
 
             Opcode::DIV => {
+                let at = self.pc - 1;
                 let register1 = self.registers[self.next_8_bits() as usize];
                 let register2 = self.registers[self.next_8_bits() as usize];
 
+                if register2 == 0 {
+                    return Err(VmError::DivisionByZero(at))
+                }
+
                 self.registers[self.next_8_bits() as usize] = register1  / register2;
 
                 self.remainder = ( register1 % register2 ) as u32;
             },
 
+            // Arithmetic that wraps around on overflow, for code that relies on it
+            Opcode::WADD => {
+                let register1 = self.registers[self.next_8_bits() as usize];
+                let register2 = self.registers[self.next_8_bits() as usize];
+
+                self.registers[self.next_8_bits() as usize] = register1.wrapping_add(register2);
+            },
+
+            Opcode::WSUB => {
+                let register1 = self.registers[self.next_8_bits() as usize];
+                let register2 = self.registers[self.next_8_bits() as usize];
+
+                self.registers[self.next_8_bits() as usize] = register1.wrapping_sub(register2);
+            },
+
+            Opcode::WMUL => {
+                let register1 = self.registers[self.next_8_bits() as usize];
+                let register2 = self.registers[self.next_8_bits() as usize];
+
+                self.registers[self.next_8_bits() as usize] = register1.wrapping_mul(register2);
+            },
+
+            // Arithmetic that clamps to i32::MIN or i32::MAX on overflow
+            Opcode::SADD => {
+                let register1 = self.registers[self.next_8_bits() as usize];
+                let register2 = self.registers[self.next_8_bits() as usize];
+
+                self.registers[self.next_8_bits() as usize] = register1.saturating_add(register2);
+            },
+
+            Opcode::SSUB => {
+                let register1 = self.registers[self.next_8_bits() as usize];
+                let register2 = self.registers[self.next_8_bits() as usize];
+
+                self.registers[self.next_8_bits() as usize] = register1.saturating_sub(register2);
+            },
+
+            Opcode::SMUL => {
+                let register1 = self.registers[self.next_8_bits() as usize];
+                let register2 = self.registers[self.next_8_bits() as usize];
+
+                self.registers[self.next_8_bits() as usize] = register1.saturating_mul(register2);
+            },
+
             Opcode::LOAD => {
                 let register = self.next_8_bits() as usize;
-                let number = self.next_16_bits() as u16;
+                let number = self.next_16_bits();
 
                 self.registers[register] = number as i32;
             },
 
+            // The address isn't checked here, only when the heap is accessed through it
+            Opcode::LOADA => {
+                let register = self.next_8_bits() as usize;
+                let address = self.next_16_bits();
+
+                self.registers[register] = i32::from(address);
+            },
+
+            // Like LOAD, but the immediate is a signed 16 bit value
+            Opcode::LOADS => {
+                let register = self.next_8_bits() as usize;
+                let number = self.next_16_bits() as i16;
+
+                self.registers[register] = i32::from(number);
+            },
+
+            Opcode::FADD => {
+                let register1 = self.float_registers[self.next_8_bits() as usize];
+                let register2 = self.float_registers[self.next_8_bits() as usize];
+
+                self.float_registers[self.next_8_bits() as usize] = register1 + register2;
+            },
+
+            Opcode::FSUB => {
+                let register1 = self.float_registers[self.next_8_bits() as usize];
+                let register2 = self.float_registers[self.next_8_bits() as usize];
+
+                self.float_registers[self.next_8_bits() as usize] = register1 - register2;
+            },
+
+            Opcode::FMUL => {
+                let register1 = self.float_registers[self.next_8_bits() as usize];
+                let register2 = self.float_registers[self.next_8_bits() as usize];
+
+                self.float_registers[self.next_8_bits() as usize] = register1 * register2;
+            },
+
+            Opcode::FDIV => {
+                let register1 = self.float_registers[self.next_8_bits() as usize];
+                let register2 = self.float_registers[self.next_8_bits() as usize];
+
+                self.float_registers[self.next_8_bits() as usize] = register1 / register2;
+            },
+
+            Opcode::FLOAD => {
+                let register = self.next_8_bits() as usize;
+                let number = self.next_16_bits();
+
+                self.float_registers[register] = f64::from(number);
+            },
+
+            Opcode::ITOF => {
+                let value = self.registers[self.next_8_bits() as usize];
+
+                self.float_registers[self.next_8_bits() as usize] = f64::from(value);
+
+                self.skip_unused(opcode);
+            },
+
+            Opcode::FTOI => {
+                let value = self.float_registers[self.next_8_bits() as usize];
+
+                // Truncates towards zero, saturating at the bounds of i32
+                self.registers[self.next_8_bits() as usize] = value as i32;
+
+                self.skip_unused(opcode);
+            },
+
+            Opcode::MOV => {
+                let value = self.registers[self.next_8_bits() as usize];
+
+                self.registers[self.next_8_bits() as usize] = value;
+
+                self.skip_unused(opcode);
+            },
+
+            // MOV that only happens when the last comparison set the flag
+            Opcode::CMOV => {
+                let value = self.registers[self.next_8_bits() as usize];
+                let register = self.next_8_bits() as usize;
+
+                if self.equal_flag {
+                    self.registers[register] = value;
+                }
+
+                self.skip_unused(opcode);
+            },
+
             Opcode::JMP => {
                 let target = self.registers[self.next_8_bits() as usize];
-                self.pc = target as usize;
+                self.jump_to(target as usize)?;
             },
 
             Opcode::JMPF => {
                 let offset = self.registers[self.next_8_bits() as usize] as usize;
-                self.pc += offset;
+                let target = self.pc.checked_add(offset).ok_or(VmError::BadJump(offset))?;
+                self.jump_to(target)?;
             },
 
             Opcode::JMPB => {
                 let offset = self.registers[self.next_8_bits() as usize] as usize;
-                self.pc -= offset;
+                // Jumping back past the start of the program has no address, report the offset
+                let target = self.pc.checked_sub(offset).ok_or(VmError::BadJump(offset))?;
+                self.jump_to(target)?;
+            },
+
+            // Counts the register down, jumping to the target until it reaches zero
+            Opcode::LOOP => {
+                let counter = self.next_8_bits() as usize;
+                let target = self.registers[self.next_8_bits() as usize];
+
+                self.skip_unused(opcode);
+
+                self.registers[counter] = self.registers[counter].wrapping_sub(1);
+
+                if self.registers[counter] != 0 {
+                    self.jump_to(target as usize)?;
+                }
+            },
+
+            // Immediate jump offsets are relative to the start of the next instruction
+            Opcode::JMPI => {
+                let offset = self.next_16_bits() as i16;
+                self.skip_unused(opcode);
+
+                self.jump_by(offset as isize)?;
+            },
+
+            Opcode::JEQI => {
+                let offset = self.next_16_bits() as i16;
+                self.skip_unused(opcode);
+
+                if self.equal_flag {
+                    self.jump_by(offset as isize)?;
+                }
             },
 
             Opcode::EQ => {
@@ -146,7 +572,14 @@ impl VM {
                     self.equal_flag = false;
                 }
 
-                self.skip_8_bits();
+                self.skip_unused(opcode);
+            },
+
+            Opcode::CMPI => {
+                let register = self.registers[self.next_8_bits() as usize];
+                let number = self.next_16_bits();
+
+                self.equal_flag = register == i32::from(number);
             },
 
             Opcode::NEQ => {
@@ -159,15 +592,17 @@ impl VM {
                     self.equal_flag = false;
                 }
 
-                self.skip_8_bits();
+                self.skip_unused(opcode);
             },
 
             Opcode::JEQ => {
-                if self.equal_flag {
-                    let register = self.next_8_bits() as usize;
-                    let target = self.registers[register];
+                let register = self.next_8_bits() as usize;
+                let target = self.registers[register];
 
-                    self.pc = target as usize;
+                self.skip_unused(opcode);
+
+                if self.equal_flag {
+                    self.jump_to(target as usize)?;
                 }
             },
 
@@ -175,14 +610,16 @@ impl VM {
                 let register = self.next_8_bits() as usize;
                 let target = self.registers[register];
 
+                self.skip_unused(opcode);
+
                 if !self.equal_flag {
-                    self.pc = target as usize;
+                    self.jump_to(target as usize)?;
                 }
             },
 
             Opcode::GTE => {
-                let register1 = self.registers[self.next_8_bits() as usize] as usize;
-                let register2 = self.registers[self.next_8_bits() as usize] as usize;
+                let register1 = self.registers[self.next_8_bits() as usize];
+                let register2 = self.registers[self.next_8_bits() as usize];
 
                 if register1 >= register2 {
                     self.equal_flag = true;
@@ -190,12 +627,12 @@ impl VM {
                     self.equal_flag = false;
                 }
 
-                self.skip_8_bits();
+                self.skip_unused(opcode);
             },
 
             Opcode::LTE => {
-                let register1 = self.registers[self.next_8_bits() as usize] as usize;
-                let register2 = self.registers[self.next_8_bits() as usize] as usize;
+                let register1 = self.registers[self.next_8_bits() as usize];
+                let register2 = self.registers[self.next_8_bits() as usize];
 
                 if register1 <= register2 {
                     self.equal_flag = true;
@@ -203,12 +640,12 @@ impl VM {
                     self.equal_flag = false;
                 }
 
-                self.skip_8_bits();
+                self.skip_unused(opcode);
             },
 
             Opcode::LT => {
-                let register1 = self.registers[self.next_8_bits() as usize] as usize;
-                let register2 = self.registers[self.next_8_bits() as usize] as usize;
+                let register1 = self.registers[self.next_8_bits() as usize];
+                let register2 = self.registers[self.next_8_bits() as usize];
 
                 if register1 < register2 {
                     self.equal_flag = true;
@@ -216,12 +653,12 @@ impl VM {
                     self.equal_flag = false;
                 }
 
-                self.skip_8_bits();
+                self.skip_unused(opcode);
             },
 
             Opcode::GT => {
-                let register1 = self.registers[self.next_8_bits() as usize] as usize;
-                let register2 = self.registers[self.next_8_bits() as usize] as usize;
+                let register1 = self.registers[self.next_8_bits() as usize];
+                let register2 = self.registers[self.next_8_bits() as usize];
 
                 if register1 > register2 {
                     self.equal_flag = true;
@@ -229,30 +666,171 @@ impl VM {
                     self.equal_flag = false;
                 }
 
-                self.skip_8_bits();
+                self.skip_unused(opcode);
             },
 
             Opcode::NOP => {
-                self.skip_24_bits();
+                self.skip_unused(opcode);
+            },
+
+            // The syscall is taken out of its slot while it runs, so it can have the whole VM
+            Opcode::SYS => {
+                let at = self.pc - 1;
+                let index = self.next_8_bits();
+                self.skip_unused(opcode);
+
+                let mut syscall = match self.syscalls.get_mut(index as usize).and_then(|s| s.take()) {
+                    Some(syscall) => syscall,
+                    None => return Err(VmError::UnknownSyscall(at, index)),
+                };
+
+                syscall(self);
+
+                // Unless the syscall registered a replacement for itself
+                if self.syscalls[index as usize].is_none() {
+                    self.syscalls[index as usize] = Some(syscall);
+                }
+            },
+
+            Opcode::ASSERT => {
+                let pc = self.pc - 1;
+                let expected = self.registers[self.next_8_bits() as usize];
+                let actual = self.registers[self.next_8_bits() as usize];
+
+                self.skip_unused(opcode);
+
+                if expected != actual {
+                    return Err(VmError::AssertionFailed { expected, actual, pc });
+                }
             },
 
+            // ALOC is additive: it grows the heap by the register's count on top of
+            // whatever is already allocated. ALOC_ABS sets the size outright.
             Opcode::ALOC => {
                 let register = self.next_8_bits() as usize;
                 let bytes = self.registers[register];
-                let new_len = self.heap.len() as i32 + bytes;
+                let new_len = self.heap.len() as i64 + i64::from(bytes);
+
+                if new_len > self.heap_limit as i64 {
+                    return Err(VmError::HeapLimitExceeded(new_len, self.heap_limit))
+                }
+
+                self.heap.resize(new_len.max(0) as usize, 0);
+
+                self.skip_unused(opcode);
+            }
+
+            Opcode::ALOC_ABS => {
+                let register = self.next_8_bits() as usize;
+                let new_len = self.registers[register].max(0) as usize;
+
+                if new_len > self.heap_limit {
+                    return Err(VmError::HeapLimitExceeded(new_len as i64, self.heap_limit))
+                }
+
+                self.heap.resize(new_len, 0);
+
+                self.skip_unused(opcode);
+            }
 
-                self.heap.resize(new_len as usize, 0);
+            // Releases bytes from the end of the heap, emptying it at most
+            Opcode::FREE => {
+                let register = self.next_8_bits() as usize;
+                let bytes = self.registers[register].max(0) as usize;
+                let new_len = self.heap.len().saturating_sub(bytes);
+
+                self.heap.truncate(new_len);
 
-                self.skip_16_bits();
+                self.skip_unused(opcode);
             }
 
+            // Words are stored big endian, like immediates in the program
+            Opcode::SW => {
+                let value = self.registers[self.next_8_bits() as usize];
+                let offset = self.registers[self.next_8_bits() as usize];
+
+                let range = self.heap_range(offset, 4)?;
+                self.heap[range].copy_from_slice(&value.to_be_bytes());
+
+                self.skip_unused(opcode);
+            },
+
+            Opcode::LW => {
+                let offset = self.registers[self.next_8_bits() as usize];
+                let register = self.next_8_bits() as usize;
+
+                let range = self.heap_range(offset, 4)?;
+                let mut word = [0; 4];
+                word.copy_from_slice(&self.heap[range]);
+                self.registers[register] = i32::from_be_bytes(word);
+
+                self.skip_unused(opcode);
+            },
+
+            Opcode::STORE_IDX => {
+                let base = self.registers[self.next_8_bits() as usize];
+                let index = self.registers[self.next_8_bits() as usize];
+                let value = self.registers[self.next_8_bits() as usize];
+
+                let range = self.heap_range(VM::element_offset(base, index), 4)?;
+                self.heap[range].copy_from_slice(&value.to_be_bytes());
+            },
+
+            Opcode::LOAD_IDX => {
+                let base = self.registers[self.next_8_bits() as usize];
+                let index = self.registers[self.next_8_bits() as usize];
+                let register = self.next_8_bits() as usize;
+
+                let range = self.heap_range(VM::element_offset(base, index), 4)?;
+                let mut word = [0; 4];
+                word.copy_from_slice(&self.heap[range]);
+                self.registers[register] = i32::from_be_bytes(word);
+            },
+
+            // Prints the heap bytes in hex, sixteen to a line prefixed by the offset of the first
+            Opcode::DUMP => {
+                let offset = self.registers[self.next_8_bits() as usize];
+                let len = self.registers[self.next_8_bits() as usize];
+
+                let range = self.heap_range(offset, len)?;
+                let start = range.start;
+
+                for (i, line) in self.heap[range].chunks(16).enumerate() {
+                    let bytes: Vec<String> = line.iter().map(|b| format!("{:02x}", b)).collect();
+                    writeln!(self.out, "{:04}: {}", start + i * 16, bytes.join(" ")).expect("Unable to write output");
+                }
+
+                self.skip_unused(opcode);
+            },
+
+            Opcode::PRTR => {
+                let value = self.registers[self.next_8_bits() as usize];
+                writeln!(self.out, "{}", value).expect("Unable to write output");
+
+                self.skip_unused(opcode);
+            },
+
+            Opcode::NEG => {
+                let register = self.next_8_bits() as usize;
+                self.registers[register] = self.registers[register].wrapping_neg();
+
+                self.skip_unused(opcode);
+            },
+
+            Opcode::NOT => {
+                let register = self.next_8_bits() as usize;
+                self.registers[register] = !self.registers[register];
+
+                self.skip_unused(opcode);
+            },
+
             _ => {
-                println!("Illegal operation encountered");
-                return true;
+                writeln!(self.out, "Illegal operation encountered").expect("Unable to write output");
+                return Ok(true);
             }
         }
 
-        false
+        return Ok(false)
     }
 }
 
@@ -260,6 +838,22 @@ impl VM {
 mod tests {
     use super::*;
 
+    use std::rc::Rc;
+    use std::cell::RefCell;
+
+    #[derive(Clone)]
+    struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
     fn get_test_vm() -> VM {
         let mut test_vm = VM::new();
 
@@ -276,13 +870,46 @@ mod tests {
     }
 
     #[test]
-    fn test_opcode_hlt() {
+    fn test_load_bytes_and_rerun() {
         let mut test_vm = get_test_vm();
 
-        test_vm.program = vec![5, 0, 0, 0];
-        test_vm.run_once();
+        test_vm.pc = 3;
+        test_vm.load_bytes(vec![1, 0, 1, 0]);
 
-        assert_eq!(test_vm.pc, 1);
+        assert_eq!(test_vm.pc, 0);
+
+        test_vm.run().unwrap();
+
+        assert_eq!(test_vm.registers[0], 15);
+        assert_eq!(test_vm.pc, 4);
+
+        test_vm.reset_pc();
+        test_vm.run().unwrap();
+
+        assert_eq!(test_vm.registers[0], 25);
+    }
+
+    #[test]
+    fn test_opcode_hlt() {
+        let mut test_vm = get_test_vm();
+
+        test_vm.program = vec![5, 0, 0, 0];
+        test_vm.run_once().unwrap();
+
+        assert_eq!(test_vm.pc, 4);
+    }
+
+    #[test]
+    fn test_opcode_hlt_exit_code() {
+        let mut test_vm = get_test_vm();
+
+        test_vm.program = vec![0, 3, 0, 42, 5, 3, 0, 0, 0, 3, 0, 1];
+        test_vm.run().unwrap();
+
+        assert_eq!(test_vm.exit_code, 42);
+        // Execution stops at the HLT, the LOAD after it never runs
+        assert_eq!(test_vm.registers[3], 42);
+        assert_eq!(test_vm.pc, 8);
     }
 
     #[test]
@@ -290,7 +917,7 @@ mod tests {
         let mut test_vm = get_test_vm();
 
         test_vm.program = vec![254, 0, 0, 0];
-        test_vm.run_once();
+        test_vm.run_once().unwrap();
 
         assert_eq!(test_vm.pc, 1);
     }
@@ -300,17 +927,30 @@ mod tests {
         let mut test_vm = get_test_vm();
 
         test_vm.program = vec![0, 0, 1, 244];
-        test_vm.run();
+        test_vm.run().unwrap();
 
         assert_eq!(test_vm.registers[0], 500);
     }
 
+    #[test]
+    fn test_opcode_loads() {
+        let mut test_vm = get_test_vm();
+
+        // -5 as a 16 bit two's complement immediate
+        test_vm.program = vec![37, 0, 255, 251, 37, 1, 1, 244];
+        test_vm.run().unwrap();
+
+        assert_eq!(test_vm.registers[0], -5);
+        assert_eq!(test_vm.registers[1], 500);
+        assert_eq!(test_vm.pc, 8);
+    }
+
     #[test]
     fn test_opcode_add() {
         let mut test_vm = get_test_vm();
 
         test_vm.program = vec![1, 0, 1, 2];
-        test_vm.run();
+        test_vm.run().unwrap();
 
         assert_eq!(test_vm.registers[2], 15);
     }
@@ -320,7 +960,7 @@ mod tests {
         let mut test_vm = get_test_vm();
 
         test_vm.program = vec![2, 1, 0, 2];
-        test_vm.run();
+        test_vm.run().unwrap();
 
         assert_eq!(test_vm.registers[2], 5);
     }
@@ -330,7 +970,7 @@ mod tests {
         let mut test_vm = get_test_vm();
 
         test_vm.program = vec![3, 0, 1, 2];
-        test_vm.run();
+        test_vm.run().unwrap();
 
         assert_eq!(test_vm.registers[2], 50);
     }
@@ -340,18 +980,65 @@ mod tests {
         let mut test_vm = get_test_vm();
 
         test_vm.program = vec![4, 1, 0, 2];
-        test_vm.run();
+        test_vm.run().unwrap();
 
         assert_eq!(test_vm.registers[2], 2);
     }
 
+    #[test]
+    fn test_opcode_div_by_zero() {
+        let mut test_vm = get_test_vm();
+
+        test_vm.registers[0] = 0;
+        test_vm.program = vec![17, 0, 0, 0, 4, 1, 0, 2];
+
+        assert_eq!(test_vm.run(), Err(VmError::DivisionByZero(4)));
+    }
+
+    #[test]
+    fn test_opcode_mov() {
+        let mut test_vm = get_test_vm();
+
+        test_vm.program = vec![31, 0, 5, 0];
+        test_vm.run_once().unwrap();
+
+        assert_eq!(test_vm.registers[5], 5);
+        assert_eq!(test_vm.registers[0], 5);
+        assert_eq!(test_vm.pc, 4);
+    }
+
+    #[test]
+    fn test_opcode_cmov() {
+        let mut test_vm = get_test_vm();
+
+        test_vm.equal_flag = true;
+        test_vm.program = vec![38, 0, 2, 0];
+        test_vm.run().unwrap();
+
+        assert_eq!(test_vm.registers[2], 5);
+        assert_eq!(test_vm.pc, 4);
+    }
+
+    #[test]
+    fn test_opcode_cmov_flag_clear() {
+        let mut test_vm = get_test_vm();
+
+        test_vm.registers[2] = 7;
+        test_vm.equal_flag = false;
+        test_vm.program = vec![38, 0, 2, 0, 17, 0, 0, 0];
+        test_vm.run().unwrap();
+
+        assert_eq!(test_vm.registers[2], 7);
+        assert_eq!(test_vm.pc, 8);
+    }
+
     #[test]
     fn test_opcode_jmpf() {
         let mut test_vm = get_test_vm();
 
         test_vm.registers[0] = 2;
         test_vm.program = vec![7, 0, 0, 0, 5, 0, 0, 0];
-        test_vm.run_once();
+        test_vm.run_once().unwrap();
 
         assert_eq!(test_vm.pc, 4);
     }
@@ -362,12 +1049,150 @@ mod tests {
 
         test_vm.registers[1] = 6;
         test_vm.program = vec![0, 0, 0, 10, 8, 1 ,0, 0];
-        test_vm.run_once();
-        test_vm.run_once();
+        test_vm.run_once().unwrap();
+        test_vm.run_once().unwrap();
 
         assert_eq!(test_vm.pc, 0);
     }
 
+    #[test]
+    fn test_opcode_loop() {
+        let mut test_vm = get_test_vm();
+
+        test_vm.registers[0] = 5;
+        test_vm.registers[2] = 1;
+        test_vm.registers[4] = 0;
+
+        // The ADD body runs once per count, then execution falls out of the loop
+        test_vm.program = vec![1, 3, 2, 3, 42, 0, 4, 0, 17, 0, 0, 0];
+        test_vm.run().unwrap();
+
+        assert_eq!(test_vm.registers[3], 5);
+        assert_eq!(test_vm.registers[0], 0);
+        assert_eq!(test_vm.profile(), &[(Opcode::ADD, 5), (Opcode::LOOP, 5), (Opcode::NOP, 1)]);
+    }
+
+    #[test]
+    fn test_opcode_jmpi_forward() {
+        let mut test_vm = get_test_vm();
+
+        test_vm.program = vec![29, 0, 4, 0, 17, 0, 0, 0, 17, 0, 0, 0];
+        test_vm.run_once().unwrap();
+
+        assert_eq!(test_vm.pc, 8);
+    }
+
+    #[test]
+    fn test_opcode_jmpi_backward() {
+        let mut test_vm = get_test_vm();
+
+        // Two NOPs then a jump of -12 back to the first NOP
+        test_vm.program = vec![17, 0, 0, 0, 17, 0, 0, 0, 29, 255, 244, 0];
+        test_vm.run_once().unwrap();
+        test_vm.run_once().unwrap();
+        test_vm.run_once().unwrap();
+
+        assert_eq!(test_vm.pc, 0);
+    }
+
+    #[test]
+    fn test_opcode_jeqi() {
+        let mut test_vm = get_test_vm();
+
+        test_vm.program = vec![30, 0, 4, 0, 17, 0, 0, 0, 17, 0, 0, 0];
+        test_vm.run_once().unwrap();
+
+        assert_eq!(test_vm.pc, 4);
+
+        test_vm.pc = 0;
+        test_vm.equal_flag = true;
+        test_vm.run_once().unwrap();
+
+        assert_eq!(test_vm.pc, 8);
+    }
+
+    #[test]
+    fn test_opcode_jmp_out_of_bounds() {
+        let mut test_vm = get_test_vm();
+
+        test_vm.registers[0] = 400;
+        test_vm.program = vec![6, 0, 0, 0, 17, 0, 0, 0];
+
+        assert_eq!(test_vm.run(), Err(VmError::BadJump(400)));
+    }
+
+    #[test]
+    fn test_opcode_jmpf_negative_offset() {
+        let mut test_vm = get_test_vm();
+
+        test_vm.registers[0] = -4;
+        test_vm.program = vec![7, 0, 0, 0, 17, 0, 0, 0];
+
+        assert!(matches!(test_vm.run(), Err(VmError::BadJump(_))));
+    }
+
+    #[test]
+    fn test_immediate_jumps_validated() {
+        let mut test_vm = get_test_vm();
+
+        // Eight bytes back from the end of the first instruction
+        test_vm.program = vec![29, 0xff, 0xf8, 0, 17, 0, 0, 0];
+        assert_eq!(test_vm.run(), Err(VmError::BadJump(8)));
+
+        // Into the middle of the second instruction
+        test_vm.equal_flag = true;
+        test_vm.program = vec![30, 0, 2, 0, 17, 0, 0, 0];
+        test_vm.reset_pc();
+        assert_eq!(test_vm.run(), Err(VmError::BadJump(6)));
+    }
+
+    #[test]
+    fn test_register_jumps_validated() {
+        let mut test_vm = get_test_vm();
+
+        test_vm.equal_flag = true;
+        test_vm.registers[0] = 2;
+        test_vm.program = vec![10, 0, 0, 0, 17, 0, 0, 0];
+        assert_eq!(test_vm.run(), Err(VmError::BadJump(2)));
+
+        test_vm.equal_flag = false;
+        test_vm.registers[0] = 400;
+        test_vm.program = vec![11, 0, 0, 0, 17, 0, 0, 0];
+        test_vm.reset_pc();
+        assert_eq!(test_vm.run(), Err(VmError::BadJump(400)));
+    }
+
+    #[test]
+    fn test_opcode_jmpb_before_start() {
+        let mut test_vm = get_test_vm();
+
+        test_vm.registers[0] = 40;
+        test_vm.program = vec![17, 0, 0, 0, 8, 0, 0, 0];
+
+        assert_eq!(test_vm.run(), Err(VmError::BadJump(40)));
+    }
+
+    #[test]
+    fn test_opcode_jmpf_mid_instruction() {
+        let mut test_vm = get_test_vm();
+
+        test_vm.registers[0] = 3;
+        test_vm.program = vec![7, 0, 0, 0, 17, 0, 0, 0];
+
+        assert_eq!(test_vm.run(), Err(VmError::BadJump(5)));
+    }
+
+    #[test]
+    fn test_opcode_jmp_to_end() {
+        let mut test_vm = get_test_vm();
+
+        test_vm.registers[0] = 8;
+        test_vm.program = vec![6, 0, 0, 0, 17, 0, 0, 0];
+
+        assert_eq!(test_vm.run(), Ok(1));
+        assert_eq!(test_vm.pc, 8);
+    }
+
     #[test]
     fn test_opcode_eq() {
         let mut test_vm = get_test_vm();
@@ -376,12 +1201,27 @@ mod tests {
         test_vm.registers[1] = 10;
 
         test_vm.program = vec![9, 0, 1, 0, 9, 0, 1, 0];
-        test_vm.run_once();
+        test_vm.run_once().unwrap();
 
         assert_eq!(test_vm.equal_flag, true);
 
         test_vm.registers[1] = 20;
-        test_vm.run_once();
+        test_vm.run_once().unwrap();
+
+        assert_eq!(test_vm.equal_flag, false);
+    }
+
+    #[test]
+    fn test_opcode_cmpi() {
+        let mut test_vm = get_test_vm();
+
+        test_vm.program = vec![32, 1, 0, 10, 32, 1, 0, 11];
+        test_vm.run_once().unwrap();
+
+        assert_eq!(test_vm.equal_flag, true);
+        assert_eq!(test_vm.pc, 4);
+
+        test_vm.run_once().unwrap();
 
         assert_eq!(test_vm.equal_flag, false);
     }
@@ -394,12 +1234,12 @@ mod tests {
         test_vm.registers[1] = 10;
 
         test_vm.program = vec![12, 0, 1, 0, 12, 0, 1, 0];
-        test_vm.run_once();
+        test_vm.run_once().unwrap();
 
         assert_eq!(test_vm.equal_flag, false);
 
         test_vm.registers[1] = 20;
-        test_vm.run_once();
+        test_vm.run_once().unwrap();
 
         assert_eq!(test_vm.equal_flag, true);
     }
@@ -408,24 +1248,69 @@ mod tests {
     fn test_opcode_jeq() {
         let mut test_vm = get_test_vm();
 
-        test_vm.registers[0] = 7;
+        test_vm.registers[0] = 8;
         test_vm.equal_flag = true;
         test_vm.program = vec![10, 0, 0, 0, 17, 0, 0, 0, 17, 0, 0, 0];
-        test_vm.run_once();
+        test_vm.run_once().unwrap();
+
+        assert_eq!(test_vm.pc, 8);
+    }
+
+    #[test]
+    fn test_opcode_jeq_not_taken() {
+        let mut test_vm = get_test_vm();
+
+        test_vm.registers[0] = 8;
+        test_vm.equal_flag = false;
+        test_vm.program = vec![10, 0, 0, 0, 17, 0, 0, 0, 17, 0, 0, 0];
+        test_vm.run_once().unwrap();
+
+        assert_eq!(test_vm.pc, 4);
+
+        // The next opcode decoded is the NOP, not the JEQ's operand byte
+        test_vm.run_once().unwrap();
 
-        assert_eq!(test_vm.pc, 7);
+        assert_eq!(test_vm.pc, 8);
     }
 
     #[test]
     fn test_opcode_jne() {
         let mut test_vm = get_test_vm();
 
-        test_vm.registers[0] = 7;
+        test_vm.registers[0] = 8;
         test_vm.equal_flag = false;
         test_vm.program = vec![11, 0, 0, 0, 17, 0, 0, 0, 17, 0, 0, 0];
-        test_vm.run_once();
+        test_vm.run_once().unwrap();
 
-        assert_eq!(test_vm.pc, 7);
+        assert_eq!(test_vm.pc, 8);
+    }
+
+    #[test]
+    fn test_mixed_comparisons_stay_aligned() {
+        let mut test_vm = get_test_vm();
+
+        test_vm.registers[2] = 36;
+
+        test_vm.program = vec![
+                                9, 0, 1, 0,
+                                10, 2, 0, 0,
+                                12, 0, 1, 0,
+                                11, 2, 0, 0,
+                                13, 0, 1, 0,
+                                14, 0, 1, 0,
+                                15, 0, 1, 0,
+                                16, 0, 1, 0,
+                                10, 2, 0, 0,
+                            ];
+
+        while test_vm.pc < test_vm.program.len() {
+            test_vm.run_once().unwrap();
+
+            assert_eq!(test_vm.pc % 4, 0);
+        }
+
+        // The final JEQ was taken as 5 < 10, jumping to the end of the program
+        assert_eq!(test_vm.pc, 36);
     }
 
     #[test]
@@ -436,17 +1321,17 @@ mod tests {
         test_vm.registers[1] = 10;
 
         test_vm.program = vec![14, 0, 1, 0, 14, 0, 1, 0, 14, 0, 1, 0];
-        test_vm.run_once();
+        test_vm.run_once().unwrap();
 
         assert_eq!(test_vm.equal_flag, true);
 
         test_vm.registers[1] = 20;
-        test_vm.run_once();
+        test_vm.run_once().unwrap();
 
         assert_eq!(test_vm.equal_flag, true);
 
         test_vm.registers[1] = 6;
-        test_vm.run_once();
+        test_vm.run_once().unwrap();
 
         assert_eq!(test_vm.equal_flag, false);
     }
@@ -459,17 +1344,17 @@ mod tests {
         test_vm.registers[1] = 10;
 
         test_vm.program = vec![13, 0, 1, 0, 13, 0, 1, 0, 13, 0, 1, 0];
-        test_vm.run_once();
+        test_vm.run_once().unwrap();
 
         assert_eq!(test_vm.equal_flag, true);
 
         test_vm.registers[1] = 20;
-        test_vm.run_once();
+        test_vm.run_once().unwrap();
 
         assert_eq!(test_vm.equal_flag, false);
 
         test_vm.registers[1] = 6;
-        test_vm.run_once();
+        test_vm.run_once().unwrap();
 
         assert_eq!(test_vm.equal_flag, true);
     }
@@ -482,12 +1367,12 @@ mod tests {
         test_vm.registers[1] = 7;
 
         test_vm.program = vec![16, 0, 1, 0, 16, 0, 1, 0];
-        test_vm.run_once();
+        test_vm.run_once().unwrap();
 
         assert_eq!(test_vm.equal_flag, false);
 
         test_vm.registers[1] = 17;
-        test_vm.run_once();
+        test_vm.run_once().unwrap();
 
         assert_eq!(test_vm.equal_flag, true);
     }
@@ -500,12 +1385,12 @@ mod tests {
         test_vm.registers[1] = 7;
 
         test_vm.program = vec![15, 0, 1, 0, 15, 0, 1, 0];
-        test_vm.run_once();
+        test_vm.run_once().unwrap();
 
         assert_eq!(test_vm.equal_flag, false);
 
         test_vm.registers[1] = 17;
-        test_vm.run_once();
+        test_vm.run_once().unwrap();
 
         assert_eq!(test_vm.equal_flag, false);
     }
@@ -517,9 +1402,242 @@ mod tests {
         test_vm.registers[0] = 1024;
 
         test_vm.program = vec![18, 0, 0, 0];
-        test_vm.run_once();
+        test_vm.run_once().unwrap();
+
+        assert_eq!(test_vm.heap.len(), 1024);
+    }
+
+    #[test]
+    fn test_opcode_aloc_is_additive() {
+        let mut test_vm = get_test_vm();
+
+        test_vm.registers[0] = 1024;
+
+        test_vm.program = vec![18, 0, 0, 0, 18, 0, 0, 0];
+        test_vm.run().unwrap();
+
+        assert_eq!(test_vm.heap.len(), 2048);
+    }
+
+    #[test]
+    fn test_opcode_aloc_abs() {
+        let mut test_vm = get_test_vm();
+
+        test_vm.registers[0] = 1024;
+        test_vm.registers[1] = 100;
+
+        test_vm.program = vec![18, 0, 0, 0, 52, 1, 0, 0];
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.heap.len(), 100);
 
+        test_vm.load_bytes(vec![52, 0, 0, 0]);
+        test_vm.run().unwrap();
         assert_eq!(test_vm.heap.len(), 1024);
+
+        test_vm.set_heap_limit(512);
+        test_vm.load_bytes(vec![52, 0, 0, 0]);
+        assert_eq!(test_vm.run(), Err(VmError::HeapLimitExceeded(1024, 512)));
+    }
+
+    #[test]
+    fn test_opcode_free() {
+        let mut test_vm = get_test_vm();
+
+        test_vm.registers[0] = 1024;
+        test_vm.registers[1] = 512;
+
+        test_vm.program = vec![18, 0, 0, 0, 51, 1, 0, 0];
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.heap.len(), 512);
+
+        // Freeing more than is allocated leaves an empty heap
+        test_vm.registers[1] = 4096;
+        test_vm.load_bytes(vec![51, 1, 0, 0]);
+        test_vm.run().unwrap();
+        assert!(test_vm.heap.is_empty());
+    }
+
+    #[test]
+    fn test_opcode_aloc_limit() {
+        let mut test_vm = get_test_vm();
+
+        test_vm.set_heap_limit(512);
+        test_vm.registers[0] = 1024;
+
+        test_vm.program = vec![18, 0, 0, 0];
+        assert_eq!(test_vm.run(), Err(VmError::HeapLimitExceeded(1024, 512)));
+        assert!(test_vm.heap.is_empty());
+
+        // Growing up to the limit is fine
+        test_vm.registers[0] = 512;
+        test_vm.load_bytes(vec![18, 0, 0, 0]);
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.heap.len(), 512);
+    }
+
+    #[test]
+    fn test_opcode_loada() {
+        let mut test_vm = get_test_vm();
+
+        test_vm.load_data(vec![7, 8, 9]);
+        test_vm.program = vec![33, 2, 0, 2];
+        test_vm.run_once().unwrap();
+
+        assert_eq!(test_vm.registers[2], 2);
+        assert_eq!(test_vm.heap()[2], 9);
+        assert_eq!(test_vm.pc, 4);
+    }
+
+    #[test]
+    fn test_opcode_sw_lw() {
+        let mut test_vm = get_test_vm();
+
+        test_vm.registers[2] = 8;
+        test_vm.registers[3] = -2;
+        test_vm.registers[4] = 4;
+
+        test_vm.program = vec![18, 2, 0, 0, 34, 3, 4, 0, 35, 4, 5, 0];
+        test_vm.run().unwrap();
+
+        assert_eq!(test_vm.heap(), &[0, 0, 0, 0, 255, 255, 255, 254]);
+        assert_eq!(test_vm.registers[5], -2);
+    }
+
+    #[test]
+    fn test_opcode_store_load_idx() {
+        let mut test_vm = get_test_vm();
+
+        // An array of 5 words starting at offset 4
+        test_vm.registers[2] = 24;
+        test_vm.registers[3] = 4;
+        test_vm.registers[4] = 3;
+        test_vm.registers[6] = -77;
+
+        test_vm.program = vec![18, 2, 0, 0, 39, 3, 4, 6, 40, 3, 4, 7];
+        test_vm.run().unwrap();
+
+        assert_eq!(&test_vm.heap()[16..20], &(-77i32).to_be_bytes());
+        assert_eq!(test_vm.registers[7], -77);
+        assert_eq!(test_vm.pc, 12);
+
+        test_vm.registers[4] = 5;
+        test_vm.program = vec![40, 3, 4, 7];
+        test_vm.reset_pc();
+
+        assert_eq!(test_vm.run(), Err(VmError::OutOfBounds(24, 4)));
+    }
+
+    #[test]
+    fn test_opcode_dump() {
+        let mut test_vm = get_test_vm();
+
+        test_vm.registers[2] = 16;
+        test_vm.registers[3] = 500;
+        test_vm.registers[4] = 4;
+        test_vm.registers[5] = 8;
+
+        let buffer = SharedBuffer(Rc::new(RefCell::new(vec![])));
+        test_vm.set_output(Box::new(buffer.clone()));
+
+        // ALOC 16, SW $3 at 4, DUMP 8 bytes from 4
+        test_vm.program = vec![18, 2, 0, 0, 34, 3, 4, 0, 36, 4, 5, 0];
+        test_vm.run().unwrap();
+
+        assert_eq!(test_vm.pc, 12);
+        assert_eq!(&test_vm.heap()[4..8], &[0, 0, 1, 244]);
+        assert_eq!(String::from_utf8(buffer.0.borrow().clone()).unwrap(), "0004: 00 00 01 f4 00 00 00 00\n");
+
+        test_vm.registers[5] = 13;
+        test_vm.program = vec![36, 4, 5, 0];
+        test_vm.reset_pc();
+
+        assert_eq!(test_vm.run(), Err(VmError::OutOfBounds(4, 13)));
+    }
+
+    #[test]
+    fn test_opcode_assert() {
+        let mut test_vm = get_test_vm();
+
+        test_vm.registers[2] = 5;
+        test_vm.program = vec![41, 0, 2, 0, 17, 0, 0, 0];
+
+        assert_eq!(test_vm.run(), Ok(2));
+        assert_eq!(test_vm.pc, 8);
+    }
+
+    #[test]
+    fn test_opcode_assert_failed() {
+        let mut test_vm = get_test_vm();
+
+        test_vm.program = vec![17, 0, 0, 0, 41, 0, 1, 0, 17, 0, 0, 0];
+
+        assert_eq!(test_vm.run(), Err(VmError::AssertionFailed { expected: 5, actual: 10, pc: 4 }));
+        assert_eq!(test_vm.pc, 8);
+    }
+
+    #[test]
+    fn test_opcode_sys() {
+        let mut test_vm = get_test_vm();
+
+        test_vm.register_syscall(3, |vm| vm.registers[0] = 99);
+        test_vm.program = vec![43, 3, 0, 0];
+        test_vm.run().unwrap();
+
+        assert_eq!(test_vm.registers[0], 99);
+        assert_eq!(test_vm.pc, 4);
+
+        test_vm.program = vec![17, 0, 0, 0, 43, 2, 0, 0];
+        test_vm.reset_pc();
+
+        assert_eq!(test_vm.run(), Err(VmError::UnknownSyscall(4, 2)));
+    }
+
+    #[test]
+    fn test_opcode_wrapping_arithmetic() {
+        let mut test_vm = get_test_vm();
+
+        test_vm.registers[2] = i32::MAX;
+        test_vm.registers[3] = 1;
+        test_vm.registers[4] = i32::MIN;
+
+        test_vm.program = vec![45, 2, 3, 5, 46, 4, 3, 6, 47, 2, 2, 7];
+        test_vm.run().unwrap();
+
+        assert_eq!(test_vm.registers[5], i32::MIN);
+        assert_eq!(test_vm.registers[6], i32::MAX);
+        assert_eq!(test_vm.registers[7], 1);
+    }
+
+    #[test]
+    fn test_opcode_saturating_arithmetic() {
+        let mut test_vm = get_test_vm();
+
+        test_vm.registers[2] = i32::MAX;
+        test_vm.registers[3] = 1;
+        test_vm.registers[4] = i32::MIN;
+
+        test_vm.program = vec![48, 2, 3, 5, 49, 4, 3, 6, 50, 4, 2, 7, 48, 0, 1, 8];
+        test_vm.run().unwrap();
+
+        assert_eq!(test_vm.registers[5], i32::MAX);
+        assert_eq!(test_vm.registers[6], i32::MIN);
+        assert_eq!(test_vm.registers[7], i32::MIN);
+        assert_eq!(test_vm.registers[8], 15);
+    }
+
+    #[test]
+    fn test_opcode_prtr() {
+        let mut test_vm = get_test_vm();
+        let buffer = SharedBuffer(Rc::new(RefCell::new(vec![])));
+
+        test_vm.set_output(Box::new(buffer.clone()));
+        test_vm.registers[2] = -42;
+
+        test_vm.program = vec![44, 1, 0, 0, 44, 2, 0, 0];
+        test_vm.run().unwrap();
+
+        assert_eq!(String::from_utf8(buffer.0.borrow().clone()).unwrap(), "10\n-42\n");
+        assert_eq!(test_vm.pc, 8);
     }
 
     #[test]
@@ -527,11 +1645,182 @@ mod tests {
         let mut test_vm = get_test_vm();
 
         test_vm.program = vec![17, 0, 0, 0];
-        test_vm.run_once();
+        test_vm.run_once().unwrap();
+
+        assert_eq!(test_vm.pc, 4);
+    }
+
+    #[test]
+    fn test_opcode_neg() {
+        let mut test_vm = get_test_vm();
+
+        test_vm.program = vec![20, 0, 0, 0];
+        test_vm.run_once().unwrap();
+
+        assert_eq!(test_vm.registers[0], -5);
+        assert_eq!(test_vm.pc, 4);
+    }
+
+    #[test]
+    fn test_opcode_not() {
+        let mut test_vm = get_test_vm();
+
+        test_vm.registers[0] = 0;
+
+        test_vm.program = vec![21, 0, 0, 0];
+        test_vm.run_once().unwrap();
+
+        assert_eq!(test_vm.registers[0], -1);
+        assert_eq!(test_vm.pc, 4);
+    }
+
+    #[test]
+    fn test_opcode_fload() {
+        let mut test_vm = get_test_vm();
+
+        test_vm.program = vec![26, 3, 1, 244];
+        test_vm.run().unwrap();
+
+        assert_eq!(test_vm.float_registers[3], 500.0);
+    }
+
+    #[test]
+    fn test_opcode_fadd() {
+        let mut test_vm = get_test_vm();
+
+        test_vm.float_registers[0] = 1.5;
+        test_vm.float_registers[1] = 2.25;
+
+        test_vm.program = vec![22, 0, 1, 2];
+        test_vm.run().unwrap();
+
+        assert_eq!(test_vm.float_registers[2], 3.75);
+    }
+
+    #[test]
+    fn test_opcode_fsub_fmul() {
+        let mut test_vm = get_test_vm();
+
+        test_vm.float_registers[0] = 4.5;
+        test_vm.float_registers[1] = 2.0;
+
+        test_vm.program = vec![23, 0, 1, 2, 24, 0, 1, 3];
+        test_vm.run().unwrap();
+
+        assert_eq!(test_vm.float_registers[2], 2.5);
+        assert_eq!(test_vm.float_registers[3], 9.0);
+    }
+
+    #[test]
+    fn test_opcode_fdiv() {
+        let mut test_vm = get_test_vm();
+
+        test_vm.float_registers[0] = 7.0;
+        test_vm.float_registers[1] = 2.0;
+
+        test_vm.program = vec![25, 0, 1, 2];
+        test_vm.run().unwrap();
+
+        assert_eq!(test_vm.float_registers[2], 3.5);
+    }
+
+    #[test]
+    fn test_opcode_itof() {
+        let mut test_vm = get_test_vm();
 
+        test_vm.registers[0] = 7;
+
+        test_vm.program = vec![27, 0, 4, 0];
+        test_vm.run_once().unwrap();
+
+        assert_eq!(test_vm.float_registers[4], 7.0);
+        assert_eq!(test_vm.pc, 4);
+    }
+
+    #[test]
+    fn test_opcode_ftoi() {
+        let mut test_vm = get_test_vm();
+
+        test_vm.float_registers[0] = 3.9;
+
+        test_vm.program = vec![28, 0, 4, 0];
+        test_vm.run_once().unwrap();
+
+        assert_eq!(test_vm.registers[4], 3);
         assert_eq!(test_vm.pc, 4);
     }
 
+    #[test]
+    fn test_profile_counts_loop() {
+        let mut test_vm = get_test_vm();
+
+        test_vm.registers[0] = 0;
+        test_vm.registers[1] = 1;
+        test_vm.registers[2] = 5;
+        test_vm.registers[3] = 0;
+
+        // Increment $0 until it equals $2, jumping back to the start through $3
+        test_vm.program = vec![
+                                1, 0, 1, 0,
+                                9, 0, 2, 0,
+                                11, 3, 0, 0
+                            ];
+
+        test_vm.run().unwrap();
+
+        assert_eq!(test_vm.registers[0], 5);
+        assert_eq!(test_vm.run(), Ok(0));
+        assert_eq!(test_vm.profile(), &[(Opcode::ADD, 5), (Opcode::EQ, 5), (Opcode::JNE, 5)]);
+    }
+
+    #[test]
+    fn test_trace_output() {
+        let mut test_vm = get_test_vm();
+        let buffer = SharedBuffer(Rc::new(RefCell::new(vec![])));
+
+        test_vm.set_trace(true);
+        test_vm.set_trace_output(Box::new(buffer.clone()));
+
+        test_vm.program = vec![0, 2, 0, 7, 1, 0, 2, 3];
+        test_vm.run().unwrap();
+
+        let trace = String::from_utf8(buffer.0.borrow().clone()).unwrap();
+
+        assert_eq!(trace, "0000 LOAD 2 0 7 | $2: 0 -> 7\n0004 ADD 0 2 3 | $3: 0 -> 12\n");
+    }
+
+    #[test]
+    fn test_verify_valid_program() {
+        let mut test_vm = get_test_vm();
+
+        test_vm.program = vec![0, 31, 1, 244, 1, 0, 1, 2, 29, 255, 244, 0, 5, 0, 0, 0];
+
+        assert_eq!(test_vm.verify(), Ok(()));
+    }
+
+    #[test]
+    fn test_verify_bad_register() {
+        let mut test_vm = get_test_vm();
+
+        test_vm.program = vec![1, 0, 1, 2, 1, 0, 32, 2];
+
+        assert_eq!(test_vm.verify(), Err(VmError::BadRegister(6, 32)));
+        assert_eq!(test_vm.run(), Err(VmError::BadRegister(6, 32)));
+        // Nothing runs when verification fails
+        assert_eq!(test_vm.registers[2], 0);
+    }
+
+    #[test]
+    fn test_verify_bad_opcode_and_length() {
+        let mut test_vm = get_test_vm();
+
+        test_vm.program = vec![17, 0, 0, 0, 200, 0, 0, 0];
+        assert_eq!(test_vm.verify(), Err(VmError::UnknownOpcode(4, 200)));
+
+        test_vm.program = vec![17, 0, 0, 0, 17];
+        assert_eq!(test_vm.verify(), Err(VmError::BadLength(5)));
+    }
+
     #[test]
     fn test_program() {
         let mut test_vm = get_test_vm();
@@ -544,11 +1833,11 @@ mod tests {
                                 3, 1, 2, 3,
                                 3, 1, 3, 4,
                                 4, 2, 1, 5,
-                                5
+                                5, 0, 0, 0
                             ];
 
-        test_vm.run();
+        assert_eq!(test_vm.run(), Ok(5));
 
-        assert_eq!(test_vm.pc, 17);
+        assert_eq!(test_vm.pc, 20);
     }
 }